@@ -0,0 +1,907 @@
+use crate::{
+    structs::{AcceptProxy, PoolConfig, SniffOptions, Timeouts},
+    utils::{self, Cidr, ListenAddr},
+};
+use color_eyre::Result;
+use std::{collections::HashMap, fmt::Debug, net::IpAddr, time::Duration};
+
+/// Settings only one of the binaries has, they live in the `[engine]`
+/// table of the config file.
+pub trait EngineConfig: Default + Debug + serde::de::DeserializeOwned {
+    fn apply_args(&mut self, args: &[String]) -> Result<()>;
+    fn validate(&self) -> Result<()>;
+}
+
+/// Settings of the `--config` file (TOML, or YAML for .yaml / .yml), every
+/// command line flag overrides the value it matches.
+#[derive(serde::Deserialize, Debug)]
+#[serde(default, deny_unknown_fields, bound = "E: EngineConfig")]
+pub struct Config<E> {
+    /// Script of listeners that don't set their own
+    pub script: String,
+    pub listeners: Vec<ListenerConfig>,
+    pub trusted_proxies: Vec<String>,
+    pub sniff: SniffConfig,
+    pub timeouts: TimeoutsConfig,
+    pub log: LogConfig,
+    pub tarpit_max: u64,
+    pub kv_max_bytes: usize,
+    pub ban_lists: Vec<String>,
+    pub history: HistoryConfig,
+    /// Same format as the `--pools` file, registered after it
+    pub pools: HashMap<String, PoolConfig>,
+    pub pools_file: Option<String>,
+    pub engine: E,
+
+    /// `--accept-proxy` / `--accept-proxy-optional` ports, for the
+    /// listeners that don't set accept_proxy
+    #[serde(skip)]
+    accept_proxy_ports: Option<(Vec<u16>, Vec<u16>)>,
+    /// `--scripts "25565,25566=game.js;22=ssh.js"`, the listeners they match
+    /// run that script instead
+    #[serde(skip)]
+    script_map: Vec<(Vec<ListenAddr>, String)>,
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerConfig {
+    /// Address of entries that don't name one
+    #[serde(default = "default_bind")]
+    pub bind: String,
+    #[serde(alias = "listen")]
+    pub ports: Ports,
+    /// "tcp" or "udp", entries can override it with a `tcp/` or `udp/` prefix
+    #[serde(default = "default_protocol")]
+    pub protocol: String,
+    /// "off" (default), "optional" or "required", tcp only
+    pub accept_proxy: Option<String>,
+    /// How long to wait for the PROXY header, defaults to 5s when it's
+    /// required and 200ms when it's optional
+    pub proxy_timeout_ms: Option<u64>,
+    /// Overrides `[sniff] max_bytes`, 0 turns sniffing off
+    pub sniff_bytes: Option<usize>,
+    /// Overrides the top level script
+    pub script: Option<String>,
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+#[serde(
+    untagged,
+    expecting = "a port, a list of ports or listener specs like \"[::]:25565,udp/53,unix:/run/deez.sock\""
+)]
+pub enum Ports {
+    One(u16),
+    List(Vec<u16>),
+    Spec(String),
+    Specs(Vec<String>),
+}
+
+#[derive(serde::Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct SniffConfig {
+    pub max_bytes: usize,
+    pub timeout_ms: u64,
+}
+
+/// The relay timers are off unless set, 0 disables one
+#[derive(serde::Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct TimeoutsConfig {
+    pub idle_ms: u64,
+    pub max_lifetime_ms: u64,
+    pub half_close_linger_ms: u64,
+    /// UDP has no close, a flow ends once it's idle this long
+    pub udp_flow_idle_ms: u64,
+}
+
+#[derive(serde::Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    /// A line for every proxied connection
+    pub connections: bool,
+    /// How often the stats line is printed, 0 turns it off
+    pub stats_interval_sec: u64,
+}
+
+#[derive(serde::Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct HistoryConfig {
+    pub max_clients: usize,
+    pub windows_sec: Vec<f64>,
+}
+
+/// One socket to listen on, what the listener entries expand to
+#[derive(Debug, Clone)]
+pub struct Listener {
+    pub addr: ListenAddr,
+    pub accept_proxy: AcceptProxy,
+    pub proxy_timeout: Duration,
+    pub sniff: SniffOptions,
+    pub script: String,
+}
+
+fn default_bind() -> String {
+    "0.0.0.0".into()
+}
+
+fn default_protocol() -> String {
+    "tcp".into()
+}
+
+impl<E: EngineConfig> Default for Config<E> {
+    fn default() -> Self {
+        Config {
+            script: "main.js".into(),
+            listeners: vec![ListenerConfig {
+                bind: default_bind(),
+                ports: Ports::One(7070),
+                protocol: default_protocol(),
+                accept_proxy: None,
+                proxy_timeout_ms: None,
+                sniff_bytes: None,
+                script: None,
+            }],
+            trusted_proxies: vec![],
+            sniff: SniffConfig::default(),
+            timeouts: TimeoutsConfig::default(),
+            log: LogConfig::default(),
+            tarpit_max: 1000,
+            kv_max_bytes: 64 * 1024 * 1024,
+            ban_lists: vec![],
+            history: HistoryConfig::default(),
+            pools: HashMap::new(),
+            pools_file: None,
+            engine: E::default(),
+            accept_proxy_ports: None,
+            script_map: vec![],
+        }
+    }
+}
+
+impl Default for SniffConfig {
+    fn default() -> Self {
+        SniffConfig {
+            // off, clients of server-first protocols would wait for the timeout
+            max_bytes: 0,
+            timeout_ms: 500,
+        }
+    }
+}
+
+impl Default for TimeoutsConfig {
+    fn default() -> Self {
+        TimeoutsConfig {
+            idle_ms: 0,
+            max_lifetime_ms: 0,
+            half_close_linger_ms: 0,
+            udp_flow_idle_ms: 60_000,
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            connections: false,
+            stats_interval_sec: 60,
+        }
+    }
+}
+
+impl Default for HistoryConfig {
+    fn default() -> Self {
+        HistoryConfig {
+            max_clients: 100_000,
+            windows_sec: vec![10.0, 60.0, 600.0],
+        }
+    }
+}
+
+/// Reads `--config` if it's set, applies the flags and checks the result.
+pub fn load<E: EngineConfig>(args: &[String]) -> Result<Config<E>> {
+    let mut config = match utils::get_arg(args, "--config") {
+        Some(path) => Config::from_file(path)?,
+        None => Config::default(),
+    };
+    config.apply_args(args)?;
+    config.validate()?;
+
+    Ok(config)
+}
+
+impl<E: EngineConfig> Config<E> {
+    pub fn from_file(path: &str) -> Result<Self> {
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| color_eyre::eyre::eyre!("Can't read config {}: {}", path, e))?;
+
+        let config = if path.ends_with(".yaml") || path.ends_with(".yml") {
+            serde_yaml::from_str(&text).map_err(|e| color_eyre::eyre::eyre!("{}", e))
+        } else {
+            toml::from_str(&text).map_err(|e| color_eyre::eyre::eyre!("{}", e))
+        };
+        config.map_err(|e| color_eyre::eyre::eyre!("Invalid config {}: {}", path, e))
+    }
+
+    fn apply_args(&mut self, args: &[String]) -> Result<()> {
+        // listeners given on the command line replace the ones of the config
+        let tcp_ports = args.get(1).filter(|arg| !arg.starts_with("--"));
+        let cli_listeners = [
+            (tcp_ports.map(|ports| ports.as_str()), "tcp"),
+            (utils::get_arg(args, "--udp"), "udp"),
+        ];
+        if cli_listeners.iter().any(|(ports, _)| ports.is_some()) {
+            self.listeners.clear();
+        }
+        for (ports, protocol) in cli_listeners {
+            let Some(ports) = ports else {
+                continue;
+            };
+            self.listeners.push(ListenerConfig {
+                bind: default_bind(),
+                ports: Ports::Spec(ports.to_string()),
+                protocol: protocol.into(),
+                accept_proxy: None,
+                proxy_timeout_ms: None,
+                sniff_bytes: None,
+                script: None,
+            });
+        }
+
+        let accept_proxy = utils::get_arg(args, "--accept-proxy");
+        let accept_proxy_optional = utils::get_arg(args, "--accept-proxy-optional");
+        if accept_proxy.is_some() || accept_proxy_optional.is_some() {
+            self.accept_proxy_ports = Some((
+                flag_ports("--accept-proxy", accept_proxy)?,
+                flag_ports("--accept-proxy-optional", accept_proxy_optional)?,
+            ));
+        }
+        if let Some(cidrs) = utils::get_arg(args, "--trusted-proxies") {
+            self.trusted_proxies = split_list(cidrs);
+        }
+        if let Some(script) = utils::get_arg(args, "--script") {
+            self.script = script.into();
+        }
+        if let Some(map) = utils::get_arg(args, "--scripts") {
+            self.script_map = parse_script_map(map)?;
+        }
+        if let Some(path) = utils::get_arg(args, "--pools") {
+            self.pools_file = Some(path.into());
+        }
+        if let Some(paths) = utils::get_arg(args, "--ban-lists") {
+            self.ban_lists = split_list(paths);
+        }
+        if let Some(windows) = utils::get_arg(args, "--history-windows") {
+            self.history.windows_sec = split_list(windows)
+                .iter()
+                .map(|window| parse_flag("--history-windows", window))
+                .collect::<Result<_>>()?;
+        }
+        if args.iter().any(|arg| arg == "--log-connections") {
+            self.log.connections = true;
+        }
+
+        if let Some(value) = flag(args, "--sniff-bytes")? {
+            self.sniff.max_bytes = value;
+        }
+        if let Some(value) = flag(args, "--sniff-timeout-ms")? {
+            self.sniff.timeout_ms = value;
+        }
+        if let Some(value) = flag(args, "--idle-timeout-ms")? {
+            self.timeouts.idle_ms = value;
+        }
+        if let Some(value) = flag(args, "--max-lifetime-ms")? {
+            self.timeouts.max_lifetime_ms = value;
+        }
+        if let Some(value) = flag(args, "--half-close-linger-ms")? {
+            self.timeouts.half_close_linger_ms = value;
+        }
+        if let Some(value) = flag(args, "--udp-flow-idle-ms")? {
+            self.timeouts.udp_flow_idle_ms = value;
+        }
+        if let Some(value) = flag(args, "--tarpit-max")? {
+            self.tarpit_max = value;
+        }
+        if let Some(value) = flag(args, "--kv-max-bytes")? {
+            self.kv_max_bytes = value;
+        }
+        if let Some(value) = flag(args, "--history-max-clients")? {
+            self.history.max_clients = value;
+        }
+        if let Some(value) = flag(args, "--stats-interval")? {
+            self.log.stats_interval_sec = value;
+        }
+
+        self.engine.apply_args(args)
+    }
+
+    /// Checks everything that can be checked before binding, errors name
+    /// the setting like `listeners[1].ports`.
+    fn validate(&self) -> Result<()> {
+        if self.listeners.is_empty() {
+            color_eyre::eyre::bail!("listeners: nothing to listen on");
+        }
+        let listeners = self.listeners()?;
+
+        for (i, cidr) in self.trusted_proxies.iter().enumerate() {
+            Cidr::parse(cidr)
+                .map_err(|e| color_eyre::eyre::eyre!("trusted_proxies[{}] {}: {}", i, cidr, e))?;
+        }
+        if self.timeouts.udp_flow_idle_ms == 0 {
+            color_eyre::eyre::bail!("timeouts.udp_flow_idle_ms: has to be above 0");
+        }
+        for (i, window) in self.history.windows_sec.iter().enumerate() {
+            if !window.is_finite() || *window <= 0.0 {
+                color_eyre::eyre::bail!("history.windows_sec[{}]: has to be above 0", i);
+            }
+        }
+
+        let mut files = vec![];
+        // with --scripts covering every port the default script isn't needed
+        if listeners
+            .iter()
+            .any(|listener| listener.script == self.script)
+        {
+            files.push(("script".to_string(), &self.script));
+        }
+        for (i, listener) in self.listeners.iter().enumerate() {
+            if let Some(script) = &listener.script {
+                files.push((format!("listeners[{}].script", i), script));
+            }
+        }
+        for (_, script) in &self.script_map {
+            files.push(("--scripts".to_string(), script));
+        }
+        for (i, path) in self.ban_lists.iter().enumerate() {
+            files.push((format!("ban_lists[{}]", i), path));
+        }
+        if let Some(path) = &self.pools_file {
+            files.push(("pools_file".to_string(), path));
+        }
+        for (name, path) in files {
+            std::fs::metadata(path)
+                .map_err(|e| color_eyre::eyre::eyre!("{}: can't read {}: {}", name, path, e))?;
+        }
+
+        self.engine.validate()
+    }
+
+    /// Every socket the listener entries ask for, a port can only be used
+    /// once per protocol and address.
+    pub fn listeners(&self) -> Result<Vec<Listener>> {
+        let mut res: Vec<Listener> = vec![];
+        // only addresses of the same protocol and port can clash
+        let mut used: HashMap<(u8, u16), Vec<(ListenAddr, usize)>> = HashMap::new();
+        let mut mapped = vec![false; self.script_map.len()];
+
+        for (i, listener) in self.listeners.iter().enumerate() {
+            let field = |name: &str| format!("listeners[{}].{}", i, name);
+
+            let ip = listener.bind.parse::<IpAddr>().map_err(|e| {
+                color_eyre::eyre::eyre!("{} {}: {}", field("bind"), listener.bind, e)
+            })?;
+            let udp = match listener.protocol.as_str() {
+                "tcp" => false,
+                "udp" => true,
+                other => color_eyre::eyre::bail!(
+                    "{}: expected \"tcp\" or \"udp\", got \"{}\"",
+                    field("protocol"),
+                    other
+                ),
+            };
+            let accept_proxy = match listener.accept_proxy.as_deref() {
+                None => None,
+                Some("off") => Some(AcceptProxy::Off),
+                Some("optional") => Some(AcceptProxy::Optional),
+                Some("required") => Some(AcceptProxy::Required),
+                Some(other) => color_eyre::eyre::bail!(
+                    "{}: expected \"off\", \"optional\" or \"required\", got \"{}\"",
+                    field("accept_proxy"),
+                    other
+                ),
+            };
+            if listener.proxy_timeout_ms == Some(0) {
+                color_eyre::eyre::bail!("{}: has to be above 0", field("proxy_timeout_ms"));
+            }
+            if udp && accept_proxy.is_some_and(|mode| mode != AcceptProxy::Off) {
+                color_eyre::eyre::bail!(
+                    "{}: udp listeners can't accept PROXY headers",
+                    field("accept_proxy")
+                );
+            }
+
+            let specs = match &listener.ports {
+                Ports::One(port) => vec![port.to_string()],
+                Ports::List(ports) => ports.iter().map(|port| port.to_string()).collect(),
+                Ports::Spec(spec) => vec![spec.clone()],
+                Ports::Specs(specs) => specs.clone(),
+            };
+            let mut addrs = vec![];
+            for (j, spec) in specs.iter().enumerate() {
+                let name = match &listener.ports {
+                    Ports::Spec(_) => field("ports"),
+                    _ => format!("{}[{}]", field("ports"), j),
+                };
+                let parsed = utils::parse_listen(spec, ip, udp)
+                    .map_err(|e| color_eyre::eyre::eyre!("{} \"{}\": {}", name, spec, e))?;
+                addrs.extend(parsed.into_iter().map(|addr| (name.clone(), addr)));
+            }
+            if addrs.is_empty() {
+                color_eyre::eyre::bail!("{}: no ports", field("ports"));
+            }
+
+            for (name, addr) in addrs {
+                let slot = match &addr {
+                    ListenAddr::Tcp(addr) => (0, addr.port()),
+                    ListenAddr::Udp(addr) => (1, addr.port()),
+                    ListenAddr::Unix(_) => (2, 0),
+                };
+                let used = used.entry(slot).or_default();
+                if let Some((other, j)) = used
+                    .iter()
+                    .find(|(other, _)| utils::listen_overlaps(other, &addr))
+                {
+                    color_eyre::eyre::bail!(
+                        "{}: {} is already used by listeners[{}] ({})",
+                        name,
+                        addr,
+                        j,
+                        other
+                    );
+                }
+                used.push((addr.clone(), i));
+
+                let accept_proxy = match (&addr, accept_proxy, &self.accept_proxy_ports) {
+                    (ListenAddr::Udp(_), _, _) => AcceptProxy::Off,
+                    (_, Some(mode), _) => mode,
+                    (ListenAddr::Tcp(addr), None, Some((required, optional))) => {
+                        if required.contains(&addr.port()) {
+                            AcceptProxy::Required
+                        } else if optional.contains(&addr.port()) {
+                            AcceptProxy::Optional
+                        } else {
+                            AcceptProxy::Off
+                        }
+                    }
+                    _ => AcceptProxy::Off,
+                };
+                let script =
+                    match self.script_map.iter().position(|(patterns, _)| {
+                        patterns.iter().any(|p| matches_listener(p, &addr))
+                    }) {
+                        Some(j) => {
+                            mapped[j] = true;
+                            self.script_map[j].1.clone()
+                        }
+                        None => listener.script.clone().unwrap_or(self.script.clone()),
+                    };
+                // server-first clients send nothing, an optional header
+                // shouldn't hold them up for long
+                let proxy_timeout = listener.proxy_timeout_ms.unwrap_or(match accept_proxy {
+                    AcceptProxy::Optional => 200,
+                    _ => 5000,
+                });
+                res.push(Listener {
+                    addr,
+                    accept_proxy,
+                    proxy_timeout: Duration::from_millis(proxy_timeout),
+                    sniff: SniffOptions {
+                        max_bytes: listener.sniff_bytes.unwrap_or(self.sniff.max_bytes),
+                        ..self.sniff()
+                    },
+                    script,
+                });
+            }
+        }
+        if let Some(j) = mapped.iter().position(|mapped| !mapped) {
+            color_eyre::eyre::bail!(
+                "--scripts: no listener matches the ports of {}",
+                self.script_map[j].1
+            );
+        }
+
+        Ok(res)
+    }
+
+    pub fn sniff(&self) -> SniffOptions {
+        SniffOptions {
+            max_bytes: self.sniff.max_bytes,
+            timeout: Duration::from_millis(self.sniff.timeout_ms),
+        }
+    }
+
+    pub fn timeouts(&self) -> Timeouts {
+        Timeouts {
+            idle: Duration::from_millis(self.timeouts.idle_ms),
+            max_lifetime: Duration::from_millis(self.timeouts.max_lifetime_ms),
+            half_close_linger: Duration::from_millis(self.timeouts.half_close_linger_ms),
+        }
+    }
+
+    pub fn udp_flow_idle(&self) -> Duration {
+        Duration::from_millis(self.timeouts.udp_flow_idle_ms)
+    }
+
+    pub fn trusted_proxies(&self) -> Result<Vec<Cidr>> {
+        self.trusted_proxies
+            .iter()
+            .map(|cidr| Cidr::parse(cidr))
+            .collect()
+    }
+
+    pub fn history_windows(&self) -> Vec<Duration> {
+        self.history
+            .windows_sec
+            .iter()
+            .map(|window| Duration::from_secs_f64(*window))
+            .collect()
+    }
+}
+
+pub fn parse_flag<T>(name: &str, value: &str) -> Result<T>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    value
+        .trim()
+        .parse()
+        .map_err(|e| color_eyre::eyre::eyre!("{} {}: {}", name, value, e))
+}
+
+pub fn flag<T>(args: &[String], name: &str) -> Result<Option<T>>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    utils::get_arg(args, name)
+        .map(|value| parse_flag(name, value))
+        .transpose()
+}
+
+/// Entries like `25565,25566=game.js` separated by `;`, the ports use the
+/// listener grammar.
+fn parse_script_map(map: &str) -> Result<Vec<(Vec<ListenAddr>, String)>> {
+    map.split(';')
+        .filter(|entry| !entry.trim().is_empty())
+        .map(|entry| {
+            let (spec, script) = entry.split_once('=').ok_or(color_eyre::eyre::eyre!(
+                "--scripts {}: expected ports=script",
+                entry.trim()
+            ))?;
+            let addrs = utils::parse_listen(spec, IpAddr::from([0, 0, 0, 0]), false)
+                .map_err(|e| color_eyre::eyre::eyre!("--scripts {}: {}", spec.trim(), e))?;
+            Ok((addrs, script.trim().to_string()))
+        })
+        .collect()
+}
+
+/// `--scripts` entries without an address match the port on any address.
+fn matches_listener(pattern: &ListenAddr, addr: &ListenAddr) -> bool {
+    match (pattern, addr) {
+        (ListenAddr::Tcp(pattern), ListenAddr::Tcp(addr))
+        | (ListenAddr::Udp(pattern), ListenAddr::Udp(addr)) => {
+            pattern.port() == addr.port()
+                && (pattern.ip().is_unspecified() || pattern.ip() == addr.ip())
+        }
+        (ListenAddr::Unix(pattern), ListenAddr::Unix(addr)) => pattern == addr,
+        _ => false,
+    }
+}
+
+fn flag_ports(name: &str, ports: Option<&str>) -> Result<Vec<u16>> {
+    utils::parse_ports(ports.unwrap_or(""))
+        .map_err(|e| color_eyre::eyre::eyre!("{} {}: {}", name, ports.unwrap_or(""), e))
+}
+
+fn split_list(list: &str) -> Vec<String> {
+    list.split(',')
+        .filter(|item| !item.trim().is_empty())
+        .map(|item| item.trim().to_string())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A file that's always there to stand in for scripts.
+    const SCRIPT: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/Cargo.toml");
+
+    /// Like the worker pool settings of deno-test.
+    #[derive(serde::Deserialize, Debug)]
+    #[serde(default, deny_unknown_fields)]
+    struct Workers {
+        workers: usize,
+    }
+
+    impl Default for Workers {
+        fn default() -> Self {
+            Workers { workers: 1 }
+        }
+    }
+
+    impl EngineConfig for Workers {
+        fn apply_args(&mut self, args: &[String]) -> Result<()> {
+            if let Some(value) = flag(args, "--workers")? {
+                self.workers = value;
+            }
+            Ok(())
+        }
+
+        fn validate(&self) -> Result<()> {
+            if self.workers == 0 {
+                color_eyre::eyre::bail!("engine.workers: has to be at least 1");
+            }
+            Ok(())
+        }
+    }
+
+    /// Like v8-test, which has no settings of its own.
+    #[derive(serde::Deserialize, Debug, Default)]
+    #[serde(deny_unknown_fields)]
+    struct NoSettings {}
+
+    impl EngineConfig for NoSettings {
+        fn apply_args(&mut self, _args: &[String]) -> Result<()> {
+            Ok(())
+        }
+
+        fn validate(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    fn args(args: &str) -> Vec<String> {
+        std::iter::once("deez")
+            .chain(args.split_whitespace())
+            .map(|arg| arg.to_string())
+            .collect()
+    }
+
+    fn toml_config<E: EngineConfig>(text: &str) -> Result<Config<E>> {
+        Ok(toml::from_str(&format!(
+            "script = \"{}\"\n{}",
+            SCRIPT, text
+        ))?)
+    }
+
+    fn modes(config: &Config<Workers>) -> Vec<(String, AcceptProxy)> {
+        config
+            .listeners()
+            .unwrap()
+            .into_iter()
+            .map(|listener| (listener.addr.to_string(), listener.accept_proxy))
+            .collect()
+    }
+
+    #[test]
+    fn engine_table() {
+        let config = toml_config::<Workers>("[engine]\nworkers = 4").unwrap();
+        assert_eq!(config.engine.workers, 4);
+
+        let err = toml_config::<NoSettings>("[engine]\nworkers = 4").unwrap_err();
+        assert!(err.to_string().contains("workers"), "{}", err);
+        assert!(toml_config::<Workers>("workers = 4").is_err());
+    }
+
+    #[test]
+    fn yaml() {
+        let config: Config<Workers> =
+            serde_yaml::from_str("listeners:\n  - ports: \"udp/53\"\nengine:\n  workers: 2")
+                .unwrap();
+        assert_eq!(config.engine.workers, 2);
+        assert_eq!(
+            modes(&config),
+            [("udp/0.0.0.0:53".into(), AcceptProxy::Off)]
+        );
+    }
+
+    #[test]
+    fn command_line_listeners_replace_the_config() {
+        let mut config = toml_config::<Workers>("[[listeners]]\nports = 80").unwrap();
+        config
+            .apply_args(&args("127.0.0.1:25565 --udp 53 --workers 8"))
+            .unwrap();
+        config.validate().unwrap();
+
+        let addrs = modes(&config)
+            .into_iter()
+            .map(|(addr, _)| addr)
+            .collect::<Vec<_>>();
+        assert_eq!(addrs, ["127.0.0.1:25565", "udp/0.0.0.0:53"]);
+        assert_eq!(config.engine.workers, 8);
+    }
+
+    #[test]
+    fn accept_proxy_flags_only_fill_in_unset_listeners() {
+        let mut config = toml_config::<Workers>(
+            r#"
+            [[listeners]]
+            ports = [25565, 25566, 25569]
+            [[listeners]]
+            ports = 25567
+            accept_proxy = "off"
+            [[listeners]]
+            ports = 25568
+            accept_proxy = "optional"
+            [[listeners]]
+            ports = "udp/25565"
+            "#,
+        )
+        .unwrap();
+        let unset = modes(&config);
+        assert_eq!(unset[4], ("0.0.0.0:25568".into(), AcceptProxy::Optional));
+        assert!(unset[..4].iter().all(|(_, mode)| *mode == AcceptProxy::Off));
+
+        config
+            .apply_args(&args(
+                "--accept-proxy 25565,25567 --accept-proxy-optional 25566,25568",
+            ))
+            .unwrap();
+        assert_eq!(
+            modes(&config),
+            [
+                ("0.0.0.0:25565".into(), AcceptProxy::Required),
+                ("0.0.0.0:25566".into(), AcceptProxy::Optional),
+                ("0.0.0.0:25569".into(), AcceptProxy::Off),
+                ("0.0.0.0:25567".into(), AcceptProxy::Off),
+                ("0.0.0.0:25568".into(), AcceptProxy::Optional),
+                ("udp/0.0.0.0:25565".into(), AcceptProxy::Off),
+            ]
+        );
+    }
+
+    #[test]
+    fn sniffing_is_opt_in() {
+        let mut config = toml_config::<Workers>(
+            "[[listeners]]\nports = 80\n[[listeners]]\nports = 443\nsniff_bytes = 4096",
+        )
+        .unwrap();
+        let sniff_bytes = |config: &Config<Workers>| {
+            config
+                .listeners()
+                .unwrap()
+                .iter()
+                .map(|listener| listener.sniff.max_bytes)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(sniff_bytes(&config), [0, 4096]);
+
+        config.apply_args(&args("--sniff-bytes 512")).unwrap();
+        assert_eq!(sniff_bytes(&config), [512, 4096]);
+    }
+
+    #[test]
+    fn relay_timers_are_off_by_default() {
+        let timeouts = Config::<Workers>::default().timeouts();
+        assert!(timeouts.idle.is_zero());
+        assert!(timeouts.max_lifetime.is_zero());
+        assert!(timeouts.half_close_linger.is_zero());
+    }
+
+    #[test]
+    fn proxy_timeouts() {
+        let config = toml_config::<Workers>(
+            r#"
+            [[listeners]]
+            ports = 80
+            accept_proxy = "required"
+            [[listeners]]
+            ports = 81
+            accept_proxy = "optional"
+            [[listeners]]
+            ports = 82
+            accept_proxy = "optional"
+            proxy_timeout_ms = 1000
+            "#,
+        )
+        .unwrap();
+        let timeouts = config
+            .listeners()
+            .unwrap()
+            .iter()
+            .map(|listener| listener.proxy_timeout.as_millis())
+            .collect::<Vec<_>>();
+        assert_eq!(timeouts, [5000, 200, 1000]);
+    }
+
+    #[test]
+    fn listener_errors() {
+        for (text, expected) in [
+            (
+                "[[listeners]]\nports = 80\n[[listeners]]\nports = \"127.0.0.1:80\"",
+                "listeners[1].ports: 127.0.0.1:80 is already used by listeners[0] (0.0.0.0:80)",
+            ),
+            (
+                "[[listeners]]\nports = [\"80\", \"7000-6000\"]",
+                "listeners[0].ports[1] \"7000-6000\": column 1: 7000-6000 is reversed",
+            ),
+            (
+                "[[listeners]]\nports = 80\nprotocol = \"sctp\"",
+                "listeners[0].protocol: expected \"tcp\" or \"udp\", got \"sctp\"",
+            ),
+            (
+                "[[listeners]]\nports = 53\nprotocol = \"udp\"\naccept_proxy = \"required\"",
+                "listeners[0].accept_proxy: udp listeners can't accept PROXY headers",
+            ),
+            (
+                "[[listeners]]\nports = 80\naccept_proxy = \"yes\"",
+                "listeners[0].accept_proxy: expected \"off\", \"optional\" or \"required\", got \"yes\"",
+            ),
+            ("[[listeners]]\nports = []", "listeners[0].ports: no ports"),
+            (
+                "[[listeners]]\nports = 80\nproxy_timeout_ms = 0",
+                "listeners[0].proxy_timeout_ms: has to be above 0",
+            ),
+        ] {
+            let config = toml_config::<Workers>(text).unwrap();
+            assert_eq!(config.listeners().unwrap_err().to_string(), expected);
+        }
+
+        // the same port is fine on another protocol or a separate address
+        let config = toml_config::<Workers>(
+            "[[listeners]]\nports = [\"80\", \"udp/80\", \"127.0.0.1:81\", \"127.0.0.2:81\"]",
+        )
+        .unwrap();
+        assert_eq!(config.listeners().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn validate_errors() {
+        for (text, expected) in [
+            ("listeners = []", "listeners: nothing to listen on"),
+            (
+                "trusted_proxies = [\"10.0.0.0/40\"]",
+                "trusted_proxies[0] 10.0.0.0/40: Invalid prefix length in 10.0.0.0/40",
+            ),
+            (
+                "[history]\nwindows_sec = [60, 0]",
+                "history.windows_sec[1]: has to be above 0",
+            ),
+            (
+                "[engine]\nworkers = 0",
+                "engine.workers: has to be at least 1",
+            ),
+            (
+                "[timeouts]\nudp_flow_idle_ms = 0",
+                "timeouts.udp_flow_idle_ms: has to be above 0",
+            ),
+        ] {
+            let config = toml_config::<Workers>(text).unwrap();
+            assert_eq!(config.validate().unwrap_err().to_string(), expected);
+        }
+
+        let config = toml_config::<Workers>("ban_lists = [\"/nonexistent/bans.txt\"]").unwrap();
+        let err = config.validate().unwrap_err().to_string();
+        assert!(err.starts_with("ban_lists[0]: can't read /nonexistent/bans.txt"));
+    }
+
+    #[test]
+    fn script_map() {
+        let mut config = toml_config::<Workers>("[[listeners]]\nports = \"80,81\"").unwrap();
+        config.apply_args(&args("--scripts 81=game.js")).unwrap();
+        let scripts = config
+            .listeners()
+            .unwrap()
+            .into_iter()
+            .map(|listener| listener.script)
+            .collect::<Vec<_>>();
+        assert_eq!(scripts, [SCRIPT, "game.js"]);
+
+        config.apply_args(&args("--scripts 82=other.js")).unwrap();
+        assert_eq!(
+            config.listeners().unwrap_err().to_string(),
+            "--scripts: no listener matches the ports of other.js"
+        );
+        assert!(config.apply_args(&args("--scripts other.js")).is_err());
+    }
+
+    #[test]
+    fn bad_flags() {
+        let mut config = Config::<Workers>::default();
+        let err = config.apply_args(&args("--sniff-bytes lots")).unwrap_err();
+        assert!(err.to_string().starts_with("--sniff-bytes lots:"));
+        assert!(config.apply_args(&args("--accept-proxy 90-80")).is_err());
+    }
+}
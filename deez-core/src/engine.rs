@@ -0,0 +1,26 @@
+use crate::structs::{CloseInfo, FilterCall, FilterResult, V8Request, V8Response};
+use color_eyre::Result;
+use futures::future::BoxFuture;
+use std::time::Duration;
+
+/// Runs a listener's script, the part every binary brings itself.
+pub trait Engine: Send + Sync + 'static {
+    /// Asks the script what to do with a new connection or UDP flow.
+    fn decide(&self, req: V8Request) -> BoxFuture<'static, Result<V8Response>>;
+
+    /// Runs the script's onClose in the background.
+    fn on_close(&self, info: CloseInfo);
+
+    /// Runs one chunk through onClientData / onServerData, `None` if the
+    /// handler didn't finish within `timeout`.
+    fn filter(
+        &self,
+        call: FilterCall,
+        timeout: Duration,
+    ) -> BoxFuture<'static, Result<Option<FilterResult>>>;
+
+    /// New connections are dropped while the engine can't keep up.
+    fn overloaded(&self) -> bool {
+        false
+    }
+}
@@ -0,0 +1,115 @@
+use crate::{
+    engine::Engine,
+    structs::{FilterBudget, FilterCall, FilterConn},
+};
+use color_eyre::Result;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+const DEFAULT_MAX_BYTES: u64 = 1024 * 1024;
+const DEFAULT_MAX_TIME: Duration = Duration::from_secs(1);
+
+/// What happens to a chunk after the script saw it
+pub enum Verdict {
+    Pass,
+    Replace(Vec<u8>),
+    Drop,
+    Close,
+}
+
+/// The handlers' state and what they used up so far
+struct Spent {
+    conn: FilterConn,
+    bytes: u64,
+    time: Duration,
+    exhausted: bool,
+}
+
+/// Runs a connection's chunks through the script. Both directions share the
+/// state, so their chunks go through one at a time.
+pub struct Filter {
+    spent: tokio::sync::Mutex<Spent>,
+    max_bytes: u64,
+    max_time: Duration,
+    close_on_exhausted: bool,
+    engine: Arc<dyn Engine>,
+}
+
+impl Filter {
+    pub fn new(
+        engine: Arc<dyn Engine>,
+        conn: FilterConn,
+        budget: Option<&FilterBudget>,
+    ) -> Result<Self> {
+        let default = FilterBudget::default();
+        let budget = budget.unwrap_or(&default);
+        let close_on_exhausted = match budget.on_exhausted.as_deref() {
+            None | Some("pass") => false,
+            Some("close") => true,
+            Some(other) => color_eyre::eyre::bail!("Unknown filter on_exhausted: {}", other),
+        };
+
+        Ok(Filter {
+            spent: tokio::sync::Mutex::new(Spent {
+                conn,
+                bytes: 0,
+                time: Duration::ZERO,
+                exhausted: false,
+            }),
+            max_bytes: budget.max_bytes.unwrap_or(DEFAULT_MAX_BYTES),
+            max_time: budget
+                .max_time_ms
+                .map(Duration::from_millis)
+                .unwrap_or(DEFAULT_MAX_TIME),
+            close_on_exhausted,
+            engine,
+        })
+    }
+
+    /// `direction` is "client" or "server", once the budget is spent every
+    /// chunk passes (or the connection closes) without asking the script.
+    pub async fn run(&self, direction: &'static str, chunk: &[u8]) -> Result<Verdict> {
+        let mut spent = self.spent.lock().await;
+        if spent.exhausted {
+            return Ok(Verdict::Pass);
+        } else if spent.bytes >= self.max_bytes || spent.time >= self.max_time {
+            return Ok(self.exhausted(&mut spent));
+        }
+
+        spent.bytes += chunk.len() as u64;
+        let call = FilterCall {
+            direction,
+            chunk: chunk.to_vec(),
+            conn: spent.conn.clone(),
+        };
+        let start = Instant::now();
+        let res = self.engine.filter(call, self.max_time - spent.time).await?;
+        spent.time += start.elapsed();
+
+        // the chunk that ran out of time passes as it was
+        let Some(res) = res else {
+            return Ok(self.exhausted(&mut spent));
+        };
+        if let Some(state) = res.state {
+            spent.conn.state = state;
+        }
+
+        Ok(match res.action.as_str() {
+            "pass" => Verdict::Pass,
+            "replace" => Verdict::Replace(res.data.unwrap_or_default()),
+            "drop" => Verdict::Drop,
+            "close" => Verdict::Close,
+            action => color_eyre::eyre::bail!("Unknown filter action: {}", action),
+        })
+    }
+
+    fn exhausted(&self, spent: &mut Spent) -> Verdict {
+        spent.exhausted = true;
+        match self.close_on_exhausted {
+            true => Verdict::Close,
+            false => Verdict::Pass,
+        }
+    }
+}
@@ -0,0 +1,255 @@
+use crate::{
+    stats::{Stats, LOG_CONNECTIONS, STATS},
+    structs::{BanInfo, BanOptions},
+    utils::Cidr,
+};
+use color_eyre::Result;
+use lazy_static::lazy_static;
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{atomic::Ordering, RwLock},
+    time::{Duration, Instant, SystemTime},
+};
+
+/// How often ban list files are checked for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+lazy_static! {
+    /// Bans added by scripts
+    static ref SCRIPT_BANS: RwLock<BanList> = RwLock::new(BanList::default());
+    /// Bans of every `--ban-lists` file, replaced when the file changes
+    static ref FILE_BANS: RwLock<HashMap<String, BanList>> = RwLock::new(HashMap::new());
+}
+
+#[derive(Debug, Clone)]
+struct Ban {
+    reason: Option<String>,
+    expires: Option<Instant>,
+}
+
+impl Ban {
+    fn active(&self, now: Instant) -> bool {
+        self.expires.is_none_or(|expires| expires > now)
+    }
+
+    fn info(&self, target: &str) -> BanInfo {
+        BanInfo {
+            target: target.to_string(),
+            reason: self.reason.clone(),
+            expires_in_ms: self.expires.map(|expires| {
+                expires
+                    .saturating_duration_since(Instant::now())
+                    .as_millis() as u64
+            }),
+        }
+    }
+}
+
+/// Single addresses are looked up directly, networks one by one.
+#[derive(Default)]
+struct BanList {
+    ips: HashMap<IpAddr, Ban>,
+    nets: Vec<(Cidr, String, Ban)>,
+}
+
+impl BanList {
+    fn insert(&mut self, target: &str, ban: Ban) -> Result<()> {
+        let cidr = Cidr::parse(target)?;
+        match cidr.single() {
+            Some(ip) => {
+                self.ips.insert(ip, ban);
+            }
+            None => {
+                self.nets.retain(|(net, _, _)| *net != cidr);
+                self.nets.push((cidr, target.trim().to_string(), ban));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn remove(&mut self, target: &str) -> Result<bool> {
+        let cidr = Cidr::parse(target)?;
+        Ok(match cidr.single() {
+            Some(ip) => self.ips.remove(&ip).is_some(),
+            None => {
+                let len = self.nets.len();
+                self.nets.retain(|(net, _, _)| *net != cidr);
+                self.nets.len() != len
+            }
+        })
+    }
+
+    fn find(&self, ip: IpAddr, now: Instant) -> Option<BanInfo> {
+        if let Some(ban) = self.ips.get(&ip).filter(|ban| ban.active(now)) {
+            return Some(ban.info(&ip.to_string()));
+        }
+
+        self.nets
+            .iter()
+            .find(|(net, _, ban)| net.contains(ip) && ban.active(now))
+            .map(|(_, target, ban)| ban.info(target))
+    }
+
+    fn sweep(&mut self, now: Instant) {
+        self.ips.retain(|_, ban| ban.active(now));
+        self.nets.retain(|(_, _, ban)| ban.active(now));
+    }
+}
+
+/// Bans an address or network, banning it again replaces the expiry and
+/// reason.
+pub fn ban(target: &str, options: &BanOptions) -> Result<()> {
+    let expires = match options.ttl_ms {
+        Some(0) => color_eyre::eyre::bail!("Firewall ttl_ms has to be above 0"),
+        // too far out to represent is as good as permanent
+        Some(ttl) => Instant::now().checked_add(Duration::from_millis(ttl)),
+        None => None,
+    };
+
+    SCRIPT_BANS.write().unwrap().insert(
+        target,
+        Ban {
+            reason: options.reason.clone(),
+            expires,
+        },
+    )
+}
+
+/// Only lifts bans added by scripts, list files have to be edited.
+pub fn unban(target: &str) -> Result<bool> {
+    SCRIPT_BANS.write().unwrap().remove(target)
+}
+
+/// The ban that covers `ip`, script bans first.
+pub fn check(ip: IpAddr) -> Option<BanInfo> {
+    let ip = ip.to_canonical();
+    let now = Instant::now();
+    if let Some(ban) = SCRIPT_BANS.read().unwrap().find(ip, now) {
+        return Some(ban);
+    }
+
+    FILE_BANS
+        .read()
+        .unwrap()
+        .values()
+        .find_map(|list| list.find(ip, now))
+}
+
+/// Counts and logs the connection if `ip` is banned, the caller drops it.
+pub fn reject(ip: IpAddr) -> bool {
+    let Some(ban) = check(ip) else {
+        return false;
+    };
+
+    Stats::inc(&STATS.banned);
+    if LOG_CONNECTIONS.load(Ordering::Relaxed) {
+        println!(
+            "Banned | {} | {} | {}",
+            ip,
+            ban.target,
+            ban.reason.as_deref().unwrap_or("no reason")
+        );
+    }
+
+    true
+}
+
+/// One address or network per line, anything after `#` is the reason.
+fn parse_list(text: &str) -> Result<BanList> {
+    let mut list = BanList::default();
+    for line in text.lines() {
+        let (target, comment) = line.split_once('#').unwrap_or((line, ""));
+        if target.trim().is_empty() {
+            continue;
+        }
+
+        let comment = comment.trim();
+        list.insert(
+            target,
+            Ban {
+                reason: (!comment.is_empty()).then(|| comment.to_string()),
+                expires: None,
+            },
+        )?;
+    }
+
+    Ok(list)
+}
+
+fn load_list(path: &str) -> Result<()> {
+    let list = parse_list(&std::fs::read_to_string(path)?)?;
+    println!(
+        "Loaded {} bans from {}",
+        list.ips.len() + list.nets.len(),
+        path
+    );
+    FILE_BANS.write().unwrap().insert(path.to_string(), list);
+    Ok(())
+}
+
+/// Loads the ban list files, then reloads them whenever they change and
+/// drops expired script bans. A broken file keeps its previous bans.
+pub fn ban_list_updater(paths: Vec<String>) -> Result<()> {
+    let mut modified: HashMap<String, Option<SystemTime>> = HashMap::new();
+    for path in &paths {
+        modified.insert(path.clone(), std::fs::metadata(path)?.modified().ok());
+        load_list(path)?;
+    }
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+
+        loop {
+            interval.tick().await;
+            SCRIPT_BANS.write().unwrap().sweep(Instant::now());
+
+            for path in &paths {
+                let Ok(metadata) = tokio::fs::metadata(path).await else {
+                    continue;
+                };
+                let changed = metadata.modified().ok();
+                if modified.get(path) == Some(&changed) {
+                    continue;
+                }
+
+                modified.insert(path.clone(), changed);
+                if let Err(e) = load_list(path) {
+                    println!("Ban list {} error: {}", path, e);
+                }
+            }
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(ttl_ms: Option<u64>) -> BanOptions {
+        BanOptions {
+            ttl_ms,
+            reason: Some("test".into()),
+        }
+    }
+
+    #[test]
+    fn ttl_ms() {
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        ban("192.0.2.1", &options(Some(60_000))).unwrap();
+        let expires_in_ms = check(ip).unwrap().expires_in_ms.unwrap();
+        assert!(expires_in_ms <= 60_000 && expires_in_ms > 50_000);
+
+        ban("192.0.2.2", &options(Some(u64::MAX))).unwrap();
+        assert!(check("192.0.2.2".parse().unwrap()).is_some());
+
+        assert!(ban("192.0.2.3", &options(Some(0)))
+            .unwrap_err()
+            .to_string()
+            .contains("ttl_ms"));
+        assert!(check("192.0.2.3".parse().unwrap()).is_none());
+    }
+}
@@ -0,0 +1,212 @@
+use crate::{
+    minecraft,
+    pools::{Member, POOLS},
+    stream::Stream,
+    structs::HealthCheck,
+    upstream, utils,
+};
+use color_eyre::Result;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+const TICK: Duration = Duration::from_secs(1);
+const MAX_EXPECT_LEN: usize = 16 * 1024;
+
+/// Probes the members of every pool with a health check, each on its own
+/// interval. Runs on the main runtime, pools registered by scripts are
+/// picked up on the next tick.
+pub fn health_checker() {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(TICK);
+
+        loop {
+            interval.tick().await;
+
+            let pools = POOLS.read().unwrap().values().cloned().collect::<Vec<_>>();
+            for pool in pools {
+                let Some(check) = &pool.health_check else {
+                    continue;
+                };
+
+                let check_interval = Duration::from_millis(check.interval_ms.unwrap_or(5000));
+                for member in &pool.members {
+                    if member.health.start_check(check_interval) {
+                        tokio::spawn(check_member(member.clone(), check.clone()));
+                    }
+                }
+            }
+        }
+    });
+}
+
+/// Catches mistakes in a check before the pool is registered, instead of
+/// on every probe.
+pub fn validate(check: &HealthCheck) -> Result<()> {
+    let kind = check.kind.as_deref().unwrap_or("tcp");
+    if !["tcp", "send-expect", "minecraft"].contains(&kind) {
+        color_eyre::eyre::bail!(
+            "Unknown health check type: {}, expected \"tcp\", \"send-expect\" or \"minecraft\"",
+            kind
+        );
+    }
+
+    let payloads = [
+        ("send", &check.send, &check.send_base64),
+        ("expect", &check.expect, &check.expect_base64),
+    ];
+    for (name, text, base64) in payloads {
+        if kind != "send-expect" && (text.is_some() || base64.is_some()) {
+            color_eyre::eyre::bail!("Health check {} needs type \"send-expect\"", name);
+        } else if text.is_some() && base64.is_some() {
+            color_eyre::eyre::bail!("Health check has both {0} and {0}_base64", name);
+        }
+        utils::decode_payload(&None, text, base64)
+            .map_err(|e| color_eyre::eyre::eyre!("Health check {}_base64: {}", name, e))?;
+    }
+    let expect = utils::decode_payload(&None, &check.expect, &check.expect_base64)?;
+    if expect.len() > MAX_EXPECT_LEN {
+        color_eyre::eyre::bail!(
+            "Health check expect is longer than {} bytes",
+            MAX_EXPECT_LEN
+        );
+    }
+
+    if check.interval_ms == Some(0) || check.timeout_ms == Some(0) {
+        color_eyre::eyre::bail!("Health check interval_ms and timeout_ms have to be above 0");
+    } else if check.rise == Some(0) || check.fall == Some(0) {
+        color_eyre::eyre::bail!("Health check rise and fall have to be at least 1");
+    }
+
+    Ok(())
+}
+
+async fn check_member(member: Arc<Member>, check: HealthCheck) {
+    let timeout = Duration::from_millis(check.timeout_ms.unwrap_or(2000));
+    let res = match tokio::time::timeout(timeout, probe(&member.addr, &check)).await {
+        Ok(res) => res,
+        Err(_) => Err(color_eyre::eyre::eyre!("Health check timed out")),
+    };
+
+    let error = res.as_ref().err().map(|e| e.to_string());
+    if member
+        .health
+        .record(res, check.rise.unwrap_or(2), check.fall.unwrap_or(3))
+    {
+        match member.health.is_healthy() {
+            true => println!("Upstream {} is healthy again", member.addr),
+            false => println!(
+                "Upstream {} is unhealthy: {}",
+                member.addr,
+                error.unwrap_or_default()
+            ),
+        }
+    }
+}
+
+async fn probe(addr: &str, check: &HealthCheck) -> Result<Duration> {
+    let start = Instant::now();
+    let (mut stream, _) = upstream::dial(addr).await?;
+
+    match check.kind.as_deref().unwrap_or("tcp") {
+        "tcp" => {}
+        "send-expect" => {
+            let send = utils::decode_payload(&None, &check.send, &check.send_base64)?;
+            let expect = utils::decode_payload(&None, &check.expect, &check.expect_base64)?;
+            stream.write_all(&send).await?;
+            read_expected(&mut stream, &expect).await?;
+        }
+        "minecraft" => {
+            let (host, port) = match upstream::parse_target(addr)? {
+                // the handshake needs some host, the server behind the socket doesn't care
+                upstream::Target::Unix(_) => ("localhost", 25565),
+                upstream::Target::Host(host) => {
+                    let (host, port) = host
+                        .rsplit_once(':')
+                        .ok_or(color_eyre::eyre::eyre!("Upstream has no port"))?;
+                    (host.trim_matches(['[', ']']), port.parse()?)
+                }
+            };
+            minecraft::ping(&mut stream, host, port).await?;
+        }
+        kind => color_eyre::eyre::bail!("Unknown health check type: {}", kind),
+    }
+
+    Ok(start.elapsed())
+}
+
+/// Reads until the reply contains `expect`, an empty one only needs any reply.
+async fn read_expected(stream: &mut Stream, expect: &[u8]) -> Result<()> {
+    let mut reply = vec![];
+    let mut buf = [0u8; 4096];
+
+    loop {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            color_eyre::eyre::bail!("Connection closed before the expected reply");
+        }
+
+        reply.extend_from_slice(&buf[..n]);
+        if expect.is_empty() || reply.windows(expect.len()).any(|window| window == expect) {
+            return Ok(());
+        } else if reply.len() > MAX_EXPECT_LEN {
+            color_eyre::eyre::bail!("Reply didn't contain the expected bytes");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(json: serde_json::Value) -> Result<()> {
+        validate(&serde_json::from_value(json).unwrap())
+    }
+
+    #[test]
+    fn valid_checks() {
+        assert!(check(serde_json::json!({})).is_ok());
+        assert!(check(serde_json::json!({ "type": "minecraft", "rise": 1 })).is_ok());
+        assert!(check(serde_json::json!({
+            "type": "send-expect",
+            "send": "PING\r\n",
+            "expect_base64": "K1BPTkc=",
+        }))
+        .is_ok());
+    }
+
+    #[test]
+    fn invalid_checks() {
+        for (json, error) in [
+            (
+                serde_json::json!({ "type": "http" }),
+                "Unknown health check type: http",
+            ),
+            (
+                serde_json::json!({ "send": "PING" }),
+                "Health check send needs type \"send-expect\"",
+            ),
+            (
+                serde_json::json!({ "type": "send-expect", "expect": "a", "expect_base64": "YQ==" }),
+                "Health check has both expect and expect_base64",
+            ),
+            (
+                serde_json::json!({ "type": "send-expect", "send_base64": "not base64" }),
+                "Health check send_base64:",
+            ),
+            (
+                serde_json::json!({ "interval_ms": 0 }),
+                "Health check interval_ms and timeout_ms have to be above 0",
+            ),
+            (
+                serde_json::json!({ "fall": 0 }),
+                "Health check rise and fall have to be at least 1",
+            ),
+        ] {
+            let err = check(json).unwrap_err().to_string();
+            assert!(err.starts_with(error), "{}", err);
+        }
+    }
+}
@@ -0,0 +1,177 @@
+use crate::structs::{ClientHistory, HistoryWindow, V8Response};
+use lazy_static::lazy_static;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+/// Events kept per client, older ones are dropped even if still in a window
+const MAX_EVENTS: usize = 1024;
+
+/// Clients tracked at once, 0 turns the history off
+pub static HISTORY_MAX_CLIENTS: AtomicUsize = AtomicUsize::new(100_000);
+
+lazy_static! {
+    /// Windows every `req.history` reports on, set with `--history-windows`
+    pub static ref HISTORY_WINDOWS: RwLock<Vec<Duration>> = RwLock::new(vec![
+        Duration::from_secs(10),
+        Duration::from_secs(60),
+        Duration::from_secs(600),
+    ]);
+    static ref CLIENTS: Mutex<HashMap<IpAddr, Client>> = Mutex::new(HashMap::new());
+}
+
+struct Client {
+    first_seen: Instant,
+    last_seen: Instant,
+    connections: u64,
+    bytes_up: u64,
+    bytes_down: u64,
+    last_decision: Option<&'static str>,
+    /// Connection time and the port it was made to
+    connects: VecDeque<(Instant, u16)>,
+    /// Close time and bytes client -> upstream, upstream -> client
+    transfers: VecDeque<(Instant, u64, u64)>,
+}
+
+impl Client {
+    fn new(now: Instant) -> Self {
+        Client {
+            first_seen: now,
+            last_seen: now,
+            connections: 0,
+            bytes_up: 0,
+            bytes_down: 0,
+            last_decision: None,
+            connects: VecDeque::new(),
+            transfers: VecDeque::new(),
+        }
+    }
+
+    fn prune(&mut self, now: Instant, keep: Duration) {
+        while self.connects.len() > MAX_EVENTS
+            || self
+                .connects
+                .front()
+                .is_some_and(|(at, _)| now.duration_since(*at) > keep)
+        {
+            self.connects.pop_front();
+        }
+        while self.transfers.len() > MAX_EVENTS
+            || self
+                .transfers
+                .front()
+                .is_some_and(|(at, _, _)| now.duration_since(*at) > keep)
+        {
+            self.transfers.pop_front();
+        }
+    }
+}
+
+/// Records a new connection and returns the client's history including
+/// it, except `last_seen_ms_ago` which is the connection before.
+pub fn record_connection(ip: IpAddr, port: u16) -> Option<ClientHistory> {
+    let max_clients = HISTORY_MAX_CLIENTS.load(Ordering::Relaxed);
+    if max_clients == 0 {
+        return None;
+    }
+
+    let windows = HISTORY_WINDOWS.read().unwrap().clone();
+    let keep = windows.iter().max().copied().unwrap_or_default();
+    let now = Instant::now();
+
+    let mut clients = CLIENTS.lock().unwrap();
+    if clients.len() >= max_clients && !clients.contains_key(&ip) {
+        evict(&mut clients, max_clients);
+    }
+
+    let client = clients.entry(ip).or_insert_with(|| Client::new(now));
+    let last_seen = (client.connections > 0).then(|| now.duration_since(client.last_seen));
+    client.last_seen = now;
+    client.connections += 1;
+    client.connects.push_back((now, port));
+    client.prune(now, keep);
+
+    let windows = windows
+        .iter()
+        .map(|window| {
+            let connects = client
+                .connects
+                .iter()
+                .filter(|(at, _)| now.duration_since(*at) <= *window);
+            let transfers = client
+                .transfers
+                .iter()
+                .filter(|(at, _, _)| now.duration_since(*at) <= *window);
+
+            HistoryWindow {
+                window_sec: window.as_secs_f64(),
+                connections: connects.clone().count() as u64,
+                distinct_ports: connects
+                    .map(|(_, port)| *port)
+                    .collect::<HashSet<u16>>()
+                    .len() as u64,
+                bytes_up: transfers.clone().map(|(_, up, _)| up).sum(),
+                bytes_down: transfers.map(|(_, _, down)| down).sum(),
+            }
+        })
+        .collect();
+
+    Some(ClientHistory {
+        connections: client.connections,
+        first_seen_ms_ago: now.duration_since(client.first_seen).as_millis() as u64,
+        last_seen_ms_ago: last_seen.map(|last_seen| last_seen.as_millis() as u64),
+        bytes_up: client.bytes_up,
+        bytes_down: client.bytes_down,
+        last_decision: client.last_decision.map(|decision| decision.to_string()),
+        windows,
+    })
+}
+
+/// Remembers what the script did with the client's connection.
+pub fn record_decision(ip: IpAddr, res: &V8Response) {
+    let decision = if res.block_connection.unwrap_or(false) {
+        "block"
+    } else if res.tarpit.is_some() {
+        "tarpit"
+    } else if res.hang_connection.unwrap_or(false) {
+        "hang"
+    } else if res.minecraft_status.is_some() {
+        "minecraft_status"
+    } else if res.respond.is_some() {
+        "respond"
+    } else {
+        "proxy"
+    };
+
+    if let Some(client) = CLIENTS.lock().unwrap().get_mut(&ip) {
+        client.last_decision = Some(decision);
+    }
+}
+
+/// Adds the bytes a finished relay moved.
+pub fn record_transfer(ip: IpAddr, up: u64, down: u64) {
+    if let Some(client) = CLIENTS.lock().unwrap().get_mut(&ip) {
+        client.bytes_up += up;
+        client.bytes_down += down;
+        client.transfers.push_back((Instant::now(), up, down));
+    }
+}
+
+/// Drops the least recently seen tenth of the table.
+fn evict(clients: &mut HashMap<IpAddr, Client>, max_clients: usize) {
+    let mut last_seen = clients
+        .values()
+        .map(|client| client.last_seen)
+        .collect::<Vec<Instant>>();
+    let cutoff = (max_clients / 10).clamp(1, last_seen.len());
+    let (_, cutoff, _) = last_seen.select_nth_unstable(cutoff - 1);
+    let cutoff = *cutoff;
+
+    clients.retain(|_, client| client.last_seen > cutoff);
+}
@@ -0,0 +1,185 @@
+use color_eyre::Result;
+use lazy_static::lazy_static;
+use serde_json::Value;
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap},
+    hash::{Hash, Hasher},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+const SHARD_COUNT: usize = 16;
+/// Rough per-entry cost of the maps on top of the key and value
+const ENTRY_OVERHEAD: usize = 64;
+
+/// Memory cap of the whole store, each shard gets an equal part
+pub static KV_MAX_BYTES: AtomicUsize = AtomicUsize::new(64 * 1024 * 1024);
+
+lazy_static! {
+    static ref SHARDS: Vec<Mutex<Shard>> = (0..SHARD_COUNT)
+        .map(|_| Mutex::new(Shard::default()))
+        .collect();
+}
+
+#[derive(Default)]
+struct Shard {
+    entries: HashMap<String, Entry>,
+    /// Last use -> key, the first one is evicted first
+    lru: BTreeMap<u64, String>,
+    tick: u64,
+    used: usize,
+}
+
+struct Entry {
+    value: Value,
+    expires: Option<Instant>,
+    size: usize,
+    tick: u64,
+}
+
+impl Shard {
+    /// The live entry, marked as just used. Expired entries are removed.
+    fn get(&mut self, key: &str) -> Option<&mut Entry> {
+        let expired = self
+            .entries
+            .get(key)?
+            .expires
+            .is_some_and(|expires| expires <= Instant::now());
+        if expired {
+            self.remove(key);
+            return None;
+        }
+
+        self.tick += 1;
+        let entry = self.entries.get_mut(key)?;
+        self.lru.remove(&entry.tick);
+        self.lru.insert(self.tick, key.to_string());
+        entry.tick = self.tick;
+        Some(entry)
+    }
+
+    fn insert(&mut self, key: &str, value: Value, expires: Option<Instant>) {
+        self.remove(key);
+
+        let size = key.len() + value.to_string().len() + ENTRY_OVERHEAD;
+        self.tick += 1;
+        self.used += size;
+        self.lru.insert(self.tick, key.to_string());
+        self.entries.insert(
+            key.to_string(),
+            Entry {
+                value,
+                expires,
+                size,
+                tick: self.tick,
+            },
+        );
+
+        let max = KV_MAX_BYTES.load(Ordering::Relaxed) / SHARD_COUNT;
+        while self.used > max {
+            match self.lru.first_key_value() {
+                Some((_, key)) => {
+                    let key = key.clone();
+                    self.remove(&key);
+                }
+                None => break,
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &str) -> Option<Entry> {
+        let entry = self.entries.remove(key)?;
+        self.lru.remove(&entry.tick);
+        self.used -= entry.size;
+        Some(entry)
+    }
+}
+
+fn shard(key: &str) -> &'static Mutex<Shard> {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    &SHARDS[hasher.finish() as usize % SHARD_COUNT]
+}
+
+fn expires(ttl_ms: Option<u64>) -> Option<Instant> {
+    ttl_ms
+        .filter(|ttl| *ttl > 0)
+        .map(|ttl| Instant::now() + Duration::from_millis(ttl))
+}
+
+pub fn get(key: &str) -> Option<Value> {
+    let mut shard = shard(key).lock().unwrap();
+    shard.get(key).map(|entry| entry.value.clone())
+}
+
+/// Without a TTL the key never expires, an existing TTL is replaced.
+pub fn set(key: &str, value: Value, ttl_ms: Option<u64>) {
+    let mut shard = shard(key).lock().unwrap();
+    shard.insert(key, value, expires(ttl_ms));
+}
+
+pub fn delete(key: &str) -> bool {
+    let mut shard = shard(key).lock().unwrap();
+    shard.remove(key).is_some()
+}
+
+/// Adds `by` to the number at `key`, a missing key counts as 0 and gets
+/// the TTL. Existing keys keep their expiry.
+pub fn incr(key: &str, by: f64, ttl_ms: Option<u64>) -> Result<Value> {
+    let mut shard = shard(key).lock().unwrap();
+    let (current, expires) = match shard.get(key) {
+        Some(entry) => (entry.value.clone(), entry.expires),
+        None => (Value::from(0), expires(ttl_ms)),
+    };
+
+    let value = match (current.as_i64(), by.fract() == 0.0) {
+        (Some(current), true) => Value::from(current.saturating_add(by as i64)),
+        _ => {
+            let current = current.as_f64().ok_or(color_eyre::eyre::eyre!(
+                "KV value at {} isn't a number",
+                key
+            ))?;
+            serde_json::Number::from_f64(current + by)
+                .map(Value::Number)
+                .ok_or(color_eyre::eyre::eyre!("KV incr overflowed at {}", key))?
+        }
+    };
+
+    shard.insert(key, value.clone(), expires);
+    Ok(value)
+}
+
+/// Sets `value` only if the key currently holds `expected`, null expects
+/// a missing key.
+pub fn compare_and_swap(key: &str, expected: &Value, value: Value, ttl_ms: Option<u64>) -> bool {
+    let mut shard = shard(key).lock().unwrap();
+    let matches = match shard.get(key) {
+        Some(entry) => values_equal(&entry.value, expected),
+        None => expected.is_null(),
+    };
+
+    if matches {
+        shard.insert(key, value, expires(ttl_ms));
+    }
+
+    matches
+}
+
+/// JS numbers can arrive as integers or floats, 1 and 1.0 are the same.
+fn values_equal(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a.as_f64() == b.as_f64(),
+        (Value::Array(a), Value::Array(b)) => {
+            a.len() == b.len() && a.iter().zip(b).all(|(a, b)| values_equal(a, b))
+        }
+        (Value::Object(a), Value::Object(b)) => {
+            a.len() == b.len()
+                && a.iter()
+                    .all(|(key, a)| b.get(key).is_some_and(|b| values_equal(a, b)))
+        }
+        _ => a == b,
+    }
+}
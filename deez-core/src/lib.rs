@@ -0,0 +1,24 @@
+pub mod config;
+pub mod engine;
+pub mod filter;
+pub mod firewall;
+pub mod health;
+pub mod history;
+pub mod kv;
+pub mod listener;
+pub mod minecraft;
+pub mod pools;
+pub mod proxy_protocol;
+pub mod ratelimit;
+pub mod relay;
+pub mod respond;
+pub mod rewrite;
+pub mod sniff;
+pub mod stats;
+pub mod stream;
+pub mod structs;
+pub mod tarpit;
+pub mod tls;
+pub mod udp;
+pub mod upstream;
+pub mod utils;
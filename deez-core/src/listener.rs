@@ -0,0 +1,276 @@
+use crate::{
+    engine::Engine,
+    filter, firewall, history, minecraft, proxy_protocol, relay, respond, rewrite, sniff,
+    stats::{Stats, LOG_CONNECTIONS, STATS},
+    stream::{Listener, Stream},
+    structs::{
+        CloseInfo, ConnectionInfo, FilterConn, ListenerOptions, Tarpit, V8Request, V8Response,
+    },
+    tarpit, tls, upstream,
+    utils::ListenAddr,
+};
+use color_eyre::Result;
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+
+/// Accepts TCP or Unix socket connections and proxies them where the
+/// listener's script says.
+pub async fn port_listener(
+    addr: ListenAddr,
+    options: ListenerOptions,
+    engine: Arc<dyn Engine>,
+) -> Result<()> {
+    let options = Arc::new(options);
+    let (port, unix_socket) = match &addr {
+        ListenAddr::Unix(path) => (0, Some(path.clone())),
+        ListenAddr::Tcp(addr) | ListenAddr::Udp(addr) => (addr.port(), None),
+    };
+    let listener = Listener::bind(&addr).await?;
+    println!("Listening on: {}", addr);
+
+    loop {
+        let socket_res = listener.accept().await;
+
+        match socket_res {
+            Ok((mut socket, addr)) => {
+                Stats::inc(&STATS.connections);
+                if firewall::reject(addr.ip()) {
+                    continue;
+                }
+                if engine.overloaded() {
+                    Stats::inc(&STATS.queue_full);
+                    continue;
+                }
+
+                let options = options.clone();
+                let engine = engine.clone();
+                let unix_socket = unix_socket.clone();
+                tokio::spawn(async move {
+                    let mut conn = ConnectionInfo {
+                        client_addr: addr,
+                        local_addr: socket.local_addr()?,
+                        initial_bytes: vec![],
+                        accepted: std::time::Instant::now(),
+                    };
+
+                    let mut proxy_tlvs = None;
+                    if let Some(header) =
+                        proxy_protocol::accept_header(&mut socket, addr, &options).await?
+                    {
+                        if let Some((src, dst)) = header.addrs {
+                            conn.client_addr = src;
+                            conn.local_addr = dst;
+                        }
+                        proxy_tlvs = Some(header.tlvs);
+                    }
+                    // the real client is only known after the PROXY header
+                    if conn.client_addr != addr && firewall::reject(conn.client_addr.ip()) {
+                        return Ok(());
+                    }
+
+                    conn.initial_bytes =
+                        sniff::read_initial_bytes(&mut socket, &options.sniff).await?;
+                    let sniffed = sniff::detect(&conn.initial_bytes);
+                    let http = match sniffed.protocol {
+                        "http" => sniff::parse_http(&conn.initial_bytes),
+                        _ => None,
+                    };
+                    let tls = match tls::parse_client_hello(&conn.initial_bytes) {
+                        tls::ClientHello::Complete(info) => Some(info),
+                        _ => None,
+                    };
+                    let minecraft = match sniffed.protocol {
+                        "minecraft" => match minecraft::parse_sniffed(&conn.initial_bytes) {
+                            minecraft::Handshake::Complete(info, _) => Some(info),
+                            _ => None,
+                        },
+                        _ => None,
+                    };
+
+                    let res = engine
+                        .decide(V8Request {
+                            ip: conn.client_addr.ip().to_string(),
+                            port,
+                            protocol: sniffed.protocol.into(),
+                            unix_socket,
+                            src_port: conn.client_addr.port(),
+                            dst_ip: conn.local_addr.ip().to_string(),
+                            dst_port: conn.local_addr.port(),
+                            proxy_tlvs,
+                            initial_bytes: conn.initial_bytes.clone(),
+                            http,
+                            tls,
+                            minecraft,
+                            history: history::record_connection(
+                                conn.client_addr.ip(),
+                                conn.local_addr.port(),
+                            ),
+                        })
+                        .await?;
+
+                    if let Err(_e) = handle_client(socket, conn, res, &options, &engine).await {
+                        //println!("Handle Client Error: {}", _e);
+                    }
+
+                    Ok::<(), color_eyre::eyre::Error>(())
+                });
+            }
+            Err(e) => {
+                println!("Bind Socket Error: {}", e);
+            }
+        }
+    }
+}
+
+async fn handle_client(
+    mut socket: Stream,
+    mut conn: ConnectionInfo,
+    res: V8Response,
+    options: &ListenerOptions,
+    engine: &Arc<dyn Engine>,
+) -> Result<()> {
+    history::record_decision(conn.client_addr.ip(), &res);
+    if res.block_connection.unwrap_or(false) {
+        Stats::inc(&STATS.blocked);
+        return Ok(());
+    } else if let Some(options) = &res.tarpit {
+        return tarpit::tarpit(&mut socket, options).await;
+    } else if res.hang_connection.unwrap_or(false) {
+        return tarpit::tarpit(&mut socket, &Tarpit::default()).await;
+    }
+
+    if let Some(status) = &res.minecraft_status {
+        return minecraft::respond_status(&mut socket, &conn.initial_bytes, status).await;
+    }
+
+    if let Some(reply) = &res.respond {
+        respond::send(&mut socket, &mut conn.initial_bytes, reply).await?;
+        if reply.close_after.unwrap_or(true) {
+            return Ok(());
+        } else if res.ip.is_none() && res.upstreams.is_none() && res.pool.is_none() {
+            // keep the connection open until the client goes away
+            tokio::io::copy(&mut socket, &mut tokio::io::sink()).await?;
+            return Ok(());
+        }
+    }
+
+    if let Some(rewrite) = &res.rewrite_initial {
+        conn.initial_bytes = rewrite::apply(&conn.initial_bytes, rewrite)?;
+    }
+
+    let upstream = match upstream::candidates(&res, conn.client_addr.ip()) {
+        Ok(candidates) => upstream::connect(&candidates).await,
+        Err(e) => Err(e),
+    };
+    let upstream = match upstream {
+        Ok(upstream) => upstream,
+        Err(e) => {
+            if res.on_close {
+                engine.on_close(CloseInfo {
+                    connect_error: Some(e.to_string()),
+                    ..close_info(
+                        &conn,
+                        None,
+                        &relay::Transferred::default(),
+                        "connect_failed",
+                    )
+                });
+            }
+            return Err(e);
+        }
+    };
+    if LOG_CONNECTIONS.load(std::sync::atomic::Ordering::Relaxed) {
+        println!(
+            "Connection | {} -> {} ({})",
+            conn.client_addr, upstream.upstream, upstream.addr
+        );
+    }
+
+    let mut out_stream = upstream.stream;
+    // pool members count the connection as active until it ends
+    let _lease = upstream.lease;
+    out_stream.set_nodelay(res.no_delay.unwrap_or(false))?;
+
+    if let Some(version) = &res.proxy_protocol {
+        let header = proxy_protocol::build_header(
+            version,
+            conn.client_addr,
+            conn.local_addr,
+            res.proxy_protocol_tlvs.as_deref().unwrap_or_default(),
+        )?;
+        out_stream.write_all(&header).await?;
+    }
+    out_stream.write_all(&conn.initial_bytes).await?;
+
+    let timeouts = relay::timeouts(&options.timeouts, &res);
+    let transferred = relay::Transferred::default();
+    let filter = match res.filter.unwrap_or(false) {
+        true => Some(filter::Filter::new(
+            engine.clone(),
+            FilterConn {
+                ip: conn.client_addr.ip().to_string(),
+                src_port: conn.client_addr.port(),
+                port: conn.local_addr.port(),
+                upstream: upstream.upstream.clone(),
+                state: serde_json::json!({}),
+            },
+            res.filter_budget.as_ref(),
+        )?),
+        false => None,
+    };
+    let reason = relay::relay(
+        &mut socket,
+        &mut out_stream,
+        res.limits.as_ref(),
+        &timeouts,
+        &transferred,
+        filter.as_ref(),
+    )
+    .await;
+    history::record_transfer(
+        conn.client_addr.ip(),
+        transferred.up.load(std::sync::atomic::Ordering::Relaxed),
+        transferred.down.load(std::sync::atomic::Ordering::Relaxed),
+    );
+    if res.on_close {
+        let upstream = Some((upstream.upstream.as_str(), upstream.addr.as_str()));
+        engine.on_close(match &reason {
+            Ok(reason) => close_info(&conn, upstream, &transferred, reason.code()),
+            Err(e) => CloseInfo {
+                error: Some(e.to_string()),
+                ..close_info(&conn, upstream, &transferred, "error")
+            },
+        });
+    }
+    let reason = reason?;
+    if LOG_CONNECTIONS.load(std::sync::atomic::Ordering::Relaxed) {
+        println!(
+            "Closed | {} -> {} ({}) | {}",
+            conn.client_addr, upstream.upstream, upstream.addr, reason
+        );
+    }
+
+    Ok(())
+}
+
+/// What the script's onClose gets, without the errors.
+fn close_info(
+    conn: &ConnectionInfo,
+    upstream: Option<(&str, &str)>,
+    transferred: &relay::Transferred,
+    reason: &str,
+) -> CloseInfo {
+    CloseInfo {
+        ip: conn.client_addr.ip().to_string(),
+        src_port: conn.client_addr.port(),
+        port: conn.local_addr.port(),
+        upstream: upstream.map(|(upstream, _)| upstream.to_string()),
+        upstream_addr: upstream.map(|(_, addr)| addr.to_string()),
+        bytes_up: transferred.up.load(std::sync::atomic::Ordering::Relaxed),
+        bytes_down: transferred.down.load(std::sync::atomic::Ordering::Relaxed),
+        duration_ms: conn.accepted.elapsed().as_millis() as u64,
+        reason: reason.to_string(),
+        connect_error: None,
+        error: None,
+    }
+}
@@ -0,0 +1,656 @@
+use crate::{
+    stream::Stream,
+    structs::{MinecraftInfo, MinecraftStatus},
+};
+use color_eyre::Result;
+use serde_json::json;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+const RESPOND_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+const MAX_PACKET_LEN: usize = 32 * 1024;
+
+#[derive(Debug)]
+pub enum Handshake {
+    Incomplete,
+    Invalid,
+    /// The parsed handshake and how many bytes it took.
+    Complete(MinecraftInfo, usize),
+}
+
+pub fn parse_handshake(bytes: &[u8]) -> Handshake {
+    if bytes.first() == Some(&0xFE) {
+        return parse_legacy_ping(bytes);
+    }
+
+    let (len, len_size) = match read_varint(bytes) {
+        Some(varint) => varint,
+        None if bytes.len() < 5 => return Handshake::Incomplete,
+        None => return Handshake::Invalid,
+    };
+    if len <= 0 {
+        return Handshake::Invalid;
+    }
+    let packet_len = len_size + len as usize;
+    if bytes.len() < packet_len {
+        return Handshake::Incomplete;
+    }
+
+    let mut packet = &bytes[len_size..packet_len];
+    let info = (|| {
+        if read_varint_from(&mut packet)? != 0x00 {
+            return None;
+        }
+
+        let protocol_version = read_varint_from(&mut packet)?;
+        let address = read_string(&mut packet)?;
+        let port = packet.get(..2)?;
+        let server_port = u16::from_be_bytes([port[0], port[1]]);
+        packet = &packet[2..];
+        let next_state = read_varint_from(&mut packet)?;
+
+        let (server_address, forge) = split_address(&address);
+        Some(MinecraftInfo {
+            protocol_version,
+            server_address: Some(server_address),
+            server_port: Some(server_port),
+            next_state,
+            legacy: None,
+            forge,
+        })
+    })();
+
+    match info {
+        Some(info) => Handshake::Complete(info, packet_len),
+        None => Handshake::Invalid,
+    }
+}
+
+/// Swaps the hostname in a handshake, keeping what Forge or BungeeCord
+/// appended to it and every byte after the handshake.
+pub fn rewrite_host(bytes: &[u8], host: &str) -> Result<Vec<u8>> {
+    let Handshake::Complete(info, packet_len) = parse_sniffed(bytes) else {
+        color_eyre::eyre::bail!("Opening bytes aren't a complete Minecraft handshake");
+    };
+    if info.legacy.is_some() {
+        color_eyre::eyre::bail!("Can't rewrite the host of a legacy Minecraft ping");
+    }
+
+    let (_, len_size) = read_varint(bytes).unwrap_or_default();
+    // packet id, protocol version and address, the port and next state stay
+    let mut rest = &bytes[len_size..packet_len];
+    read_varint_from(&mut rest);
+    read_varint_from(&mut rest);
+    let address = read_string(&mut rest).unwrap_or_default();
+    let suffix = address
+        .find('\0')
+        .map(|i| &address[i..])
+        .unwrap_or_default();
+
+    let mut data = varint(info.protocol_version);
+    data.extend(string(&format!("{}{}", host, suffix)));
+    data.extend_from_slice(rest);
+
+    let mut res = packet(0x00, &data);
+    res.extend_from_slice(&bytes[packet_len..]);
+    Ok(res)
+}
+
+/// parse_handshake for the bytes the sniff ended with, a legacy ping that
+/// didn't grow into a newer one by then is all the client sends.
+pub fn parse_sniffed(bytes: &[u8]) -> Handshake {
+    match bytes {
+        [0xFE] => Handshake::Complete(legacy("beta", -1, None, None), 1),
+        [0xFE, 0x01] => Handshake::Complete(legacy("1.4", -1, None, None), 2),
+        _ => parse_handshake(bytes),
+    }
+}
+
+fn legacy(
+    variant: &'static str,
+    protocol_version: i32,
+    server_address: Option<String>,
+    server_port: Option<u16>,
+) -> MinecraftInfo {
+    MinecraftInfo {
+        protocol_version,
+        server_address,
+        server_port,
+        next_state: 1,
+        legacy: Some(variant),
+        forge: None,
+    }
+}
+
+/// Pre-1.7 clients send 0xFE (beta - 1.3), 0xFE 0x01 (1.4 - 1.5) or
+/// 0xFE 0x01 0xFA followed by a MC|PingHost plugin message (1.6).
+fn parse_legacy_ping(bytes: &[u8]) -> Handshake {
+    match bytes {
+        // both can still turn into a newer ping, the sniff timeout decides
+        [_] | [_, 0x01] => return Handshake::Incomplete,
+        [_, 0x01, 0xFA, ..] => {}
+        [_, 0x01, ..] => return Handshake::Complete(legacy("1.4", -1, None, None), 2),
+        _ => return Handshake::Complete(legacy("beta", -1, None, None), 1),
+    }
+
+    // 0xFE 0x01 0xFA, "MC|PingHost" as UTF-16BE, data length, data
+    let mut rest = &bytes[3..];
+    let channel_len = match rest.get(..2) {
+        Some(len) => u16::from_be_bytes([len[0], len[1]]) as usize * 2,
+        None => return Handshake::Incomplete,
+    };
+    rest = &rest[2..];
+    if rest.len() < channel_len + 2 {
+        return Handshake::Incomplete;
+    }
+
+    let data_len = u16::from_be_bytes([rest[channel_len], rest[channel_len + 1]]) as usize;
+    let data = &rest[channel_len + 2..];
+    if data.len() < data_len {
+        return Handshake::Incomplete;
+    }
+    let total_len = bytes.len() - data.len() + data_len;
+    let data = &data[..data_len];
+
+    // protocol version, hostname length in chars, hostname, port (int)
+    if data.len() < 3 {
+        return Handshake::Invalid;
+    }
+    let protocol_version = data[0] as i32;
+    let host_len = u16::from_be_bytes([data[1], data[2]]) as usize * 2;
+    if data.len() < 3 + host_len + 4 {
+        return Handshake::Invalid;
+    }
+
+    let host = data[3..3 + host_len]
+        .chunks(2)
+        .map(|c| u16::from_be_bytes([c[0], c[1]]))
+        .collect::<Vec<u16>>();
+    let host = String::from_utf16_lossy(&host);
+    let port = &data[3 + host_len..3 + host_len + 4];
+    let port = i32::from_be_bytes([port[0], port[1], port[2], port[3]]);
+
+    let (host, _) = split_address(&host);
+    Handshake::Complete(
+        legacy(
+            "1.6",
+            protocol_version,
+            Some(host),
+            u16::try_from(port).ok(),
+        ),
+        total_len,
+    )
+}
+
+/// Answers the client like an online server would: the server list status
+/// (plus ping / pong) for status handshakes and a Disconnect with the kick
+/// message for login attempts.
+pub async fn respond_status(
+    socket: &mut Stream,
+    initial_bytes: &[u8],
+    status: &MinecraftStatus,
+) -> Result<()> {
+    let (info, len) = match parse_sniffed(initial_bytes) {
+        Handshake::Complete(info, len) => (info, len),
+        _ => color_eyre::eyre::bail!("Connection didn't start with a minecraft handshake"),
+    };
+
+    let mut reader = PacketReader {
+        buf: initial_bytes[len..].to_vec(),
+    };
+
+    tokio::time::timeout(RESPOND_TIMEOUT, async {
+        if info.legacy.is_some() {
+            socket.write_all(&legacy_status(&info, status)).await?;
+        } else if info.next_state == 1 {
+            // status request, then an optional ping
+            reader.read_packet(socket).await?;
+            let response = status_json(&info, status).to_string();
+            socket.write_all(&packet(0x00, &string(&response))).await?;
+
+            let ping = reader.read_packet(socket).await?;
+            if ping.first() == Some(&0x01) {
+                socket.write_all(&packet(0x01, &ping[1..])).await?;
+            }
+        } else {
+            // read the login start, unread data would turn the close into a RST
+            reader.read_packet(socket).await?;
+            let message = chat(status.kick_message.as_deref().unwrap_or(&status.motd));
+            socket
+                .write_all(&packet(0x00, &string(&message.to_string())))
+                .await?;
+        }
+
+        socket.shutdown().await?;
+        Ok::<(), color_eyre::eyre::Error>(())
+    })
+    .await?
+}
+
+/// Status ping against a backend, used by the minecraft health check.
+pub async fn ping(stream: &mut Stream, host: &str, port: u16) -> Result<()> {
+    // -1 is accepted by every version for status requests
+    let mut handshake = varint(-1);
+    handshake.extend_from_slice(&string(host));
+    handshake.extend_from_slice(&port.to_be_bytes());
+    handshake.extend_from_slice(&varint(1));
+
+    let mut request = packet(0x00, &handshake);
+    request.extend_from_slice(&packet(0x00, &[]));
+    stream.write_all(&request).await?;
+
+    let mut reader = PacketReader { buf: vec![] };
+    let response = reader.read_packet(stream).await?;
+    let mut data = response.as_slice();
+    if read_varint_from(&mut data) != Some(0x00) {
+        color_eyre::eyre::bail!("Expected a status response");
+    }
+
+    let json = read_string(&mut data).ok_or(color_eyre::eyre::eyre!("Invalid status response"))?;
+    serde_json::from_str::<serde_json::Value>(&json)?;
+    Ok(())
+}
+
+fn status_json(info: &MinecraftInfo, status: &MinecraftStatus) -> serde_json::Value {
+    let mut response = json!({
+        "version": {
+            "name": status.version_name,
+            "protocol": status.version_protocol.unwrap_or(info.protocol_version),
+        },
+        "players": {
+            "max": status.max_players,
+            "online": status.online,
+            "sample": [],
+        },
+        "description": chat(&status.motd),
+    });
+
+    if let Some(favicon) = &status.favicon {
+        let favicon = match favicon.starts_with("data:") {
+            true => favicon.to_string(),
+            false => format!("data:image/png;base64,{}", favicon),
+        };
+        response["favicon"] = favicon.into();
+    }
+
+    response
+}
+
+/// Kick packet (0xFF) that pre-1.7 clients expect as the ping response.
+fn legacy_status(info: &MinecraftInfo, status: &MinecraftStatus) -> Vec<u8> {
+    let motd = plain_text(&status.motd);
+    let message = if info.legacy == Some("beta") {
+        format!("{}§{}§{}", motd, status.online, status.max_players)
+    } else {
+        // 1.4 and newer, the same reply for 1.6's MC|PingHost
+        format!(
+            "§1\0{}\0{}\0{}\0{}\0{}",
+            status.version_protocol.unwrap_or(info.protocol_version),
+            status.version_name,
+            motd,
+            status.online,
+            status.max_players
+        )
+    };
+
+    let message = message.encode_utf16().collect::<Vec<u16>>();
+    let mut res = vec![0xFF];
+    res.extend_from_slice(&(message.len() as u16).to_be_bytes());
+    for c in message {
+        res.extend_from_slice(&c.to_be_bytes());
+    }
+
+    res
+}
+
+/// Scripts can pass either plain text or a JSON chat component.
+fn chat(message: &str) -> serde_json::Value {
+    match serde_json::from_str::<serde_json::Value>(message) {
+        Ok(value) if value.is_object() || value.is_array() => value,
+        _ => json!({ "text": message }),
+    }
+}
+
+fn plain_text(message: &str) -> String {
+    fn collect(value: &serde_json::Value, out: &mut String) {
+        match value {
+            serde_json::Value::String(text) => out.push_str(text),
+            serde_json::Value::Array(parts) => parts.iter().for_each(|part| collect(part, out)),
+            serde_json::Value::Object(obj) => {
+                if let Some(text) = obj.get("text") {
+                    collect(text, out);
+                }
+                if let Some(extra) = obj.get("extra") {
+                    collect(extra, out);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let mut out = String::new();
+    collect(&chat(message), &mut out);
+    out
+}
+
+struct PacketReader {
+    buf: Vec<u8>,
+}
+
+impl PacketReader {
+    /// Returns the next packet (id + data) without its length prefix.
+    async fn read_packet(&mut self, socket: &mut Stream) -> Result<Vec<u8>> {
+        loop {
+            if let Some((len, len_size)) = read_varint(&self.buf) {
+                let len = usize::try_from(len)?;
+                if len > MAX_PACKET_LEN {
+                    color_eyre::eyre::bail!("Minecraft packet is too big ({} bytes)", len);
+                } else if self.buf.len() >= len_size + len {
+                    let packet = self.buf[len_size..len_size + len].to_vec();
+                    self.buf.drain(..len_size + len);
+                    return Ok(packet);
+                }
+            }
+
+            let mut chunk = [0u8; 1024];
+            let n = socket.read(&mut chunk).await?;
+            if n == 0 {
+                color_eyre::eyre::bail!("Connection closed mid packet");
+            }
+            self.buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+}
+
+fn packet(id: i32, data: &[u8]) -> Vec<u8> {
+    let mut body = varint(id);
+    body.extend_from_slice(data);
+
+    let mut res = varint(body.len() as i32);
+    res.extend_from_slice(&body);
+    res
+}
+
+fn string(value: &str) -> Vec<u8> {
+    let mut res = varint(value.len() as i32);
+    res.extend_from_slice(value.as_bytes());
+    res
+}
+
+fn varint(value: i32) -> Vec<u8> {
+    let mut value = value as u32;
+    let mut res = vec![];
+    loop {
+        if value & !0x7F == 0 {
+            res.push(value as u8);
+            return res;
+        }
+
+        res.push((value & 0x7F) as u8 | 0x80);
+        value >>= 7;
+    }
+}
+
+/// Forge appends "\0FML\0" (or FML2 / FML3) to the hostname and BungeeCord
+/// IP forwarding appends "\0ip\0uuid...", only the part before is the host.
+/// Hostnames resolved through SRV records may also end with a dot.
+fn split_address(address: &str) -> (String, Option<String>) {
+    let mut parts = address.split('\0');
+    let host = parts.next().unwrap_or_default();
+    let forge = parts
+        .find(|part| part.starts_with("FML"))
+        .map(|part| part.to_string());
+
+    let host = host.trim_end_matches('.').to_lowercase();
+    (host, forge)
+}
+
+pub fn read_varint(bytes: &[u8]) -> Option<(i32, usize)> {
+    let mut value = 0i32;
+    for (i, byte) in bytes.iter().take(5).enumerate() {
+        value |= ((byte & 0x7F) as i32) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+
+    None
+}
+
+pub fn read_varint_from(bytes: &mut &[u8]) -> Option<i32> {
+    let (value, len) = read_varint(bytes)?;
+    *bytes = &bytes[len..];
+    Some(value)
+}
+
+pub fn read_string(bytes: &mut &[u8]) -> Option<String> {
+    let len = usize::try_from(read_varint_from(bytes)?).ok()?;
+    let string = bytes.get(..len)?;
+    *bytes = &bytes[len..];
+    Some(String::from_utf8_lossy(string).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status() -> MinecraftStatus {
+        MinecraftStatus {
+            motd: "A Server".into(),
+            max_players: 20,
+            online: 5,
+            version_name: "1.6.4".into(),
+            version_protocol: None,
+            favicon: None,
+            kick_message: None,
+        }
+    }
+
+    fn utf16(text: &str) -> Vec<u8> {
+        text.encode_utf16().flat_map(|c| c.to_be_bytes()).collect()
+    }
+
+    fn kick(message: &str) -> Vec<u8> {
+        let mut res = vec![0xFF];
+        res.extend_from_slice(&(message.encode_utf16().count() as u16).to_be_bytes());
+        res.extend(utf16(message));
+        res
+    }
+
+    /// What a 1.6 client sends, 0xFE 0x01 0xFA and the MC|PingHost message
+    fn ping_host(protocol: u8, host: &str, port: i32) -> Vec<u8> {
+        let mut data = vec![protocol];
+        data.extend_from_slice(&(host.encode_utf16().count() as u16).to_be_bytes());
+        data.extend(utf16(host));
+        data.extend_from_slice(&port.to_be_bytes());
+
+        let mut res = vec![0xFE, 0x01, 0xFA, 0x00, 0x0B];
+        res.extend(utf16("MC|PingHost"));
+        res.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        res.extend(data);
+        res
+    }
+
+    /// Feeds the ping one byte at a time like a slow client, every prefix
+    /// has to wait for more.
+    fn parse_fed(bytes: &[u8]) -> (MinecraftInfo, usize) {
+        for end in 1..bytes.len() {
+            assert!(
+                matches!(parse_handshake(&bytes[..end]), Handshake::Incomplete),
+                "{} of {} bytes",
+                end,
+                bytes.len()
+            );
+        }
+        match parse_sniffed(bytes) {
+            Handshake::Complete(info, len) => (info, len),
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn beta_ping() {
+        let (info, len) = parse_fed(&[0xFE]);
+        assert_eq!(len, 1);
+        assert_eq!(info.legacy, Some("beta"));
+        assert_eq!(info.protocol_version, -1);
+        assert!(matches!(parse_handshake(&[0xFE]), Handshake::Incomplete));
+
+        assert_eq!(legacy_status(&info, &status()), kick("A Server§5§20"));
+    }
+
+    #[test]
+    fn beta_ping_followed_by_other_bytes() {
+        let Handshake::Complete(info, len) = parse_handshake(&[0xFE, 0x00]) else {
+            panic!();
+        };
+        assert_eq!((info.legacy, len), (Some("beta"), 1));
+    }
+
+    #[test]
+    fn one_four_ping() {
+        let (info, len) = parse_fed(&[0xFE, 0x01]);
+        assert_eq!(len, 2);
+        assert_eq!(info.legacy, Some("1.4"));
+        assert_eq!(info.server_address, None);
+
+        assert_eq!(
+            legacy_status(&info, &status()),
+            kick(&["§1", "-1", "1.6.4", "A Server", "5", "20"].join("\0"))
+        );
+        let status = MinecraftStatus {
+            version_protocol: Some(61),
+            ..status()
+        };
+        assert_eq!(
+            legacy_status(&info, &status),
+            kick(&["§1", "61", "1.6.4", "A Server", "5", "20"].join("\0"))
+        );
+    }
+
+    #[test]
+    fn one_six_ping() {
+        let ping = ping_host(78, "Play.Example.com", 25565);
+        let (info, len) = parse_fed(&ping);
+        assert_eq!(len, ping.len());
+        assert_eq!(info.legacy, Some("1.6"));
+        assert_eq!(info.protocol_version, 78);
+        assert_eq!(info.server_address.as_deref(), Some("play.example.com"));
+        assert_eq!(info.server_port, Some(25565));
+
+        assert_eq!(
+            legacy_status(&info, &status()),
+            kick(&["§1", "78", "1.6.4", "A Server", "5", "20"].join("\0"))
+        );
+    }
+
+    #[test]
+    fn one_six_ping_with_bad_data() {
+        let mut ping = ping_host(78, "host", 25565);
+        // host length past the end of the data
+        let host_len = ping.len() - 4 - 8 - 2;
+        ping[host_len] = 0x40;
+        assert!(matches!(parse_handshake(&ping), Handshake::Invalid));
+    }
+
+    fn handshake(protocol: i32, host: &str, port: u16, next_state: i32) -> Vec<u8> {
+        let mut data = varint(protocol);
+        data.extend(string(host));
+        data.extend_from_slice(&port.to_be_bytes());
+        data.extend(varint(next_state));
+        packet(0x00, &data)
+    }
+
+    #[test]
+    fn varints() {
+        for (value, len) in [
+            (0, 1),
+            (127, 1),
+            (128, 2),
+            (25565, 3),
+            (i32::MAX, 5),
+            (-1, 5),
+        ] {
+            assert_eq!(read_varint(&varint(value)), Some((value, len)));
+        }
+
+        assert_eq!(read_varint(&[]), None);
+        assert_eq!(read_varint(&[0x80]), None);
+        assert_eq!(read_varint(&[0x80; 6]), None);
+    }
+
+    #[test]
+    fn strings() {
+        let mut bytes = &[string("abc"), vec![0x01]].concat()[..];
+        assert_eq!(read_string(&mut bytes).as_deref(), Some("abc"));
+        assert_eq!(bytes, [0x01]);
+
+        // longer than what's there, or a negative length
+        assert_eq!(read_string(&mut &[0x04, b'a', b'b'][..]), None);
+        assert_eq!(read_string(&mut &varint(-1)[..]), None);
+    }
+
+    #[test]
+    fn modern_handshake() {
+        let mut bytes = handshake(763, "Play.Example.com.", 25565, 2);
+        let packet_len = bytes.len();
+        // the login start that follows isn't part of it
+        bytes.extend(packet(0x00, &string("Notch")));
+
+        let (info, len) = parse_fed(&bytes[..packet_len]);
+        assert_eq!(len, packet_len);
+        assert_eq!(info.protocol_version, 763);
+        assert_eq!(info.server_address.as_deref(), Some("play.example.com"));
+        assert_eq!(info.server_port, Some(25565));
+        assert_eq!(info.next_state, 2);
+        assert!(info.legacy.is_none() && info.forge.is_none());
+
+        let Handshake::Complete(_, len) = parse_handshake(&bytes) else {
+            panic!();
+        };
+        assert_eq!(len, packet_len);
+    }
+
+    #[test]
+    fn forge_handshake() {
+        let bytes = handshake(47, "mc.example.com\0FML2\0", 25565, 2);
+        let (info, _) = parse_fed(&bytes);
+        assert_eq!(info.server_address.as_deref(), Some("mc.example.com"));
+        assert_eq!(info.forge.as_deref(), Some("FML2"));
+    }
+
+    #[test]
+    fn invalid_handshakes() {
+        let wrong_id = packet(0x01, &handshake(763, "a", 1, 1)[2..]);
+        let zero_len = vec![0x00, 0x00];
+        let negative_len = varint(-1);
+        let long_len = vec![0x80; 5];
+        // the hostname claims more bytes than the packet has
+        let mut long_host = handshake(47, "a", 1, 1);
+        long_host[3] = 0x20;
+
+        for bytes in [wrong_id, zero_len, negative_len, long_len, long_host] {
+            assert!(
+                matches!(parse_handshake(&bytes), Handshake::Invalid),
+                "{:?}",
+                bytes
+            );
+        }
+    }
+
+    #[test]
+    fn rewrites_host() {
+        let mut bytes = handshake(763, "old.example.com\0FML3\0", 25565, 2);
+        bytes.extend(packet(0x00, &string("Notch")));
+
+        let rewritten = rewrite_host(&bytes, "new.example.com").unwrap();
+        let mut expected = handshake(763, "new.example.com\0FML3\0", 25565, 2);
+        expected.extend(packet(0x00, &string("Notch")));
+        assert_eq!(rewritten, expected);
+    }
+
+    #[test]
+    fn rewrite_host_needs_a_modern_handshake() {
+        assert!(rewrite_host(&[0xFE, 0x01], "example.com").is_err());
+        assert!(rewrite_host(&handshake(763, "a", 1, 1)[..4], "example.com").is_err());
+    }
+}
@@ -0,0 +1,365 @@
+use crate::{
+    health,
+    structs::{HealthCheck, HealthStatus, PoolConfig, Upstream},
+};
+use color_eyre::Result;
+use lazy_static::lazy_static;
+use rand::Rng;
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, Instant},
+};
+
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Weight of the newest sample in the connect latency average
+const EWMA_ALPHA: f64 = 0.3;
+
+lazy_static! {
+    /// Shared by every worker, scripts only see pools by name
+    pub static ref POOLS: RwLock<HashMap<String, Arc<Pool>>> = RwLock::new(HashMap::new());
+    /// Health of every pool member by address, members of different pools
+    /// with the same address share it
+    pub static ref HEALTH: RwLock<HashMap<String, Arc<Health>>> = RwLock::new(HashMap::new());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Strategy {
+    RoundRobin,
+    Weighted,
+    LeastConnections,
+    ConsistentHash,
+    Ewma,
+}
+
+impl Strategy {
+    pub fn parse(name: &str) -> Result<Self> {
+        Ok(match name {
+            "round-robin" => Strategy::RoundRobin,
+            "weighted" => Strategy::Weighted,
+            "least-connections" => Strategy::LeastConnections,
+            "consistent-hash" => Strategy::ConsistentHash,
+            "ewma" => Strategy::Ewma,
+            _ => color_eyre::eyre::bail!("Unknown pool strategy: {}", name),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct Pool {
+    pub strategy: Strategy,
+    pub members: Vec<Arc<Member>>,
+    pub health_check: Option<HealthCheck>,
+    next: AtomicUsize,
+}
+
+#[derive(Debug)]
+pub struct Member {
+    pub addr: String,
+    pub weight: u32,
+    pub timeout: Duration,
+    pub active: AtomicU64,
+    /// Average connect latency in microseconds, 0 until the first attempt
+    pub ewma_us: AtomicU64,
+    pub health: Arc<Health>,
+}
+
+impl Member {
+    fn new(upstream: &Upstream) -> Self {
+        Member {
+            addr: upstream.addr.clone(),
+            weight: upstream.weight.unwrap_or(1),
+            timeout: upstream
+                .connect_timeout_ms
+                .map(Duration::from_millis)
+                .unwrap_or(DEFAULT_CONNECT_TIMEOUT),
+            active: AtomicU64::new(0),
+            ewma_us: AtomicU64::new(0),
+            health: HEALTH
+                .write()
+                .unwrap()
+                .entry(upstream.addr.clone())
+                .or_insert_with(|| Arc::new(Health::default()))
+                .clone(),
+        }
+    }
+
+    /// Failed attempts should pass the timeout, so slow and dead members
+    /// sink to the end of "ewma" pools.
+    pub fn record_latency(&self, latency: Duration) {
+        let sample = latency.as_micros() as f64;
+        let _ = self
+            .ewma_us
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |ewma| {
+                Some(match ewma {
+                    0 => sample as u64,
+                    _ => (ewma as f64 * (1.0 - EWMA_ALPHA) + sample * EWMA_ALPHA) as u64,
+                })
+            });
+    }
+
+    pub fn lease(self: &Arc<Self>) -> Lease {
+        self.active.fetch_add(1, Ordering::Relaxed);
+        Lease(self.clone())
+    }
+}
+
+/// Counts as an active connection of the member until dropped
+pub struct Lease(Arc<Member>);
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Members start out healthy and flip after `rise` / `fall` consecutive
+/// probe results.
+#[derive(Debug)]
+pub struct Health {
+    pub healthy: AtomicBool,
+    state: Mutex<HealthState>,
+}
+
+#[derive(Debug, Default)]
+struct HealthState {
+    successes: u32,
+    failures: u32,
+    running: bool,
+    last_check: Option<Instant>,
+    last_error: Option<String>,
+    latency: Option<Duration>,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Health {
+            healthy: AtomicBool::new(true),
+            state: Mutex::new(HealthState::default()),
+        }
+    }
+}
+
+impl Health {
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    /// Claims the next probe if it's due and no other one is running.
+    pub fn start_check(&self, interval: Duration) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.running
+            || state
+                .last_check
+                .is_some_and(|last| last.elapsed() < interval)
+        {
+            return false;
+        }
+
+        state.running = true;
+        state.last_check = Some(Instant::now());
+        true
+    }
+
+    /// Returns true if the result flipped the member's health.
+    pub fn record(&self, res: Result<Duration>, rise: u32, fall: u32) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.running = false;
+        match res {
+            Ok(latency) => {
+                state.successes += 1;
+                state.failures = 0;
+                state.latency = Some(latency);
+                state.last_error = None;
+            }
+            Err(e) => {
+                state.successes = 0;
+                state.failures += 1;
+                state.last_error = Some(e.to_string());
+            }
+        }
+
+        let healthy = self.is_healthy();
+        if healthy && state.failures >= fall || !healthy && state.successes >= rise {
+            self.healthy.store(!healthy, Ordering::Relaxed);
+            return true;
+        }
+
+        false
+    }
+
+    pub fn status(&self, addr: &str) -> HealthStatus {
+        let state = self.state.lock().unwrap();
+        HealthStatus {
+            addr: addr.to_string(),
+            healthy: self.is_healthy(),
+            checked: state.successes + state.failures > 0,
+            successes: state.successes,
+            failures: state.failures,
+            last_check_ms: state
+                .last_check
+                .map(|last| last.elapsed().as_millis() as u64),
+            last_error: state.last_error.clone(),
+            latency_ms: state.latency.map(|latency| latency.as_secs_f64() * 1000.0),
+        }
+    }
+}
+
+/// Health of the upstream with this address, None if no pool has it.
+pub fn status(addr: &str) -> Option<HealthStatus> {
+    HEALTH
+        .read()
+        .unwrap()
+        .get(addr)
+        .map(|health| health.status(addr))
+}
+
+/// Adds or replaces a pool. Scripts run this for every connection, so an
+/// unchanged definition is a no-op and members that stay keep their state.
+pub fn register(name: &str, config: &PoolConfig) -> Result<()> {
+    let strategy = Strategy::parse(config.strategy.as_deref().unwrap_or("round-robin"))?;
+    if config.upstreams.is_empty() {
+        color_eyre::eyre::bail!("Pool {} has no upstreams", name);
+    }
+    if let Some(check) = &config.health_check {
+        health::validate(check).map_err(|e| color_eyre::eyre::eyre!("Pool {}: {}", name, e))?;
+    }
+
+    let mut pools = POOLS.write().unwrap();
+    let old = pools.get(name);
+    let members = config
+        .upstreams
+        .iter()
+        .map(|upstream| {
+            let member = Member::new(upstream);
+            old.and_then(|old| {
+                old.members.iter().find(|old| {
+                    old.addr == member.addr
+                        && old.weight == member.weight
+                        && old.timeout == member.timeout
+                })
+            })
+            .cloned()
+            .unwrap_or_else(|| Arc::new(member))
+        })
+        .collect::<Vec<Arc<Member>>>();
+
+    let unchanged = old.is_some_and(|old| {
+        old.strategy == strategy
+            && old.health_check == config.health_check
+            && old.members.len() == members.len()
+            && old
+                .members
+                .iter()
+                .zip(&members)
+                .all(|(a, b)| Arc::ptr_eq(a, b))
+    });
+    if !unchanged {
+        pools.insert(
+            name.to_string(),
+            Arc::new(Pool {
+                strategy,
+                members,
+                health_check: config.health_check.clone(),
+                next: AtomicUsize::new(0),
+            }),
+        );
+    }
+
+    Ok(())
+}
+
+pub fn remove(name: &str) -> bool {
+    POOLS.write().unwrap().remove(name).is_some()
+}
+
+/// Pools declared with `--pools <file>`, a JSON object of name -> pool.
+pub fn load_file(path: &str) -> Result<()> {
+    let pools: HashMap<String, PoolConfig> = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+    for (name, config) in &pools {
+        register(name, config)?;
+    }
+
+    println!("Loaded {} pools from {}", pools.len(), path);
+    Ok(())
+}
+
+/// Every healthy member of the pool, the strategy's pick first and the
+/// rest in the order they should be tried if it fails.
+pub fn select(name: &str, client_ip: IpAddr) -> Result<Vec<Arc<Member>>> {
+    let pool = POOLS
+        .read()
+        .unwrap()
+        .get(name)
+        .cloned()
+        .ok_or(color_eyre::eyre::eyre!("Pool {} doesn't exist", name))?;
+
+    let mut members = pool
+        .members
+        .iter()
+        .filter(|member| member.health.is_healthy())
+        .cloned()
+        .collect::<Vec<Arc<Member>>>();
+    if members.is_empty() {
+        color_eyre::eyre::bail!("Pool {} has no healthy members", name);
+    }
+
+    match pool.strategy {
+        Strategy::RoundRobin => {
+            let start = pool.next.fetch_add(1, Ordering::Relaxed) % members.len();
+            members.rotate_left(start);
+        }
+        Strategy::Weighted => {
+            members = weighted_shuffle(members, |member| member.weight);
+        }
+        Strategy::LeastConnections => {
+            // rotate first so ties are spread instead of hitting the first member
+            let start = pool.next.fetch_add(1, Ordering::Relaxed) % members.len();
+            members.rotate_left(start);
+            members.retain(|member| member.weight > 0);
+            members.sort_by_key(|member| {
+                member.active.load(Ordering::Relaxed) * 1000 / member.weight as u64
+            });
+        }
+        Strategy::ConsistentHash => {
+            // weighted rendezvous hashing, members only move when they're removed
+            let score = |member: &Arc<Member>| {
+                let mut hasher = DefaultHasher::new();
+                (client_ip, &member.addr).hash(&mut hasher);
+                let hash = (hasher.finish() as f64 + 1.0) / (u64::MAX as f64 + 2.0);
+                -(member.weight as f64) / hash.ln()
+            };
+            members.retain(|member| member.weight > 0);
+            members.sort_by(|a, b| score(b).total_cmp(&score(a)));
+        }
+        Strategy::Ewma => {
+            let start = pool.next.fetch_add(1, Ordering::Relaxed) % members.len();
+            members.rotate_left(start);
+            members.sort_by_key(|member| member.ewma_us.load(Ordering::Relaxed));
+        }
+    }
+
+    Ok(members)
+}
+
+/// Random order where every item's chance to come first is proportional to
+/// its weight (key u^(1/weight), highest first). Zero weights are dropped.
+pub fn weighted_shuffle<T>(items: Vec<T>, weight: impl Fn(&T) -> u32) -> Vec<T> {
+    let mut rng = rand::thread_rng();
+    let mut keyed = items
+        .into_iter()
+        .filter(|item| weight(item) > 0)
+        .map(|item| {
+            let key = rng.gen::<f64>().powf(1.0 / weight(&item) as f64);
+            (key, item)
+        })
+        .collect::<Vec<(f64, T)>>();
+
+    keyed.sort_by(|a, b| b.0.total_cmp(&a.0));
+    keyed.into_iter().map(|(_, item)| item).collect()
+}
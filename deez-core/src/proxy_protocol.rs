@@ -0,0 +1,582 @@
+use crate::{
+    stream::Stream,
+    structs::{AcceptProxy, ListenerOptions, ProxyTlv},
+};
+use color_eyre::Result;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::AsyncReadExt;
+
+pub const V2_SIGNATURE: [u8; 12] = [
+    0x0D, 0x0A, 0x0D, 0x0A, 0x00, 0x0D, 0x0A, 0x51, 0x55, 0x49, 0x54, 0x0A,
+];
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+const V1_MAX_LEN: usize = 107;
+
+#[derive(Debug, Default)]
+pub struct ProxyHeader {
+    /// `None` for LOCAL / UNKNOWN headers, the socket addresses are kept then.
+    pub addrs: Option<(SocketAddr, SocketAddr)>,
+    pub tlvs: Vec<ProxyTlv>,
+}
+
+/// Reads the inbound PROXY header according to the listener options.
+/// Returns `None` when the header is optional and the client didn't send one.
+pub async fn accept_header(
+    socket: &mut Stream,
+    peer: SocketAddr,
+    options: &ListenerOptions,
+) -> Result<Option<ProxyHeader>> {
+    if options.accept_proxy == AcceptProxy::Off {
+        return Ok(None);
+    }
+
+    // only local processes can reach a unix socket
+    let trusted = socket.is_unix()
+        || options
+            .trusted_proxies
+            .iter()
+            .any(|cidr| cidr.contains(peer.ip()));
+
+    let mut read = vec![];
+    let version =
+        tokio::time::timeout(options.proxy_timeout, detect_version(socket, &mut read)).await;
+    // the header is read again from the start, anything else belongs to
+    // the connection
+    socket.unread(&read);
+    let version = match version {
+        Ok(version) => version?,
+        Err(_) if options.accept_proxy == AcceptProxy::Optional => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    match version {
+        Some(_) if !trusted => {
+            color_eyre::eyre::bail!("PROXY header from untrusted peer {}", peer)
+        }
+        Some(version) => {
+            let header =
+                tokio::time::timeout(options.proxy_timeout, read_header(socket, version)).await??;
+            Ok(Some(header))
+        }
+        None if options.accept_proxy == AcceptProxy::Required => {
+            color_eyre::eyre::bail!("Missing required PROXY header from {}", peer)
+        }
+        None => Ok(None),
+    }
+}
+
+/// Reads until it is clear whether a v1 or v2 header follows, at most the
+/// length of the v2 signature.
+async fn detect_version(socket: &mut Stream, read: &mut Vec<u8>) -> Result<Option<u8>> {
+    loop {
+        if read.starts_with(&V2_SIGNATURE) {
+            return Ok(Some(2));
+        } else if read.starts_with(V1_PREFIX) {
+            return Ok(Some(1));
+        } else if !V2_SIGNATURE.starts_with(read) && !V1_PREFIX.starts_with(read) {
+            return Ok(None);
+        }
+
+        // partial signature, wait for the rest of it
+        let mut chunk = [0u8; V2_SIGNATURE.len()];
+        let n = socket
+            .read(&mut chunk[..V2_SIGNATURE.len() - read.len()])
+            .await?;
+        if n == 0 {
+            color_eyre::eyre::bail!("Connection closed before PROXY header");
+        }
+        read.extend_from_slice(&chunk[..n]);
+    }
+}
+
+async fn read_header(socket: &mut Stream, version: u8) -> Result<ProxyHeader> {
+    if version == 1 {
+        let mut line = Vec::with_capacity(V1_MAX_LEN);
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_LEN {
+                color_eyre::eyre::bail!("PROXY v1 header is too long");
+            }
+            line.push(socket.read_u8().await?);
+        }
+
+        return parse_v1(&line);
+    }
+
+    let mut fixed = [0u8; 16];
+    socket.read_exact(&mut fixed).await?;
+    let len = u16::from_be_bytes([fixed[14], fixed[15]]) as usize;
+    let mut body = vec![0u8; len];
+    socket.read_exact(&mut body).await?;
+
+    parse_v2(fixed[12], fixed[13], &body)
+}
+
+pub fn parse_v1(line: &[u8]) -> Result<ProxyHeader> {
+    let line = std::str::from_utf8(line)?.trim_end();
+    let parts = line.split(' ').collect::<Vec<&str>>();
+
+    match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(ProxyHeader::default()),
+        ["PROXY", family @ ("TCP4" | "TCP6"), src_ip, dst_ip, src_port, dst_port] => {
+            // both addresses have to be of the family the header names
+            let ip = |ip: &str| -> Result<IpAddr> {
+                Ok(match *family {
+                    "TCP4" => ip.parse::<Ipv4Addr>()?.into(),
+                    _ => ip.parse::<Ipv6Addr>()?.into(),
+                })
+            };
+            let src = SocketAddr::new(ip(src_ip)?, src_port.parse()?);
+            let dst = SocketAddr::new(ip(dst_ip)?, dst_port.parse()?);
+
+            Ok(ProxyHeader {
+                addrs: Some((src, dst)),
+                tlvs: vec![],
+            })
+        }
+        _ => color_eyre::eyre::bail!("Malformed PROXY v1 header: {:?}", line),
+    }
+}
+
+pub fn parse_v2(ver_cmd: u8, family: u8, body: &[u8]) -> Result<ProxyHeader> {
+    if ver_cmd >> 4 != 2 {
+        color_eyre::eyre::bail!("Unsupported PROXY version {}", ver_cmd >> 4);
+    }
+
+    let addrs_len = match family >> 4 {
+        0x1 => 12,
+        0x2 => 36,
+        0x3 => 216,
+        _ => 0,
+    };
+    if body.len() < addrs_len {
+        color_eyre::eyre::bail!("PROXY v2 header is truncated");
+    }
+
+    let addrs = match family >> 4 {
+        // only the PROXY command carries addresses, LOCAL is a health check
+        _ if ver_cmd & 0x0F != 0x01 => None,
+        0x1 => {
+            let src_ip = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            let dst_ip = Ipv4Addr::new(body[4], body[5], body[6], body[7]);
+            Some((
+                SocketAddr::new(src_ip.into(), u16::from_be_bytes([body[8], body[9]])),
+                SocketAddr::new(dst_ip.into(), u16::from_be_bytes([body[10], body[11]])),
+            ))
+        }
+        0x2 => {
+            let src_ip = Ipv6Addr::from(<[u8; 16]>::try_from(&body[0..16])?);
+            let dst_ip = Ipv6Addr::from(<[u8; 16]>::try_from(&body[16..32])?);
+            Some((
+                SocketAddr::new(src_ip.into(), u16::from_be_bytes([body[32], body[33]])),
+                SocketAddr::new(dst_ip.into(), u16::from_be_bytes([body[34], body[35]])),
+            ))
+        }
+        _ => None,
+    };
+
+    let mut tlvs = vec![];
+    let mut rest = &body[addrs_len..];
+    while rest.len() >= 3 {
+        let len = u16::from_be_bytes([rest[1], rest[2]]) as usize;
+        if rest.len() < 3 + len {
+            color_eyre::eyre::bail!("PROXY v2 TLV is truncated");
+        }
+
+        tlvs.push(ProxyTlv {
+            kind: rest[0],
+            value: rest[3..3 + len].to_vec(),
+        });
+        rest = &rest[3 + len..];
+    }
+
+    Ok(ProxyHeader { addrs, tlvs })
+}
+
+/// Builds the PROXY header requested by the script ("v1" or "v2").
+pub fn build_header(
+    version: &str,
+    src: SocketAddr,
+    dst: SocketAddr,
+    tlvs: &[ProxyTlv],
+) -> Result<Vec<u8>> {
+    match version {
+        "v1" => Ok(encode_v1(src, dst)),
+        "v2" => encode_v2(src, dst, tlvs),
+        _ => Err(color_eyre::eyre::eyre!(
+            "Unknown proxy_protocol version: {}",
+            version
+        )),
+    }
+}
+
+pub fn encode_v1(src: SocketAddr, dst: SocketAddr) -> Vec<u8> {
+    let (src_ip, dst_ip) = same_family(src.ip(), dst.ip());
+    let family = match src_ip {
+        IpAddr::V4(_) => "TCP4",
+        IpAddr::V6(_) => "TCP6",
+    };
+
+    format!(
+        "PROXY {} {} {} {} {}\r\n",
+        family,
+        src_ip,
+        dst_ip,
+        src.port(),
+        dst.port()
+    )
+    .into_bytes()
+}
+
+pub fn encode_v2(src: SocketAddr, dst: SocketAddr, tlvs: &[ProxyTlv]) -> Result<Vec<u8>> {
+    let mut body = vec![];
+    let family = match same_family(src.ip(), dst.ip()) {
+        (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+            body.extend_from_slice(&src_ip.octets());
+            body.extend_from_slice(&dst_ip.octets());
+            0x11
+        }
+        (IpAddr::V6(src_ip), IpAddr::V6(dst_ip)) => {
+            body.extend_from_slice(&src_ip.octets());
+            body.extend_from_slice(&dst_ip.octets());
+            0x21
+        }
+        _ => unreachable!(),
+    };
+    body.extend_from_slice(&src.port().to_be_bytes());
+    body.extend_from_slice(&dst.port().to_be_bytes());
+
+    for tlv in tlvs {
+        let value = tlv.value.as_slice();
+        let len = u16::try_from(value.len())
+            .map_err(|_| color_eyre::eyre::eyre!("TLV {:#04x} value is too long", tlv.kind))?;
+
+        body.push(tlv.kind);
+        body.extend_from_slice(&len.to_be_bytes());
+        body.extend_from_slice(value);
+    }
+
+    let len = u16::try_from(body.len())
+        .map_err(|_| color_eyre::eyre::eyre!("PROXY v2 header is too long"))?;
+
+    let mut header = Vec::with_capacity(16 + body.len());
+    header.extend_from_slice(&V2_SIGNATURE);
+    header.push(0x21); // version 2, PROXY command
+    header.push(family);
+    header.extend_from_slice(&len.to_be_bytes());
+    header.extend_from_slice(&body);
+
+    Ok(header)
+}
+
+/// Both addresses in a PROXY header must share a family, so mixed pairs are
+/// sent as IPv6 with the IPv4 side mapped into it.
+fn same_family(src: IpAddr, dst: IpAddr) -> (IpAddr, IpAddr) {
+    match (src, dst) {
+        (IpAddr::V4(src), IpAddr::V6(dst)) => (IpAddr::V6(src.to_ipv6_mapped()), IpAddr::V6(dst)),
+        (IpAddr::V6(src), IpAddr::V4(dst)) => (IpAddr::V6(src), IpAddr::V6(dst.to_ipv6_mapped())),
+        (src, dst) => (src, dst),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(addr: &str) -> SocketAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn v1_ipv4() {
+        let header = encode_v1(addr("192.0.2.1:51000"), addr("198.51.100.2:25565"));
+        assert_eq!(header, b"PROXY TCP4 192.0.2.1 198.51.100.2 51000 25565\r\n");
+    }
+
+    #[test]
+    fn v1_ipv6() {
+        let header = encode_v1(addr("[2001:db8::1]:51000"), addr("[2001:db8::2]:443"));
+        assert_eq!(header, b"PROXY TCP6 2001:db8::1 2001:db8::2 51000 443\r\n");
+    }
+
+    #[test]
+    fn v1_mixed_families_are_sent_as_ipv6() {
+        let header = encode_v1(addr("192.0.2.1:51000"), addr("[2001:db8::2]:443"));
+        assert_eq!(
+            header,
+            b"PROXY TCP6 ::ffff:192.0.2.1 2001:db8::2 51000 443\r\n"
+        );
+    }
+
+    #[test]
+    fn v2_ipv4_layout() {
+        let tlvs = [ProxyTlv {
+            kind: 0x01,
+            value: b"h2".to_vec(),
+        }];
+        let header = encode_v2(addr("192.0.2.1:51000"), addr("198.51.100.2:25565"), &tlvs).unwrap();
+
+        let mut expected = V2_SIGNATURE.to_vec();
+        expected.extend_from_slice(&[0x21, 0x11, 0x00, 12 + 5]);
+        expected.extend_from_slice(&[192, 0, 2, 1, 198, 51, 100, 2]);
+        expected.extend_from_slice(&51000u16.to_be_bytes());
+        expected.extend_from_slice(&25565u16.to_be_bytes());
+        expected.extend_from_slice(&[0x01, 0x00, 0x02, b'h', b'2']);
+        assert_eq!(header, expected);
+    }
+
+    #[test]
+    fn v2_ipv6_family_and_length() {
+        let header = encode_v2(addr("[2001:db8::1]:1"), addr("[2001:db8::2]:2"), &[]).unwrap();
+        assert_eq!(header[13], 0x21);
+        assert_eq!(u16::from_be_bytes([header[14], header[15]]), 36);
+        assert_eq!(header.len(), 16 + 36);
+    }
+
+    #[test]
+    fn v2_tlv_too_long() {
+        let tlvs = [ProxyTlv {
+            kind: 0xE0,
+            value: vec![0; 65536],
+        }];
+        assert!(encode_v2(addr("192.0.2.1:1"), addr("192.0.2.2:2"), &tlvs).is_err());
+    }
+
+    #[test]
+    fn unknown_version() {
+        let res = build_header("v3", addr("192.0.2.1:1"), addr("192.0.2.2:2"), &[]);
+        assert!(res.is_err());
+    }
+
+    fn parse_encoded(header: &[u8]) -> ProxyHeader {
+        parse_v2(header[12], header[13], &header[16..]).unwrap()
+    }
+
+    #[test]
+    fn v1_parse() {
+        let header = parse_v1(b"PROXY TCP4 192.0.2.1 198.51.100.2 51000 25565\r\n").unwrap();
+        assert_eq!(
+            header.addrs,
+            Some((addr("192.0.2.1:51000"), addr("198.51.100.2:25565")))
+        );
+
+        let header = parse_v1(b"PROXY TCP6 2001:db8::1 2001:db8::2 51000 443\r\n").unwrap();
+        assert_eq!(
+            header.addrs,
+            Some((addr("[2001:db8::1]:51000"), addr("[2001:db8::2]:443")))
+        );
+
+        assert!(parse_v1(b"PROXY UNKNOWN\r\n").unwrap().addrs.is_none());
+    }
+
+    #[test]
+    fn v1_malformed() {
+        for line in [
+            &b"PROXY TCP4 192.0.2.1 198.51.100.2 51000\r\n"[..],
+            b"PROXY TCP4 192.0.2.1 198.51.100.2 51000 70000\r\n",
+            b"PROXY TCP4 192.0.2.300 198.51.100.2 1 2\r\n",
+            b"PROXY UDP4 192.0.2.1 198.51.100.2 1 2\r\n",
+            b"PROXY TCP4 \xff 198.51.100.2 1 2\r\n",
+            b"PROXY TCP4 2001:db8::1 198.51.100.2 1 2\r\n",
+            b"PROXY TCP4 192.0.2.1 2001:db8::2 1 2\r\n",
+            b"PROXY TCP6 192.0.2.1 2001:db8::2 1 2\r\n",
+        ] {
+            assert!(parse_v1(line).is_err(), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn v1_round_trip() {
+        let (src, dst) = (addr("192.0.2.1:51000"), addr("198.51.100.2:25565"));
+        let header = parse_v1(&encode_v1(src, dst)).unwrap();
+        assert_eq!(header.addrs, Some((src, dst)));
+    }
+
+    #[test]
+    fn v2_round_trip() {
+        let tlvs = [
+            ProxyTlv {
+                kind: 0x01,
+                value: b"h2".to_vec(),
+            },
+            // not UTF-8, has to come back unchanged
+            ProxyTlv {
+                kind: 0xE0,
+                value: vec![0x00, 0xFF, 0xC3, 0x28],
+            },
+        ];
+        for (src, dst) in [
+            ("192.0.2.1:51000", "198.51.100.2:25565"),
+            ("[2001:db8::1]:51000", "[2001:db8::2]:443"),
+        ] {
+            let (src, dst) = (addr(src), addr(dst));
+            let header = parse_encoded(&encode_v2(src, dst, &tlvs).unwrap());
+            assert_eq!(header.addrs, Some((src, dst)));
+            assert_eq!(header.tlvs.len(), 2);
+            for (parsed, tlv) in header.tlvs.iter().zip(&tlvs) {
+                assert_eq!((parsed.kind, &parsed.value), (tlv.kind, &tlv.value));
+            }
+
+            // what a script reads can be sent on as it is
+            let again = encode_v2(src, dst, &header.tlvs).unwrap();
+            assert_eq!(again, encode_v2(src, dst, &tlvs).unwrap());
+        }
+    }
+
+    #[test]
+    fn v2_round_trip_mixed_families() {
+        let (src, dst) = (addr("192.0.2.1:51000"), addr("[2001:db8::2]:443"));
+        let header = encode_v2(src, dst, &[]).unwrap();
+        assert_eq!(header[13], 0x21);
+
+        let mapped = addr("[::ffff:192.0.2.1]:51000");
+        assert_eq!(parse_encoded(&header).addrs, Some((mapped, dst)));
+        let header = encode_v2(dst, src, &[]).unwrap();
+        let mapped = addr("[::ffff:192.0.2.1]:51000");
+        assert_eq!(parse_encoded(&header).addrs, Some((dst, mapped)));
+    }
+
+    #[test]
+    fn v2_local_command_has_no_addresses() {
+        let mut header = encode_v2(addr("192.0.2.1:1"), addr("192.0.2.2:2"), &[]).unwrap();
+        header[12] = 0x20;
+        assert!(parse_encoded(&header).addrs.is_none());
+    }
+
+    #[test]
+    fn v2_truncated() {
+        let header = encode_v2(addr("[2001:db8::1]:1"), addr("[2001:db8::2]:2"), &[]).unwrap();
+        let body = &header[16..];
+        for len in 0..36 {
+            assert!(parse_v2(0x21, 0x21, &body[..len]).is_err(), "{} bytes", len);
+        }
+    }
+
+    #[test]
+    fn v2_malformed_tlv_length() {
+        let tlvs = [ProxyTlv {
+            kind: 0x01,
+            value: b"h2".to_vec(),
+        }];
+        let mut header = encode_v2(addr("192.0.2.1:1"), addr("192.0.2.2:2"), &tlvs).unwrap();
+        // the TLV claims more bytes than the header has
+        header[16 + 12 + 2] = 0x09;
+        assert!(parse_v2(header[12], header[13], &header[16..]).is_err());
+    }
+
+    #[test]
+    fn v2_unsupported_version() {
+        let mut header = encode_v2(addr("192.0.2.1:1"), addr("192.0.2.2:2"), &[]).unwrap();
+        header[12] = 0x11;
+        assert!(parse_v2(header[12], header[13], &header[16..]).is_err());
+    }
+
+    #[test]
+    fn tlv_value_from_text_or_bytes() {
+        let text: ProxyTlv = serde_json::from_str(r#"{"type": 1, "value": "h2"}"#).unwrap();
+        let bytes: ProxyTlv = serde_json::from_str(r#"{"type": 1, "value": [104, 50]}"#).unwrap();
+        assert_eq!(text.value, b"h2");
+        assert_eq!(bytes.value, b"h2");
+    }
+
+    mod accept {
+        use super::*;
+        use crate::{
+            structs::{SniffOptions, Timeouts},
+            utils::Cidr,
+        };
+        use std::time::Duration;
+        use tokio::{
+            io::AsyncWriteExt,
+            net::{TcpListener, TcpStream},
+        };
+
+        fn options(accept_proxy: AcceptProxy, trusted: &str) -> ListenerOptions {
+            ListenerOptions {
+                accept_proxy,
+                trusted_proxies: vec![Cidr::parse(trusted).unwrap()],
+                sniff: SniffOptions {
+                    max_bytes: 4096,
+                    timeout: Duration::from_millis(500),
+                },
+                timeouts: Timeouts {
+                    idle: Duration::ZERO,
+                    max_lifetime: Duration::ZERO,
+                    half_close_linger: Duration::ZERO,
+                },
+                proxy_timeout: Duration::from_millis(200),
+            }
+        }
+
+        /// Accepts a connection that sent `sent`, the client stays open.
+        async fn accept(
+            sent: &[u8],
+            options: &ListenerOptions,
+        ) -> (Result<Option<ProxyHeader>>, Stream, TcpStream) {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let mut client = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            client.write_all(sent).await.unwrap();
+            let (socket, peer) = listener.accept().await.unwrap();
+
+            let mut socket = Stream::tcp(socket);
+            let res = accept_header(&mut socket, peer, options).await;
+            (res, socket, client)
+        }
+
+        async fn rest(socket: &mut Stream, len: usize) -> Vec<u8> {
+            let mut buf = vec![0u8; len];
+            socket.read_exact(&mut buf).await.unwrap();
+            buf
+        }
+
+        #[tokio::test]
+        async fn optional_without_header_keeps_the_bytes() {
+            let options = options(AcceptProxy::Optional, "127.0.0.1");
+            let (res, mut socket, _client) = accept(b"PRX and more", &options).await;
+            assert!(res.unwrap().is_none());
+            assert_eq!(rest(&mut socket, 12).await, b"PRX and more");
+        }
+
+        #[tokio::test]
+        async fn optional_gives_up_on_silent_clients() {
+            let options = options(AcceptProxy::Optional, "127.0.0.1");
+            let started = std::time::Instant::now();
+            let (res, _socket, _client) = accept(b"", &options).await;
+            assert!(res.unwrap().is_none());
+            assert!(started.elapsed() < Duration::from_secs(1));
+
+            let options = ListenerOptions {
+                accept_proxy: AcceptProxy::Required,
+                ..options
+            };
+            assert!(accept(b"", &options).await.0.is_err());
+        }
+
+        #[tokio::test]
+        async fn header_is_consumed() {
+            let (src, dst) = (addr("192.0.2.1:51000"), addr("198.51.100.2:25565"));
+            let mut sent = encode_v2(src, dst, &[]).unwrap();
+            sent.extend_from_slice(b"hello");
+
+            let options = options(AcceptProxy::Required, "127.0.0.1");
+            let (res, mut socket, _client) = accept(&sent, &options).await;
+            assert_eq!(res.unwrap().unwrap().addrs, Some((src, dst)));
+            assert_eq!(rest(&mut socket, 5).await, b"hello");
+        }
+
+        #[tokio::test]
+        async fn untrusted_peer() {
+            let options = options(AcceptProxy::Optional, "10.0.0.0/8");
+            let sent = encode_v1(addr("192.0.2.1:1"), addr("192.0.2.2:2"));
+            assert!(accept(&sent, &options).await.0.is_err());
+        }
+
+        #[tokio::test]
+        async fn required_without_header() {
+            let options = options(AcceptProxy::Required, "127.0.0.1");
+            assert!(accept(b"GET / HTTP/1.1\r\n", &options).await.0.is_err());
+        }
+    }
+}
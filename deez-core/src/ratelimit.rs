@@ -0,0 +1,304 @@
+use crate::structs::{RateLimitOptions, RateLimitPeriod, RateLimitResult};
+use color_eyre::Result;
+use lazy_static::lazy_static;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// Entries are only swept once the map doubled since the last sweep
+const MIN_SWEEP_LEN: usize = 1024;
+
+lazy_static! {
+    static ref LIMITERS: Mutex<Limiters> = Mutex::new(Limiters {
+        entries: HashMap::new(),
+        next_sweep: MIN_SWEEP_LEN,
+    });
+}
+
+struct Limiters {
+    entries: HashMap<String, Entry>,
+    next_sweep: usize,
+}
+
+struct Entry {
+    state: State,
+    /// After this the entry is back to its initial state and can go, never
+    /// if that's further out than an Instant reaches
+    idle_at: Option<Instant>,
+}
+
+enum State {
+    TokenBucket {
+        tokens: f64,
+        last: Instant,
+    },
+    /// Approximated with the previous and current fixed window
+    SlidingWindow {
+        start: Instant,
+        previous: u64,
+        current: u64,
+    },
+}
+
+/// Takes `cost` (default 1) from the key's budget of `rate` per `per`.
+/// Denied checks don't take anything.
+pub fn check(key: &str, options: &RateLimitOptions) -> Result<RateLimitResult> {
+    let per = parse_period(&options.per)?;
+    if options.rate == 0 || per.is_zero() {
+        color_eyre::eyre::bail!("RateLimit rate and per have to be above 0");
+    }
+
+    let sliding = match options.algorithm.as_deref().unwrap_or("token-bucket") {
+        "token-bucket" => false,
+        "sliding-window" => true,
+        algorithm => color_eyre::eyre::bail!("Unknown RateLimit algorithm: {}", algorithm),
+    };
+    // a check that costs more than the bucket holds could never pass
+    let cost = options.cost.unwrap_or(1);
+    let capacity = match sliding {
+        true => options.rate,
+        false => options.burst.unwrap_or(options.rate),
+    };
+    if cost > capacity {
+        color_eyre::eyre::bail!(
+            "RateLimit cost {} is above the {} the limit holds",
+            cost,
+            capacity
+        );
+    }
+
+    let now = Instant::now();
+    let mut limiters = LIMITERS.lock().unwrap();
+    if limiters.entries.len() >= limiters.next_sweep {
+        limiters
+            .entries
+            .retain(|_, entry| entry.idle_at.is_none_or(|idle_at| idle_at > now));
+        limiters.next_sweep = MIN_SWEEP_LEN.max(limiters.entries.len() * 2);
+    }
+
+    let entry = limiters
+        .entries
+        .entry(key.to_string())
+        .or_insert_with(|| Entry {
+            state: initial_state(sliding, options, now),
+            idle_at: Some(now),
+        });
+    if sliding != matches!(entry.state, State::SlidingWindow { .. }) {
+        entry.state = initial_state(sliding, options, now);
+    }
+
+    let (res, idle_at) = match &mut entry.state {
+        State::TokenBucket { tokens, last } => {
+            let capacity = capacity as f64;
+            let refill = options.rate as f64 / per.as_secs_f64();
+            *tokens = (*tokens + now.duration_since(*last).as_secs_f64() * refill).min(capacity);
+            *last = now;
+
+            let allowed = *tokens >= cost as f64;
+            let retry_after = match allowed {
+                true => {
+                    *tokens -= cost as f64;
+                    Duration::ZERO
+                }
+                false => wait((cost as f64 - *tokens) / refill),
+            };
+
+            let full_in = wait((capacity - *tokens) / refill);
+            (
+                RateLimitResult {
+                    allowed,
+                    remaining: *tokens as u64,
+                    retry_after_ms: millis(retry_after),
+                    limit: capacity as u64,
+                },
+                now.checked_add(full_in),
+            )
+        }
+        State::SlidingWindow {
+            start,
+            previous,
+            current,
+        } => {
+            let since = now.duration_since(*start);
+            if since >= per {
+                *previous = if since < per.saturating_mul(2) {
+                    *current
+                } else {
+                    0
+                };
+                *current = 0;
+                *start = now - Duration::from_nanos((since.as_nanos() % per.as_nanos()) as u64);
+            }
+
+            let elapsed = now.duration_since(*start).as_secs_f64() / per.as_secs_f64();
+            let estimate = *previous as f64 * (1.0 - elapsed) + *current as f64;
+            let allowed = estimate + cost as f64 <= options.rate as f64;
+            let retry_after = if allowed {
+                *current += cost;
+                Duration::ZERO
+            } else if *current + cost > options.rate || *previous == 0 {
+                // the previous window alone can't free enough
+                per.saturating_sub(now.duration_since(*start))
+            } else {
+                let free = (options.rate - *current - cost) as f64 / *previous as f64;
+                per.mul_f64((1.0 - free - elapsed).max(0.0))
+            };
+
+            let estimate = *previous as f64 * (1.0 - elapsed) + *current as f64;
+            (
+                RateLimitResult {
+                    allowed,
+                    remaining: (options.rate as f64 - estimate).max(0.0) as u64,
+                    retry_after_ms: millis(retry_after),
+                    limit: options.rate,
+                },
+                start.checked_add(per.saturating_mul(2)),
+            )
+        }
+    };
+
+    entry.idle_at = idle_at;
+    Ok(res)
+}
+
+fn initial_state(sliding: bool, options: &RateLimitOptions, now: Instant) -> State {
+    match sliding {
+        true => State::SlidingWindow {
+            start: now,
+            previous: 0,
+            current: 0,
+        },
+        false => State::TokenBucket {
+            tokens: options.burst.unwrap_or(options.rate) as f64,
+            last: now,
+        },
+    }
+}
+
+/// Seconds to wait, saturating where the script's numbers overflow.
+fn wait(secs: f64) -> Duration {
+    Duration::try_from_secs_f64(secs).unwrap_or(Duration::MAX)
+}
+
+fn millis(duration: Duration) -> u64 {
+    u64::try_from(duration.as_millis()).unwrap_or(u64::MAX)
+}
+
+/// Milliseconds or a number with a unit: "500ms", "10s", "1m", "1h", "1d".
+fn parse_period(per: &RateLimitPeriod) -> Result<Duration> {
+    let text = match per {
+        RateLimitPeriod::Ms(ms) => {
+            return Duration::try_from_secs_f64(ms / 1000.0)
+                .map_err(|_| color_eyre::eyre::eyre!("Invalid RateLimit period: {}ms", ms))
+        }
+        RateLimitPeriod::Text(text) => text.trim(),
+    };
+
+    let split = text
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(text.len());
+    let (value, unit) = text.split_at(split);
+    let value: f64 = value
+        .parse()
+        .map_err(|_| color_eyre::eyre::eyre!("Invalid RateLimit period: {}", text))?;
+
+    let unit = match unit.trim() {
+        "ms" => 0.001,
+        "s" => 1.0,
+        "m" => 60.0,
+        "h" => 3600.0,
+        "d" => 86400.0,
+        _ => color_eyre::eyre::bail!("Invalid RateLimit period: {}", text),
+    };
+
+    Duration::try_from_secs_f64(value * unit)
+        .map_err(|_| color_eyre::eyre::eyre!("Invalid RateLimit period: {}", text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(rate: u64, per: RateLimitPeriod) -> RateLimitOptions {
+        RateLimitOptions {
+            rate,
+            per,
+            burst: None,
+            algorithm: None,
+            cost: None,
+        }
+    }
+
+    fn error(key: &str, options: &RateLimitOptions) -> String {
+        check(key, options).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn zero_rate() {
+        let options = options(0, RateLimitPeriod::Text("1s".into()));
+        assert_eq!(
+            error("zero_rate", &options),
+            "RateLimit rate and per have to be above 0"
+        );
+    }
+
+    #[test]
+    fn periods_that_overflow() {
+        for per in [
+            RateLimitPeriod::Ms(f64::INFINITY),
+            RateLimitPeriod::Ms(f64::NAN),
+            RateLimitPeriod::Ms(-1.0),
+            RateLimitPeriod::Ms(1e300),
+            RateLimitPeriod::Text("99999999999999999999999d".into()),
+        ] {
+            let options = options(1, per);
+            assert!(
+                error("periods_that_overflow", &options).starts_with("Invalid RateLimit period")
+            );
+        }
+    }
+
+    #[test]
+    fn cost_above_burst() {
+        let mut options = options(10, RateLimitPeriod::Text("1s".into()));
+        options.burst = Some(5);
+        options.cost = Some(6);
+        assert_eq!(
+            error("cost_above_burst", &options),
+            "RateLimit cost 6 is above the 5 the limit holds"
+        );
+
+        // the window holds `rate`, burst doesn't apply
+        options.algorithm = Some("sliding-window".into());
+        assert!(check("cost_above_burst", &options).unwrap().allowed);
+        options.cost = Some(11);
+        assert!(check("cost_above_burst", &options).is_err());
+    }
+
+    #[test]
+    fn waits_too_long_for_a_duration_saturate() {
+        // one token every 10^15 seconds, the whole bucket takes longer than
+        // a Duration can hold
+        let mut options = options(1, RateLimitPeriod::Text("1000000000000000s".into()));
+        options.burst = Some(u64::MAX);
+        options.cost = Some(u64::MAX);
+
+        let first = check("saturate", &options).unwrap();
+        assert!(first.allowed);
+        let second = check("saturate", &options).unwrap();
+        assert!(!second.allowed);
+        assert_eq!(second.retry_after_ms, u64::MAX);
+    }
+
+    #[test]
+    fn sliding_window_with_a_long_period() {
+        let mut options = options(2, RateLimitPeriod::Ms(1e21));
+        options.algorithm = Some("sliding-window".into());
+        assert!(check("long_window", &options).unwrap().allowed);
+        assert!(check("long_window", &options).unwrap().allowed);
+        let denied = check("long_window", &options).unwrap();
+        assert!(!denied.allowed && denied.retry_after_ms > 0);
+    }
+}
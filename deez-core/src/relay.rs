@@ -0,0 +1,385 @@
+use crate::{
+    filter::{Filter, Verdict},
+    stats::{Stats, STATS},
+    stream::Stream,
+    structs::{Limits, Timeouts, V8Response},
+};
+use color_eyre::Result;
+use lazy_static::lazy_static;
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::Notify,
+    time::Instant,
+};
+
+const BUF_SIZE: usize = 8 * 1024;
+
+lazy_static! {
+    /// Limiters of keyed limits, alive while a connection uses them
+    static ref SHARED_LIMITERS: Mutex<HashMap<String, Weak<Limiter>>> = Mutex::new(HashMap::new());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CloseReason {
+    /// Both sides closed their end
+    Done,
+    IdleTimeout,
+    MaxLifetime,
+    HalfCloseLinger,
+    QuotaExceeded,
+    /// The script's filter closed it
+    Filter,
+}
+
+impl CloseReason {
+    /// How scripts see the reason in onClose
+    pub fn code(self) -> &'static str {
+        match self {
+            CloseReason::Done => "done",
+            CloseReason::IdleTimeout => "idle_timeout",
+            CloseReason::MaxLifetime => "max_lifetime",
+            CloseReason::HalfCloseLinger => "half_close_linger",
+            CloseReason::QuotaExceeded => "quota_exceeded",
+            CloseReason::Filter => "filter",
+        }
+    }
+
+    fn count(self) {
+        match self {
+            CloseReason::Done => {}
+            CloseReason::IdleTimeout => Stats::inc(&STATS.closed_idle),
+            CloseReason::MaxLifetime => Stats::inc(&STATS.closed_lifetime),
+            CloseReason::HalfCloseLinger => Stats::inc(&STATS.closed_linger),
+            CloseReason::QuotaExceeded => Stats::inc(&STATS.closed_quota),
+            CloseReason::Filter => Stats::inc(&STATS.closed_filter),
+        }
+    }
+}
+
+impl std::fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            CloseReason::Done => "closed by peers",
+            CloseReason::IdleTimeout => "idle timeout",
+            CloseReason::MaxLifetime => "max lifetime",
+            CloseReason::HalfCloseLinger => "half-close linger",
+            CloseReason::QuotaExceeded => "byte quota exceeded",
+            CloseReason::Filter => "closed by filter",
+        })
+    }
+}
+
+#[derive(Debug)]
+struct QuotaExceeded;
+
+impl std::fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Byte quota exceeded")
+    }
+}
+
+impl std::error::Error for QuotaExceeded {}
+
+#[derive(Debug)]
+struct FilterClosed;
+
+impl std::fmt::Display for FilterClosed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Closed by filter")
+    }
+}
+
+impl std::error::Error for FilterClosed {}
+
+/// Bytes relayed so far, client -> upstream and upstream -> client
+#[derive(Debug, Default)]
+pub struct Transferred {
+    pub up: AtomicU64,
+    pub down: AtomicU64,
+}
+
+/// Last time data moved and when the first side closed
+struct Activity {
+    last: Mutex<Instant>,
+    half_closed: Mutex<Option<Instant>>,
+    /// Wakes the watchdog so it can start the linger timer
+    closing: Notify,
+}
+
+struct Bucket {
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn new(rate: u64, burst: u64) -> Self {
+        Bucket {
+            rate: rate as f64,
+            burst: burst as f64,
+            tokens: burst as f64,
+            last: Instant::now(),
+        }
+    }
+
+    /// Takes `n` tokens, going into debt if there aren't enough. Returns
+    /// how long to wait until the debt is paid off.
+    fn take(&mut self, n: usize) -> Duration {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+        self.last = now;
+
+        self.tokens -= n as f64;
+        match self.tokens < 0.0 {
+            true => Duration::from_secs_f64(-self.tokens / self.rate),
+            false => Duration::ZERO,
+        }
+    }
+}
+
+pub struct Limiter {
+    up: Option<Mutex<Bucket>>,
+    down: Option<Mutex<Bucket>>,
+    transferred: AtomicU64,
+    max_total_bytes: Option<u64>,
+}
+
+impl Limiter {
+    fn new(limits: &Limits) -> Self {
+        let bucket = |rate: Option<u64>| {
+            rate.filter(|rate| *rate > 0)
+                .map(|rate| Mutex::new(Bucket::new(rate, limits.burst.unwrap_or(rate).max(1))))
+        };
+
+        Limiter {
+            up: bucket(limits.up_bps),
+            down: bucket(limits.down_bps),
+            transferred: AtomicU64::new(0),
+            max_total_bytes: limits.max_total_bytes,
+        }
+    }
+
+    /// Connections with the same key share one limiter (buckets and quota)
+    /// for as long as any of them is open.
+    pub fn get(limits: &Limits) -> Arc<Limiter> {
+        let Some(key) = &limits.key else {
+            return Arc::new(Limiter::new(limits));
+        };
+
+        let mut shared = SHARED_LIMITERS.lock().unwrap();
+        if let Some(limiter) = shared.get(key).and_then(Weak::upgrade) {
+            return limiter;
+        }
+
+        shared.retain(|_, limiter| limiter.strong_count() > 0);
+        let limiter = Arc::new(Limiter::new(limits));
+        shared.insert(key.clone(), Arc::downgrade(&limiter));
+        limiter
+    }
+
+    fn count(&self, n: usize) -> Result<()> {
+        let total = self.transferred.fetch_add(n as u64, Ordering::Relaxed) + n as u64;
+        if self.max_total_bytes.is_some_and(|max| total > max) {
+            return Err(QuotaExceeded.into());
+        }
+
+        Ok(())
+    }
+}
+
+/// The listener's timeouts with the script's overrides applied.
+pub fn timeouts(defaults: &Timeouts, res: &V8Response) -> Timeouts {
+    let pick = |ms: Option<u64>, default| ms.map(Duration::from_millis).unwrap_or(default);
+    Timeouts {
+        idle: pick(res.idle_timeout_ms, defaults.idle),
+        max_lifetime: pick(res.max_lifetime_ms, defaults.max_lifetime),
+        half_close_linger: pick(res.half_close_linger_ms, defaults.half_close_linger),
+    }
+}
+
+/// Relays between the client and the upstream until both sides are done or
+/// a timer fires, shaping and counting the traffic if the script set
+/// limits and running it through the script's filter if there is one.
+/// Timers, the quota and the filter close both sides with a FIN.
+pub async fn relay(
+    client: &mut Stream,
+    upstream: &mut Stream,
+    limits: Option<&Limits>,
+    timeouts: &Timeouts,
+    transferred: &Transferred,
+    filter: Option<&Filter>,
+) -> Result<CloseReason> {
+    let limiter = limits.map(Limiter::get);
+    let activity = Activity {
+        last: Mutex::new(Instant::now()),
+        half_closed: Mutex::new(None),
+        closing: Notify::new(),
+    };
+
+    let res = {
+        let (mut client_read, mut client_write) = tokio::io::split(&mut *client);
+        let (mut upstream_read, mut upstream_write) = tokio::io::split(&mut *upstream);
+
+        let limiter = limiter.as_deref();
+        let up = limiter.and_then(|limiter| limiter.up.as_ref());
+        let down = limiter.and_then(|limiter| limiter.down.as_ref());
+        let copy = async {
+            tokio::try_join!(
+                pipe(
+                    &mut client_read,
+                    &mut upstream_write,
+                    limiter,
+                    up,
+                    &transferred.up,
+                    &activity,
+                    filter.map(|filter| (filter, "client")),
+                ),
+                pipe(
+                    &mut upstream_read,
+                    &mut client_write,
+                    limiter,
+                    down,
+                    &transferred.down,
+                    &activity,
+                    filter.map(|filter| (filter, "server")),
+                ),
+            )
+        };
+
+        tokio::select! {
+            res = copy => match res {
+                Ok(_) => Ok(CloseReason::Done),
+                Err(e) if e.downcast_ref::<QuotaExceeded>().is_some() => {
+                    Ok(CloseReason::QuotaExceeded)
+                }
+                Err(e) if e.downcast_ref::<FilterClosed>().is_some() => Ok(CloseReason::Filter),
+                Err(e) => Err(e),
+            },
+            reason = watchdog(&activity, timeouts) => Ok(reason),
+        }
+    };
+
+    if let Ok(reason) = res {
+        reason.count();
+        if reason != CloseReason::Done {
+            let _ = client.shutdown().await;
+            let _ = upstream.shutdown().await;
+        }
+    }
+
+    res
+}
+
+/// Resolves with the first timer that fires, never if all are disabled.
+async fn watchdog(activity: &Activity, timeouts: &Timeouts) -> CloseReason {
+    let start = Instant::now();
+
+    loop {
+        let now = Instant::now();
+        let last = *activity.last.lock().unwrap();
+        let half_closed = *activity.half_closed.lock().unwrap();
+
+        let mut timers = vec![
+            (
+                start + timeouts.max_lifetime,
+                timeouts.max_lifetime,
+                CloseReason::MaxLifetime,
+            ),
+            (
+                last + timeouts.idle,
+                timeouts.idle,
+                CloseReason::IdleTimeout,
+            ),
+        ];
+        if let Some(half_closed) = half_closed {
+            let linger = timeouts.half_close_linger;
+            timers.push((half_closed + linger, linger, CloseReason::HalfCloseLinger));
+        }
+        timers.retain(|(_, duration, _)| !duration.is_zero());
+
+        if let Some((_, _, reason)) = timers.iter().find(|(deadline, _, _)| *deadline <= now) {
+            return *reason;
+        }
+
+        let wake = timers.iter().map(|(deadline, _, _)| *deadline).min();
+        let sleep = async {
+            match wake {
+                Some(wake) => tokio::time::sleep_until(wake).await,
+                None => std::future::pending().await,
+            }
+        };
+
+        tokio::select! {
+            _ = sleep => {}
+            _ = activity.closing.notified(), if half_closed.is_none() => {}
+        }
+    }
+}
+
+/// Copies one direction and passes the EOF on as a half close.
+async fn pipe<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    limiter: Option<&Limiter>,
+    bucket: Option<&Mutex<Bucket>>,
+    counter: &AtomicU64,
+    activity: &Activity,
+    filter: Option<(&Filter, &'static str)>,
+) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let buf_size = match bucket {
+        Some(bucket) => BUF_SIZE.min(bucket.lock().unwrap().burst as usize),
+        None => BUF_SIZE,
+    };
+    let mut buf = vec![0u8; buf_size];
+
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            activity
+                .half_closed
+                .lock()
+                .unwrap()
+                .get_or_insert(Instant::now());
+            activity.closing.notify_one();
+            writer.shutdown().await?;
+            return Ok(());
+        }
+        *activity.last.lock().unwrap() = Instant::now();
+        counter.fetch_add(n as u64, Ordering::Relaxed);
+
+        if let Some(limiter) = limiter {
+            limiter.count(n)?;
+        }
+        if let Some(bucket) = bucket {
+            let wait = bucket.lock().unwrap().take(n);
+            if !wait.is_zero() {
+                tokio::time::sleep(wait).await;
+            }
+        }
+
+        let verdict = match filter {
+            Some((filter, direction)) => filter.run(direction, &buf[..n]).await?,
+            None => Verdict::Pass,
+        };
+        match verdict {
+            Verdict::Pass => writer.write_all(&buf[..n]).await?,
+            Verdict::Replace(data) => writer.write_all(&data).await?,
+            Verdict::Drop => {}
+            Verdict::Close => return Err(FilterClosed.into()),
+        }
+    }
+}
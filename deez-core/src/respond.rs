@@ -0,0 +1,48 @@
+use crate::{stream::Stream, structs::Respond, utils};
+use color_eyre::Result;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Sends the script's static reply. Up to `discard_input` bytes of client
+/// input are read before and after it (lingering close), closing a socket
+/// with unread data would send a RST and the client could lose the reply.
+pub async fn send(
+    socket: &mut Stream,
+    initial_bytes: &mut Vec<u8>,
+    respond: &Respond,
+) -> Result<()> {
+    let payload = utils::decode_payload(&respond.bytes, &respond.text, &respond.base64)?;
+    let discard = respond.discard_input.unwrap_or(0);
+    let timeout = Duration::from_millis(respond.discard_timeout_ms.unwrap_or(1000));
+
+    // the sniffed bytes were already read, they count as discarded input
+    let discarded = initial_bytes.len().min(discard);
+    initial_bytes.drain(..discarded);
+    let mut budget = discard - discarded;
+
+    let mut buf = vec![0u8; 4096];
+    if budget > 0 && discarded == 0 {
+        // wait for the request so the reply doesn't come before it
+        let len = budget.min(buf.len());
+        if let Ok(Ok(n)) = tokio::time::timeout(timeout, socket.read(&mut buf[..len])).await {
+            budget -= n;
+        }
+    }
+
+    socket.write_all(&payload).await?;
+    if !respond.close_after.unwrap_or(true) {
+        return Ok(());
+    }
+
+    socket.shutdown().await?;
+    let deadline = tokio::time::Instant::now() + timeout;
+    while budget > 0 {
+        let len = budget.min(buf.len());
+        match tokio::time::timeout_at(deadline, socket.read(&mut buf[..len])).await {
+            Ok(Ok(n)) if n > 0 => budget -= n,
+            _ => break,
+        }
+    }
+
+    Ok(())
+}
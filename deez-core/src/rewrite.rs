@@ -0,0 +1,127 @@
+use crate::{minecraft, structs::RewriteInitial, utils};
+use color_eyre::Result;
+
+/// Builds the opening bytes the upstream gets in place of `initial`.
+pub fn apply(initial: &[u8], rewrite: &RewriteInitial) -> Result<Vec<u8>> {
+    if let Some(host) = &rewrite.minecraft_host {
+        return minecraft::rewrite_host(initial, host);
+    }
+
+    let Some(ranges) = &rewrite.replace else {
+        if rewrite.bytes.is_none() && rewrite.text.is_none() && rewrite.base64.is_none() {
+            color_eyre::eyre::bail!(
+                "rewrite_initial needs bytes, text, base64, replace or minecraft_host"
+            );
+        }
+        return utils::decode_payload(&rewrite.bytes, &rewrite.text, &rewrite.base64);
+    };
+
+    let mut ranges = ranges
+        .iter()
+        .map(|range| {
+            let end = range.end.unwrap_or(range.start);
+            if range.start > end || end > initial.len() {
+                color_eyre::eyre::bail!(
+                    "Replace range {}..{} is outside the {} opening bytes",
+                    range.start,
+                    end,
+                    initial.len()
+                );
+            }
+            let payload = utils::decode_payload(&range.bytes, &range.text, &range.base64)?;
+            Ok((range.start, end, payload))
+        })
+        .collect::<Result<Vec<(usize, usize, Vec<u8>)>>>()?;
+    ranges.sort_by_key(|(start, end, _)| (*start, *end));
+
+    let mut res = Vec::with_capacity(initial.len());
+    let mut pos = 0;
+    for (start, end, payload) in ranges {
+        if start < pos {
+            color_eyre::eyre::bail!("Replace ranges overlap at {}", start);
+        }
+        res.extend_from_slice(&initial[pos..start]);
+        res.extend(payload);
+        pos = end;
+    }
+    res.extend_from_slice(&initial[pos..]);
+
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn rewrite(initial: &[u8], rewrite: serde_json::Value) -> Result<Vec<u8>> {
+        apply(initial, &serde_json::from_value(rewrite).unwrap())
+    }
+
+    #[test]
+    fn whole_payload() {
+        assert_eq!(rewrite(b"abc", json!({ "text": "xyz" })).unwrap(), b"xyz");
+        assert_eq!(rewrite(b"abc", json!({ "bytes": [1, 2] })).unwrap(), [1, 2]);
+        assert_eq!(rewrite(b"abc", json!({ "base64": "aGk=" })).unwrap(), b"hi");
+        assert!(rewrite(b"abc", json!({})).is_err());
+        assert!(rewrite(b"abc", json!({ "base64": "not base64" })).is_err());
+    }
+
+    #[test]
+    fn ranges() {
+        let replaced = rewrite(
+            b"GET /old HTTP/1.1",
+            json!({ "replace": [
+                // out of order on purpose
+                { "start": 17, "text": "\r\n" },
+                { "start": 5, "end": 8, "text": "new/path" },
+                { "start": 0, "end": 3, "text": "POST" },
+            ] }),
+        )
+        .unwrap();
+        assert_eq!(replaced, b"POST /new/path HTTP/1.1\r\n");
+    }
+
+    #[test]
+    fn insert_and_delete() {
+        let replaced = rewrite(
+            b"abcdef",
+            json!({ "replace": [
+                { "start": 0, "text": ">" },
+                { "start": 2, "end": 4 },
+                { "start": 6, "bytes": [0] },
+            ] }),
+        )
+        .unwrap();
+        assert_eq!(replaced, b">abef\0");
+    }
+
+    #[test]
+    fn bad_ranges() {
+        for replace in [
+            json!([{ "start": 4, "end": 2 }]),
+            json!([{ "start": 2, "end": 7 }]),
+            json!([{ "start": 7 }]),
+            json!([{ "start": 0, "end": 3 }, { "start": 2, "end": 4 }]),
+        ] {
+            assert!(
+                rewrite(b"abcdef", json!({ "replace": replace })).is_err(),
+                "{}",
+                replace
+            );
+        }
+    }
+
+    #[test]
+    fn touching_ranges() {
+        let replaced = rewrite(
+            b"abcdef",
+            json!({ "replace": [
+                { "start": 0, "end": 3, "text": "x" },
+                { "start": 3, "end": 6, "text": "y" },
+            ] }),
+        )
+        .unwrap();
+        assert_eq!(replaced, b"xy");
+    }
+}
@@ -0,0 +1,152 @@
+use crate::{
+    minecraft::{self, Handshake},
+    stream::Stream,
+    structs::{HttpInfo, SniffOptions},
+    tls::{self, ClientHello},
+};
+use color_eyre::Result;
+use tokio::io::AsyncReadExt;
+
+const HTTP_METHODS: [&[u8]; 9] = [
+    b"GET ",
+    b"POST ",
+    b"PUT ",
+    b"DELETE ",
+    b"HEAD ",
+    b"OPTIONS ",
+    b"PATCH ",
+    b"CONNECT ",
+    b"TRACE ",
+];
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Sniffed {
+    pub protocol: &'static str,
+    /// Whether enough bytes were seen to stop reading.
+    pub complete: bool,
+}
+
+impl Sniffed {
+    /// A partial match can't be told apart from other protocols yet, it stays
+    /// "tcp" if nothing else arrives before the timeout.
+    fn pending() -> Self {
+        Self {
+            protocol: "tcp",
+            complete: false,
+        }
+    }
+}
+
+/// Reads the client's opening bytes until the protocol is known, `max_bytes`
+/// are buffered or the timeout runs out. The bytes must be replayed upstream.
+pub async fn read_initial_bytes(socket: &mut Stream, options: &SniffOptions) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    if options.max_bytes == 0 {
+        return Ok(buf);
+    }
+
+    let deadline = tokio::time::Instant::now() + options.timeout;
+    let mut chunk = vec![0u8; options.max_bytes];
+    while buf.len() < options.max_bytes && !detect(&buf).complete {
+        let remaining = options.max_bytes - buf.len();
+        match tokio::time::timeout_at(deadline, socket.read(&mut chunk[..remaining])).await {
+            Ok(Ok(0)) | Err(_) => break,
+            Ok(Ok(n)) => buf.extend_from_slice(&chunk[..n]),
+            Ok(Err(e)) => return Err(e.into()),
+        }
+    }
+
+    Ok(buf)
+}
+
+pub fn detect(bytes: &[u8]) -> Sniffed {
+    if bytes.is_empty() {
+        return Sniffed::pending();
+    }
+
+    if bytes[0] == 0x16 && (bytes.len() < 2 || bytes[1] == 0x03) {
+        // TLS handshake record: type, version (3, x), length
+        if bytes.len() < 5 {
+            return Sniffed::pending();
+        }
+
+        // keep reading until the whole ClientHello is buffered
+        return Sniffed {
+            protocol: "tls",
+            complete: !matches!(tls::parse_client_hello(bytes), ClientHello::Incomplete),
+        };
+    }
+
+    if bytes.starts_with(b"SSH-") {
+        return Sniffed {
+            protocol: "ssh",
+            complete: bytes.contains(&b'\n'),
+        };
+    } else if b"SSH-".starts_with(bytes) {
+        return Sniffed::pending();
+    }
+
+    if HTTP_METHODS.iter().any(|method| bytes.starts_with(method)) {
+        return Sniffed {
+            protocol: "http",
+            complete: find(bytes, b"\r\n\r\n").is_some(),
+        };
+    } else if HTTP_METHODS.iter().any(|method| method.starts_with(bytes)) {
+        return Sniffed::pending();
+    }
+
+    if bytes[0] == 0xFE {
+        // pre-1.7 minecraft server list ping
+        return Sniffed {
+            protocol: "minecraft",
+            complete: !matches!(minecraft::parse_handshake(bytes), Handshake::Incomplete),
+        };
+    }
+
+    // modern minecraft handshake: length VarInt, packet id 0x00, protocol VarInt
+    match minecraft::read_varint(bytes) {
+        Some((len, read)) if (6..=32767).contains(&len) => {
+            if bytes.len() <= read {
+                return Sniffed::pending();
+            } else if bytes[read] == 0x00 {
+                return Sniffed {
+                    protocol: "minecraft",
+                    complete: bytes.len() >= read + len as usize,
+                };
+            }
+        }
+        None if bytes.len() < 3 => return Sniffed::pending(),
+        _ => {}
+    }
+
+    Sniffed {
+        protocol: "tcp",
+        complete: true,
+    }
+}
+
+pub fn parse_http(bytes: &[u8]) -> Option<HttpInfo> {
+    let head = match find(bytes, b"\r\n\r\n") {
+        Some(end) => &bytes[..end],
+        None => bytes,
+    };
+    let head = String::from_utf8_lossy(head);
+    let mut lines = head.split("\r\n");
+
+    let mut request_line = lines.next()?.split(' ');
+    let method = request_line.next()?.to_string();
+    let path = request_line.next()?.to_string();
+
+    let host = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("host"))
+        .map(|(_, value)| value.trim().to_string());
+
+    Some(HttpInfo { method, path, host })
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
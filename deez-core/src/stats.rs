@@ -0,0 +1,81 @@
+use std::{
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// Print a line for every proxied connection and failed upstream attempt
+pub static LOG_CONNECTIONS: AtomicBool = AtomicBool::new(false);
+
+pub static STATS: Stats = Stats {
+    connections: AtomicU64::new(0),
+    blocked: AtomicU64::new(0),
+    banned: AtomicU64::new(0),
+    tarpit_active: AtomicU64::new(0),
+    tarpitted: AtomicU64::new(0),
+    tarpit_rejected: AtomicU64::new(0),
+    closed_idle: AtomicU64::new(0),
+    closed_lifetime: AtomicU64::new(0),
+    closed_linger: AtomicU64::new(0),
+    closed_quota: AtomicU64::new(0),
+    closed_filter: AtomicU64::new(0),
+    close_events_dropped: AtomicU64::new(0),
+    queue_full: AtomicU64::new(0),
+};
+
+pub struct Stats {
+    pub connections: AtomicU64,
+    pub blocked: AtomicU64,
+    /// Rejected by the firewall before reaching a script
+    pub banned: AtomicU64,
+    pub tarpit_active: AtomicU64,
+    pub tarpitted: AtomicU64,
+    /// Connections that got closed because every tarpit slot was taken
+    pub tarpit_rejected: AtomicU64,
+    /// Proxied connections closed by a timer or limit instead of a peer
+    pub closed_idle: AtomicU64,
+    pub closed_lifetime: AtomicU64,
+    pub closed_linger: AtomicU64,
+    pub closed_quota: AtomicU64,
+    pub closed_filter: AtomicU64,
+    /// onClose calls skipped because the queue was full
+    pub close_events_dropped: AtomicU64,
+    /// Connections dropped because too many were waiting for a worker
+    pub queue_full: AtomicU64,
+}
+
+impl Stats {
+    pub fn inc(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+pub fn stats_reporter(interval: Duration) {
+    if interval.is_zero() {
+        return;
+    }
+
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        interval.tick().await;
+
+        loop {
+            interval.tick().await;
+            println!(
+                "Stats | connections: {} | blocked: {} | banned: {} | tarpit: {} active, {} total, {} rejected | closed: {} idle, {} lifetime, {} linger, {} quota, {} filter | onClose dropped: {} | queue full: {}",
+                STATS.connections.load(Ordering::Relaxed),
+                STATS.blocked.load(Ordering::Relaxed),
+                STATS.banned.load(Ordering::Relaxed),
+                STATS.tarpit_active.load(Ordering::Relaxed),
+                STATS.tarpitted.load(Ordering::Relaxed),
+                STATS.tarpit_rejected.load(Ordering::Relaxed),
+                STATS.closed_idle.load(Ordering::Relaxed),
+                STATS.closed_lifetime.load(Ordering::Relaxed),
+                STATS.closed_linger.load(Ordering::Relaxed),
+                STATS.closed_quota.load(Ordering::Relaxed),
+                STATS.closed_filter.load(Ordering::Relaxed),
+                STATS.close_events_dropped.load(Ordering::Relaxed),
+                STATS.queue_full.load(Ordering::Relaxed),
+            );
+        }
+    });
+}
@@ -0,0 +1,144 @@
+use crate::utils::ListenAddr;
+use color_eyre::Result;
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    os::unix::fs::FileTypeExt,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::{TcpListener, TcpStream, UnixListener, UnixStream},
+};
+
+/// Stands in for the addresses of Unix socket peers, which have none.
+pub const UNIX_ADDR: SocketAddr = SocketAddr::new(std::net::IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    pub async fn bind(addr: &ListenAddr) -> Result<Self> {
+        match addr {
+            ListenAddr::Tcp(addr) => Ok(Listener::Tcp(TcpListener::bind(addr).await?)),
+            ListenAddr::Unix(path) => {
+                // a socket left behind by the last run would make bind fail
+                if std::fs::metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+                    std::fs::remove_file(path)?;
+                }
+                Ok(Listener::Unix(UnixListener::bind(path)?))
+            }
+            ListenAddr::Udp(_) => color_eyre::eyre::bail!("{} isn't a stream listener", addr),
+        }
+    }
+
+    /// Unix socket peers get `UNIX_ADDR`.
+    pub async fn accept(&self) -> io::Result<(Stream, SocketAddr)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((Stream::tcp(stream), addr))
+            }
+            Listener::Unix(listener) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Stream::unix(stream), UNIX_ADDR))
+            }
+        }
+    }
+}
+
+/// A client or upstream connection over TCP or a Unix socket. Bytes given
+/// back with `unread` are handed out before the socket is read again.
+pub enum Stream {
+    Tcp(TcpStream, Vec<u8>),
+    Unix(UnixStream, Vec<u8>),
+}
+
+impl Stream {
+    pub fn tcp(stream: TcpStream) -> Self {
+        Stream::Tcp(stream, vec![])
+    }
+
+    pub fn unix(stream: UnixStream) -> Self {
+        Stream::Unix(stream, vec![])
+    }
+
+    pub fn is_unix(&self) -> bool {
+        matches!(self, Stream::Unix(..))
+    }
+
+    /// `UNIX_ADDR` for Unix sockets.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Stream::Tcp(stream, _) => stream.local_addr(),
+            Stream::Unix(..) => Ok(UNIX_ADDR),
+        }
+    }
+
+    /// Unix sockets have no Nagle to turn off.
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        match self {
+            Stream::Tcp(stream, _) => stream.set_nodelay(nodelay),
+            Stream::Unix(..) => Ok(()),
+        }
+    }
+
+    /// Puts read bytes back in front of the ones still to come.
+    pub fn unread(&mut self, bytes: &[u8]) {
+        let (Stream::Tcp(_, unread) | Stream::Unix(_, unread)) = self;
+        unread.splice(..0, bytes.iter().copied());
+    }
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let unread = match self.get_mut() {
+            Stream::Tcp(stream, unread) if unread.is_empty() => {
+                return Pin::new(stream).poll_read(cx, buf)
+            }
+            Stream::Unix(stream, unread) if unread.is_empty() => {
+                return Pin::new(stream).poll_read(cx, buf)
+            }
+            Stream::Tcp(_, unread) | Stream::Unix(_, unread) => unread,
+        };
+
+        let n = unread.len().min(buf.remaining());
+        buf.put_slice(&unread[..n]);
+        unread.drain(..n);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Tcp(stream, _) => Pin::new(stream).poll_write(cx, buf),
+            Stream::Unix(stream, _) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream, _) => Pin::new(stream).poll_flush(cx),
+            Stream::Unix(stream, _) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Stream::Tcp(stream, _) => Pin::new(stream).poll_shutdown(cx),
+            Stream::Unix(stream, _) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
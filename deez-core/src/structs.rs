@@ -0,0 +1,404 @@
+use crate::utils::Cidr;
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+#[derive(serde::Deserialize, Debug, Default)]
+#[allow(dead_code)]
+pub struct V8Response {
+    pub block_connection: Option<bool>,
+    pub hang_connection: Option<bool>,
+    pub ip: Option<String>,
+    /// Name of a pool, takes precedence over upstreams and ip
+    pub pool: Option<String>,
+    pub upstreams: Option<Vec<Upstream>>,
+    /// "ordered" (default) or "weighted"
+    pub upstream_order: Option<String>,
+    pub no_delay: Option<bool>,
+    pub proxy_protocol: Option<String>,
+    pub proxy_protocol_tlvs: Option<Vec<ProxyTlv>>,
+    pub minecraft_status: Option<MinecraftStatus>,
+    pub respond: Option<Respond>,
+    /// Sent to the upstream instead of the buffered opening bytes
+    pub rewrite_initial: Option<RewriteInitial>,
+    /// Runs every later chunk through onClientData / onServerData
+    pub filter: Option<bool>,
+    pub filter_budget: Option<FilterBudget>,
+    pub tarpit: Option<Tarpit>,
+    pub limits: Option<Limits>,
+    /// Override the listener's timeouts, 0 disables the timer
+    pub idle_timeout_ms: Option<u64>,
+    pub max_lifetime_ms: Option<u64>,
+    pub half_close_linger_ms: Option<u64>,
+
+    pub cpu_time: Option<u128>,
+    /// Set when the script defines onClose
+    #[serde(skip)]
+    pub on_close: bool,
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct Limits {
+    /// Client to upstream, in bytes per second
+    pub up_bps: Option<u64>,
+    /// Upstream to client, in bytes per second
+    pub down_bps: Option<u64>,
+    /// Bucket size in bytes, defaults to one second of traffic
+    pub burst: Option<u64>,
+    /// Both directions together, the connection is closed once exceeded
+    pub max_total_bytes: Option<u64>,
+    /// Connections with the same key (e.g. the client ip) share the limits
+    pub key: Option<String>,
+}
+
+#[derive(serde::Deserialize, Debug, Default)]
+pub struct Tarpit {
+    /// Defaults to 30s, same as hang_connection
+    pub duration_ms: Option<u64>,
+    /// Junk bytes sent every drip interval, at most 1024. 0 keeps the
+    /// socket silent
+    pub drip_bytes: Option<usize>,
+    pub drip_interval_ms: Option<u64>,
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct Respond {
+    pub bytes: Option<Vec<u8>>,
+    pub text: Option<String>,
+    pub base64: Option<String>,
+    /// Defaults to true, otherwise the connection is proxied to the upstream after
+    /// the reply (or kept open if there is none)
+    pub close_after: Option<bool>,
+    /// How much client input to read and throw away around the reply
+    pub discard_input: Option<usize>,
+    pub discard_timeout_ms: Option<u64>,
+}
+
+/// One of bytes / text / base64 replaces everything, `replace` patches
+/// ranges and `minecraft_host` swaps the hostname of a Minecraft handshake
+#[derive(serde::Deserialize, Debug)]
+pub struct RewriteInitial {
+    pub bytes: Option<Vec<u8>>,
+    pub text: Option<String>,
+    pub base64: Option<String>,
+    pub replace: Option<Vec<ReplaceRange>>,
+    pub minecraft_host: Option<String>,
+}
+
+/// Offsets are into the original bytes, `end` defaults to `start` (insert)
+#[derive(serde::Deserialize, Debug)]
+pub struct ReplaceRange {
+    pub start: usize,
+    pub end: Option<usize>,
+    pub bytes: Option<Vec<u8>>,
+    pub text: Option<String>,
+    pub base64: Option<String>,
+}
+
+/// What a filtered connection may spend in the handlers, once either runs
+/// out the rest is copied as is, or closed with `on_exhausted: "close"`
+#[derive(serde::Deserialize, Debug, Default)]
+pub struct FilterBudget {
+    /// Bytes of both directions, defaults to 1 MiB
+    pub max_bytes: Option<u64>,
+    /// Time spent in the handlers, defaults to 1s
+    pub max_time_ms: Option<u64>,
+    pub on_exhausted: Option<String>,
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct MinecraftStatus {
+    /// Plain text or a JSON chat component
+    pub motd: String,
+    pub max_players: u32,
+    pub online: u32,
+    pub version_name: String,
+    /// Defaults to the client's version so it isn't shown as outdated
+    pub version_protocol: Option<i32>,
+    /// PNG as base64, with or without the data: prefix
+    pub favicon: Option<String>,
+    /// Disconnect message for players trying to join, defaults to the motd
+    pub kick_message: Option<String>,
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct PoolConfig {
+    /// "round-robin" (default), "weighted", "least-connections",
+    /// "consistent-hash" (on the client ip) or "ewma" (connect latency)
+    pub strategy: Option<String>,
+    pub upstreams: Vec<Upstream>,
+    pub health_check: Option<HealthCheck>,
+}
+
+#[derive(serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct HealthCheck {
+    /// "tcp" (default, connect only), "send-expect" or "minecraft" (status ping)
+    #[serde(rename = "type")]
+    pub kind: Option<String>,
+    pub interval_ms: Option<u64>,
+    pub timeout_ms: Option<u64>,
+    /// Consecutive successes before an unhealthy member is used again
+    pub rise: Option<u32>,
+    /// Consecutive failures before a member is taken out
+    pub fall: Option<u32>,
+    pub send: Option<String>,
+    pub send_base64: Option<String>,
+    /// The reply has to contain these bytes, any reply passes if unset
+    pub expect: Option<String>,
+    pub expect_base64: Option<String>,
+}
+
+#[derive(serde::Serialize, Debug)]
+pub struct HealthStatus {
+    pub addr: String,
+    pub healthy: bool,
+    /// False until the first probe finished, or if no pool checks it
+    pub checked: bool,
+    pub successes: u32,
+    pub failures: u32,
+    pub last_check_ms: Option<u64>,
+    pub last_error: Option<String>,
+    pub latency_ms: Option<f64>,
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct Upstream {
+    pub addr: String,
+    pub weight: Option<u32>,
+    pub connect_timeout_ms: Option<u64>,
+}
+
+#[derive(serde::Deserialize, Debug)]
+pub struct RateLimitOptions {
+    pub rate: u64,
+    pub per: RateLimitPeriod,
+    /// Token bucket size, defaults to `rate`
+    pub burst: Option<u64>,
+    /// "token-bucket" (default) or "sliding-window"
+    pub algorithm: Option<String>,
+    pub cost: Option<u64>,
+}
+
+#[derive(serde::Deserialize, Debug)]
+#[serde(untagged)]
+pub enum RateLimitPeriod {
+    Ms(f64),
+    /// "500ms", "10s", "1m", "1h" or "1d"
+    Text(String),
+}
+
+#[derive(serde::Serialize, Debug)]
+pub struct RateLimitResult {
+    pub allowed: bool,
+    pub remaining: u64,
+    pub retry_after_ms: u64,
+    pub limit: u64,
+}
+
+#[derive(serde::Deserialize, Debug, Default)]
+pub struct BanOptions {
+    /// Permanent if not set
+    pub ttl_ms: Option<u64>,
+    pub reason: Option<String>,
+}
+
+#[derive(serde::Serialize, Debug)]
+pub struct BanInfo {
+    /// The banned address or network that matched
+    pub target: String,
+    pub reason: Option<String>,
+    pub expires_in_ms: Option<u64>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
+pub struct ProxyTlv {
+    #[serde(rename = "type")]
+    pub kind: u8,
+    /// Raw bytes, scripts can also set it as text
+    #[serde(deserialize_with = "bytes_or_text")]
+    pub value: Vec<u8>,
+}
+
+fn bytes_or_text<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum Value {
+        Bytes(Vec<u8>),
+        Text(String),
+    }
+
+    Ok(match serde::Deserialize::deserialize(deserializer)? {
+        Value::Bytes(bytes) => bytes,
+        Value::Text(text) => text.into_bytes(),
+    })
+}
+
+#[derive(serde::Serialize, Debug)]
+#[allow(dead_code)]
+pub struct V8Request {
+    pub ip: String,
+    /// 0 for unix socket listeners
+    pub port: u16,
+    pub protocol: String,
+    /// Path of the unix socket listener the client connected to
+    pub unix_socket: Option<String>,
+
+    pub src_port: u16,
+    pub dst_ip: String,
+    pub dst_port: u16,
+    pub proxy_tlvs: Option<Vec<ProxyTlv>>,
+
+    pub initial_bytes: Vec<u8>,
+    pub http: Option<HttpInfo>,
+    pub tls: Option<TlsInfo>,
+    pub minecraft: Option<MinecraftInfo>,
+    /// Recent connections of the same client IP, null if disabled
+    pub history: Option<ClientHistory>,
+}
+
+/// Passed to the script's onClose after a proxied connection ends
+#[derive(serde::Serialize, Debug)]
+pub struct CloseInfo {
+    pub ip: String,
+    pub src_port: u16,
+    pub port: u16,
+    /// The upstream as the script wrote it and the address it connected to
+    pub upstream: Option<String>,
+    pub upstream_addr: Option<String>,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    /// Since the connection was accepted
+    pub duration_ms: u64,
+    /// "done", "idle_timeout", "max_lifetime", "half_close_linger",
+    /// "quota_exceeded", "filter", "connect_failed" or "error"
+    pub reason: String,
+    pub connect_error: Option<String>,
+    /// What broke the relay when the reason is "error"
+    pub error: Option<String>,
+}
+
+/// One chunk for the script's onClientData / onServerData
+#[derive(serde::Serialize, Debug)]
+pub struct FilterCall {
+    /// "client" for client -> upstream, "server" for upstream -> client
+    pub direction: &'static str,
+    pub chunk: Vec<u8>,
+    pub conn: FilterConn,
+}
+
+#[derive(serde::Serialize, Debug, Clone)]
+pub struct FilterConn {
+    pub ip: String,
+    pub src_port: u16,
+    pub port: u16,
+    pub upstream: String,
+    /// Kept between calls, starts as an empty object
+    pub state: serde_json::Value,
+}
+
+#[derive(serde::Deserialize, Debug, Default)]
+pub struct FilterResult {
+    /// "pass", "replace", "drop" or "close"
+    pub action: String,
+    pub data: Option<Vec<u8>>,
+    pub state: Option<serde_json::Value>,
+}
+
+/// Counts include the current connection
+#[derive(serde::Serialize, Debug)]
+pub struct ClientHistory {
+    pub connections: u64,
+    pub first_seen_ms_ago: u64,
+    /// Previous connection, null on the first one
+    pub last_seen_ms_ago: Option<u64>,
+    /// Bytes of finished relays, client -> upstream and back
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+    /// What the script decided for the previous connection
+    pub last_decision: Option<String>,
+    pub windows: Vec<HistoryWindow>,
+}
+
+#[derive(serde::Serialize, Debug)]
+pub struct HistoryWindow {
+    pub window_sec: f64,
+    pub connections: u64,
+    /// Local ports the client connected to
+    pub distinct_ports: u64,
+    pub bytes_up: u64,
+    pub bytes_down: u64,
+}
+
+#[derive(serde::Serialize, Debug)]
+pub struct TlsInfo {
+    pub sni: Option<String>,
+    pub alpn: Vec<String>,
+    pub versions: Vec<String>,
+    pub cipher_suites: Vec<u16>,
+}
+
+#[derive(serde::Serialize, Debug)]
+pub struct MinecraftInfo {
+    /// -1 for legacy pings that don't carry it (beta and 1.4 - 1.5)
+    pub protocol_version: i32,
+    /// Lowercased, without the Forge / BungeeCord suffixes
+    pub server_address: Option<String>,
+    pub server_port: Option<u16>,
+    /// 1 = status, 2 = login, 3 = transfer
+    pub next_state: i32,
+    /// Pre-1.7 server list ping: "beta" (0xFE), "1.4" (0xFE 0x01, also sent
+    /// by 1.5) or "1.6" (0xFE 0x01 0xFA with the host)
+    pub legacy: Option<&'static str>,
+    /// "FML", "FML2" or "FML3" for Forge clients
+    pub forge: Option<String>,
+}
+
+#[derive(serde::Serialize, Debug)]
+pub struct HttpInfo {
+    pub method: String,
+    pub path: String,
+    pub host: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcceptProxy {
+    Off,
+    Optional,
+    Required,
+}
+
+#[derive(Debug, Clone)]
+pub struct ListenerOptions {
+    pub accept_proxy: AcceptProxy,
+    pub trusted_proxies: Vec<Cidr>,
+    pub sniff: SniffOptions,
+    pub timeouts: Timeouts,
+    /// How long to wait for the PROXY header
+    pub proxy_timeout: Duration,
+}
+
+/// Defaults for proxied connections, scripts can override each of them.
+/// Zero disables a timer.
+#[derive(Debug, Clone)]
+pub struct Timeouts {
+    pub idle: Duration,
+    pub max_lifetime: Duration,
+    /// How long the other direction may stay open after one side closed
+    pub half_close_linger: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct SniffOptions {
+    /// 0 disables sniffing
+    pub max_bytes: usize,
+    pub timeout: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    pub client_addr: SocketAddr,
+    pub local_addr: SocketAddr,
+    pub initial_bytes: Vec<u8>,
+    pub accepted: Instant,
+}
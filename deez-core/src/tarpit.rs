@@ -0,0 +1,155 @@
+use crate::{
+    stats::{Stats, STATS},
+    stream::Stream,
+    structs::Tarpit,
+};
+use color_eyre::Result;
+use rand::Rng;
+use std::{
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+use tokio::io::AsyncWriteExt;
+
+/// Max simultaneously tarpitted sockets, so they can't exhaust our fds.
+pub static TARPIT_MAX: AtomicU64 = AtomicU64::new(1000);
+
+/// A drip is only there to keep the client waiting, bigger ones just cost
+/// us bandwidth.
+const MAX_DRIP_BYTES: usize = 1024;
+
+struct TarpitSlot;
+
+impl TarpitSlot {
+    fn acquire() -> Option<Self> {
+        let max = TARPIT_MAX.load(Ordering::Relaxed);
+        STATS
+            .tarpit_active
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |active| {
+                (active < max).then_some(active + 1)
+            })
+            .ok()
+            .map(|_| TarpitSlot)
+    }
+}
+
+impl Drop for TarpitSlot {
+    fn drop(&mut self) {
+        STATS.tarpit_active.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Holds the connection open for the script chosen duration, optionally
+/// dripping junk bytes (endlessh style) to keep the client waiting.
+pub async fn tarpit(socket: &mut Stream, tarpit: &Tarpit) -> Result<()> {
+    let _slot = match TarpitSlot::acquire() {
+        Some(slot) => slot,
+        None => {
+            Stats::inc(&STATS.tarpit_rejected);
+            return Ok(());
+        }
+    };
+    Stats::inc(&STATS.tarpitted);
+
+    let deadline =
+        tokio::time::Instant::now() + Duration::from_millis(tarpit.duration_ms.unwrap_or(30000));
+    let drip_bytes = tarpit.drip_bytes.unwrap_or(0).min(MAX_DRIP_BYTES);
+    if drip_bytes == 0 {
+        // whatever the client sends is thrown away, it leaving frees the slot
+        let mut sink = tokio::io::sink();
+        tokio::select! {
+            _ = tokio::time::sleep_until(deadline) => {}
+            _ = tokio::io::copy(socket, &mut sink) => {}
+        }
+        return Ok(());
+    }
+
+    let mut interval = tokio::time::interval(Duration::from_millis(
+        tarpit.drip_interval_ms.unwrap_or(10000).max(1),
+    ));
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep_until(deadline) => return Ok(()),
+            _ = interval.tick() => {
+                if socket.write_all(&junk_line(drip_bytes)).await.is_err() {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+/// Random printable line of exactly `len` bytes, never starting with "SSH-"
+/// so ssh clients keep waiting for the real banner. Lines too short for a
+/// "\r\n" go without one.
+fn junk_line(len: usize) -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    let text = if len < 2 { len } else { len - 2 };
+    let mut line = (0..text)
+        .map(|_| rng.gen_range(b'a'..=b'z'))
+        .collect::<Vec<u8>>();
+    if len >= 2 {
+        line.extend_from_slice(b"\r\n");
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{
+        io::AsyncReadExt,
+        net::{TcpListener, TcpStream},
+    };
+
+    #[tokio::test]
+    async fn slot_is_freed_when_the_client_leaves() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let mut socket = Stream::tcp(listener.accept().await.unwrap().0);
+        drop(client);
+
+        let options = Tarpit {
+            duration_ms: Some(60_000),
+            ..Default::default()
+        };
+        tokio::time::timeout(Duration::from_secs(5), tarpit(&mut socket, &options))
+            .await
+            .expect("tarpit outlived the client")
+            .unwrap();
+    }
+
+    #[test]
+    fn junk_lines_are_as_long_as_asked() {
+        assert_eq!(junk_line(0), b"");
+        assert_eq!(junk_line(1).len(), 1);
+        assert_ne!(junk_line(1), b"\n");
+        assert_eq!(junk_line(2), b"\r\n");
+        let line = junk_line(10);
+        assert_eq!(line.len(), 10);
+        assert!(line.ends_with(b"\r\n"));
+    }
+
+    #[tokio::test]
+    async fn drips_are_capped() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let mut socket = Stream::tcp(listener.accept().await.unwrap().0);
+
+        let options = Tarpit {
+            duration_ms: Some(100),
+            drip_bytes: Some(usize::MAX),
+            drip_interval_ms: Some(60_000),
+        };
+        tarpit(&mut socket, &options).await.unwrap();
+        drop(socket);
+
+        let mut received = vec![];
+        client.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received.len(), MAX_DRIP_BYTES);
+    }
+}
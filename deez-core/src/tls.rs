@@ -0,0 +1,330 @@
+use crate::structs::TlsInfo;
+
+const RECORD_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+
+const EXT_SERVER_NAME: u16 = 0x0000;
+const EXT_ALPN: u16 = 0x0010;
+const EXT_SUPPORTED_VERSIONS: u16 = 0x002B;
+
+#[derive(Debug)]
+pub enum ClientHello {
+    /// More bytes are needed, the hello spans several records or segments.
+    Incomplete,
+    Invalid,
+    Complete(TlsInfo),
+}
+
+/// Parses the ClientHello out of the buffered TLS records, joining the
+/// handshake fragments of every record that arrived so far.
+pub fn parse_client_hello(bytes: &[u8]) -> ClientHello {
+    let mut handshake = vec![];
+    let mut rest = bytes;
+
+    // anything after the hello (e.g. early data) isn't a handshake record
+    while !rest.is_empty() && rest[0] == RECORD_HANDSHAKE {
+        if rest.len() < 5 {
+            break;
+        }
+
+        let len = u16::from_be_bytes([rest[3], rest[4]]) as usize;
+        let end = (5 + len).min(rest.len());
+        handshake.extend_from_slice(&rest[5..end]);
+        rest = &rest[end..];
+    }
+
+    if bytes.first().is_some_and(|kind| *kind != RECORD_HANDSHAKE) {
+        return ClientHello::Invalid;
+    } else if handshake.len() < 4 {
+        return ClientHello::Incomplete;
+    } else if handshake[0] != HANDSHAKE_CLIENT_HELLO {
+        return ClientHello::Invalid;
+    }
+
+    let len = u32::from_be_bytes([0, handshake[1], handshake[2], handshake[3]]) as usize;
+    if handshake.len() < 4 + len {
+        return ClientHello::Incomplete;
+    }
+
+    match parse_body(&handshake[4..4 + len]) {
+        Some(info) => ClientHello::Complete(info),
+        None => ClientHello::Invalid,
+    }
+}
+
+fn parse_body(body: &[u8]) -> Option<TlsInfo> {
+    let mut reader = Reader(body);
+    let legacy_version = reader.u16()?;
+    reader.skip(32)?; // random
+
+    let session_id_len = reader.u8()? as usize;
+    reader.skip(session_id_len)?;
+
+    let mut ciphers = Reader(reader.take_u16_len()?);
+    let mut cipher_suites = vec![];
+    while let Some(cipher) = ciphers.u16() {
+        if !is_grease(cipher) {
+            cipher_suites.push(cipher);
+        }
+    }
+
+    let compression_len = reader.u8()? as usize;
+    reader.skip(compression_len)?;
+
+    let mut info = TlsInfo {
+        sni: None,
+        alpn: vec![],
+        versions: vec![],
+        cipher_suites,
+    };
+
+    // extensions are optional in old hellos
+    let mut extensions = Reader(reader.take_u16_len().unwrap_or_default());
+    while let (Some(kind), Some(data)) = (extensions.u16(), extensions.take_u16_len()) {
+        let mut data = Reader(data);
+        match kind {
+            EXT_SERVER_NAME => {
+                let mut names = Reader(data.take_u16_len()?);
+                while let (Some(name_type), Some(name)) = (names.u8(), names.take_u16_len()) {
+                    if name_type == 0 {
+                        info.sni = Some(String::from_utf8_lossy(name).to_lowercase());
+                    }
+                }
+            }
+            EXT_ALPN => {
+                let mut protocols = Reader(data.take_u16_len()?);
+                while let Some(protocol) = protocols.take_u8_len() {
+                    info.alpn
+                        .push(String::from_utf8_lossy(protocol).to_string());
+                }
+            }
+            EXT_SUPPORTED_VERSIONS => {
+                let mut versions = Reader(data.take_u8_len()?);
+                while let Some(version) = versions.u16() {
+                    if !is_grease(version) {
+                        info.versions.push(version_name(version));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if info.versions.is_empty() {
+        info.versions.push(version_name(legacy_version));
+    }
+
+    Some(info)
+}
+
+fn version_name(version: u16) -> String {
+    match version {
+        0x0300 => "SSLv3".into(),
+        0x0301 => "TLSv1.0".into(),
+        0x0302 => "TLSv1.1".into(),
+        0x0303 => "TLSv1.2".into(),
+        0x0304 => "TLSv1.3".into(),
+        _ => format!("{:#06x}", version),
+    }
+}
+
+/// GREASE values (RFC 8701) are random placeholders, not real choices.
+fn is_grease(value: u16) -> bool {
+    value & 0x0F0F == 0x0A0A && value >> 8 == value & 0xFF
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if self.0.len() < len {
+            return None;
+        }
+
+        let (data, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(data)
+    }
+
+    fn skip(&mut self, len: usize) -> Option<()> {
+        self.take(len).map(|_| ())
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|data| data[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2)
+            .map(|data| u16::from_be_bytes([data[0], data[1]]))
+    }
+
+    fn take_u8_len(&mut self) -> Option<&'a [u8]> {
+        let len = self.u8()? as usize;
+        self.take(len)
+    }
+
+    fn take_u16_len(&mut self) -> Option<&'a [u8]> {
+        let len = self.u16()? as usize;
+        self.take(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u16_len(data: &[u8]) -> Vec<u8> {
+        let mut out = (data.len() as u16).to_be_bytes().to_vec();
+        out.extend_from_slice(data);
+        out
+    }
+
+    fn extension(kind: u16, data: &[u8]) -> Vec<u8> {
+        let mut out = kind.to_be_bytes().to_vec();
+        out.extend(u16_len(data));
+        out
+    }
+
+    fn sni(name: &str) -> Vec<u8> {
+        let mut entry = vec![0];
+        entry.extend(u16_len(name.as_bytes()));
+        extension(EXT_SERVER_NAME, &u16_len(&entry))
+    }
+
+    fn alpn(protocols: &[&str]) -> Vec<u8> {
+        let mut list = vec![];
+        for protocol in protocols {
+            list.push(protocol.len() as u8);
+            list.extend_from_slice(protocol.as_bytes());
+        }
+        extension(EXT_ALPN, &u16_len(&list))
+    }
+
+    fn versions(versions: &[u16]) -> Vec<u8> {
+        let mut list = vec![versions.len() as u8 * 2];
+        for version in versions {
+            list.extend(version.to_be_bytes());
+        }
+        extension(EXT_SUPPORTED_VERSIONS, &list)
+    }
+
+    /// A ClientHello handshake message (without the record header).
+    fn hello(extensions: Option<&[u8]>) -> Vec<u8> {
+        let mut body = vec![0x03, 0x03];
+        body.extend([0x11; 32]);
+        body.push(0);
+        body.extend(u16_len(&[0x1A, 0x1A, 0x13, 0x01, 0xC0, 0x2F]));
+        body.extend([1, 0]);
+        if let Some(extensions) = extensions {
+            body.extend(u16_len(extensions));
+        }
+
+        let mut out = vec![HANDSHAKE_CLIENT_HELLO];
+        out.extend(&(body.len() as u32).to_be_bytes()[1..]);
+        out.extend(body);
+        out
+    }
+
+    fn records(handshake: &[u8], record_len: usize) -> Vec<u8> {
+        let mut out = vec![];
+        for chunk in handshake.chunks(record_len) {
+            out.extend([RECORD_HANDSHAKE, 0x03, 0x01]);
+            out.extend(u16_len(chunk));
+        }
+        out
+    }
+
+    fn complete(bytes: &[u8]) -> TlsInfo {
+        match parse_client_hello(bytes) {
+            ClientHello::Complete(info) => info,
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn extensions() {
+        let extensions = [
+            sni("Example.COM"),
+            extension(0xFF01, &[0]),
+            alpn(&["h2", "http/1.1"]),
+            versions(&[0x3A3A, 0x0304, 0x0303]),
+        ]
+        .concat();
+        let info = complete(&records(&hello(Some(&extensions)), 1 << 14));
+
+        assert_eq!(info.sni.as_deref(), Some("example.com"));
+        assert_eq!(info.alpn, ["h2", "http/1.1"]);
+        assert_eq!(info.versions, ["TLSv1.3", "TLSv1.2"]);
+        assert_eq!(info.cipher_suites, [0x1301, 0xC02F]);
+    }
+
+    #[test]
+    fn without_extensions() {
+        let info = complete(&records(&hello(None), 1 << 14));
+        assert!(info.sni.is_none() && info.alpn.is_empty());
+        assert_eq!(info.versions, ["TLSv1.2"]);
+    }
+
+    #[test]
+    fn split_across_records() {
+        let extensions = sni("example.com");
+        let bytes = records(&hello(Some(&extensions)), 7);
+        assert_eq!(complete(&bytes).sni.as_deref(), Some("example.com"));
+    }
+
+    #[test]
+    fn truncated() {
+        let extensions = [sni("example.com"), alpn(&["h2"])].concat();
+        let bytes = records(&hello(Some(&extensions)), 1 << 14);
+        for len in 0..bytes.len() {
+            assert!(
+                matches!(parse_client_hello(&bytes[..len]), ClientHello::Incomplete),
+                "{} bytes",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn not_a_hello() {
+        assert!(matches!(
+            parse_client_hello(b"GET / HTTP/1.1\r\n"),
+            ClientHello::Invalid
+        ));
+
+        let mut handshake = hello(None);
+        handshake[0] = 0x02;
+        assert!(matches!(
+            parse_client_hello(&records(&handshake, 1 << 14)),
+            ClientHello::Invalid
+        ));
+    }
+
+    #[test]
+    fn malformed_lengths() {
+        // the server name list claims more than the extension holds
+        let mut bad_sni = sni("example.com");
+        bad_sni[5] += 1;
+        let bytes = records(&hello(Some(&bad_sni)), 1 << 14);
+        assert!(matches!(parse_client_hello(&bytes), ClientHello::Invalid));
+
+        // the cipher suites run past the end of the hello
+        let mut handshake = hello(None);
+        handshake[4 + 2 + 32 + 1 + 1] = 0xFF;
+        let bytes = records(&handshake, 1 << 14);
+        assert!(matches!(parse_client_hello(&bytes), ClientHello::Invalid));
+
+        // an extension that runs past the end stops the walk, keeping what came before
+        let extensions = [sni("example.com"), vec![0x00, 0x10, 0x00, 0x20, 0x00]].concat();
+        let info = complete(&records(&hello(Some(&extensions)), 1 << 14));
+        assert_eq!(info.sni.as_deref(), Some("example.com"));
+        assert!(info.alpn.is_empty());
+    }
+
+    #[test]
+    fn grease() {
+        assert!(is_grease(0x0A0A) && is_grease(0xFAFA));
+        assert!(!is_grease(0x0A1A) && !is_grease(0x1301));
+    }
+}
@@ -0,0 +1,387 @@
+use crate::{
+    engine::Engine,
+    firewall,
+    pools::Lease,
+    stats::{Stats, LOG_CONNECTIONS, STATS},
+    structs::{V8Request, V8Response},
+    upstream,
+};
+use color_eyre::Result;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{atomic::Ordering, Arc, Mutex},
+    time::Duration,
+};
+use tokio::{net::UdpSocket, sync::mpsc};
+
+const FLOW_QUEUE_SIZE: usize = 128;
+
+type Flows = Arc<Mutex<HashMap<SocketAddr, mpsc::Sender<Vec<u8>>>>>;
+
+/// Routes the datagrams of every client address as one flow, flows end
+/// once they're idle for `idle_timeout`.
+pub async fn udp_listener(
+    addr: SocketAddr,
+    idle_timeout: Duration,
+    engine: Arc<dyn Engine>,
+) -> Result<()> {
+    let port = addr.port();
+    let socket = Arc::new(UdpSocket::bind(addr).await?);
+    println!("Listening on: udp/{}", addr);
+
+    let flows: Flows = Arc::new(Mutex::new(HashMap::new()));
+    let mut buf = vec![0u8; 65535];
+
+    loop {
+        let (n, client_addr) = match socket.recv_from(&mut buf).await {
+            Ok(res) => res,
+            Err(e) => {
+                println!("Udp Recv Error: {}", e);
+                continue;
+            }
+        };
+
+        let (rx, banned) = {
+            let mut flows = flows.lock().unwrap();
+            if let Some(tx) = flows.get(&client_addr) {
+                // bans that land mid-flow drop the rest without counting each datagram
+                if firewall::check(client_addr.ip()).is_none() {
+                    // a full queue means the flow can't keep up, drop like the network would
+                    let _ = tx.try_send(buf[..n].to_vec());
+                }
+                continue;
+            }
+
+            // only a new flow counts as a connection, or as banned
+            let banned = firewall::reject(client_addr.ip());
+            if !banned {
+                Stats::inc(&STATS.connections);
+                if engine.overloaded() {
+                    Stats::inc(&STATS.queue_full);
+                    continue;
+                }
+            }
+
+            let (tx, rx) = mpsc::channel(FLOW_QUEUE_SIZE);
+            if !banned {
+                let _ = tx.try_send(buf[..n].to_vec());
+            }
+            flows.insert(client_addr, tx);
+            (rx, banned)
+        };
+
+        if banned {
+            tokio::spawn(drop_flow(flows.clone(), client_addr, rx, idle_timeout));
+            continue;
+        }
+        tokio::spawn(run_flows(
+            socket.clone(),
+            flows.clone(),
+            client_addr,
+            port,
+            rx,
+            idle_timeout,
+            engine.clone(),
+        ));
+    }
+}
+
+/// Runs the flows of a client address one after another, datagrams that
+/// came in while a flow ended start the next one instead of getting lost.
+async fn run_flows(
+    socket: Arc<UdpSocket>,
+    flows: Flows,
+    client_addr: SocketAddr,
+    port: u16,
+    mut rx: mpsc::Receiver<Vec<u8>>,
+    idle_timeout: Duration,
+    engine: Arc<dyn Engine>,
+) {
+    loop {
+        let res = handle_flow(&socket, client_addr, port, &mut rx, idle_timeout, &*engine).await;
+        if let Err(_e) = res {
+            //println!("Udp Flow Error: {}", _e);
+        }
+
+        // the listener only sends under this lock, so nothing can end up
+        // in a queue that's gone
+        let next = {
+            let mut flows = flows.lock().unwrap();
+            match rx.try_recv() {
+                Ok(data) => {
+                    let (tx, next) = mpsc::channel(FLOW_QUEUE_SIZE);
+                    let _ = tx.try_send(data);
+                    while let Ok(data) = rx.try_recv() {
+                        let _ = tx.try_send(data);
+                    }
+                    flows.insert(client_addr, tx);
+                    Some(next)
+                }
+                Err(_) => {
+                    flows.remove(&client_addr);
+                    None
+                }
+            }
+        };
+        let Some(next) = next else {
+            return;
+        };
+        rx = next;
+        Stats::inc(&STATS.connections);
+    }
+}
+
+/// Swallows a banned client's datagrams until it goes quiet, so the ban
+/// counts once per flow.
+async fn drop_flow(
+    flows: Flows,
+    client_addr: SocketAddr,
+    mut rx: mpsc::Receiver<Vec<u8>>,
+    idle_timeout: Duration,
+) {
+    while let Ok(Some(_)) = tokio::time::timeout(idle_timeout, rx.recv()).await {}
+    flows.lock().unwrap().remove(&client_addr);
+}
+
+async fn handle_flow(
+    socket: &UdpSocket,
+    client_addr: SocketAddr,
+    port: u16,
+    rx: &mut mpsc::Receiver<Vec<u8>>,
+    idle_timeout: Duration,
+    engine: &dyn Engine,
+) -> Result<()> {
+    let local_addr = socket.local_addr()?;
+    let res = engine
+        .decide(V8Request {
+            ip: client_addr.ip().to_string(),
+            port,
+            protocol: "udp".into(),
+            unix_socket: None,
+            src_port: client_addr.port(),
+            dst_ip: local_addr.ip().to_string(),
+            dst_port: local_addr.port(),
+            proxy_tlvs: None,
+            initial_bytes: vec![],
+            http: None,
+            tls: None,
+            minecraft: None,
+            history: None,
+        })
+        .await?;
+
+    relay_flow(socket, client_addr, rx, res, idle_timeout).await
+}
+
+async fn relay_flow(
+    socket: &UdpSocket,
+    client_addr: SocketAddr,
+    rx: &mut mpsc::Receiver<Vec<u8>>,
+    res: V8Response,
+    idle_timeout: Duration,
+) -> Result<()> {
+    if res.block_connection.unwrap_or(false) || res.hang_connection.unwrap_or(false) {
+        Stats::inc(&STATS.blocked);
+        // keep the flow around so its datagrams are dropped until it goes idle
+        while let Ok(Some(_)) = tokio::time::timeout(idle_timeout, rx.recv()).await {}
+        return Ok(());
+    }
+
+    let candidates = upstream::candidates(&res, client_addr.ip())?;
+    let (upstream, _lease) = connect(&candidates).await?;
+
+    let mut buf = vec![0u8; 65535];
+    loop {
+        let activity = tokio::time::timeout(idle_timeout, async {
+            tokio::select! {
+                data = rx.recv() => match data {
+                    Some(data) => upstream.send(&data).await.map(|_| true),
+                    None => Ok(false),
+                },
+                n = upstream.recv(&mut buf) => {
+                    let n = n?;
+                    socket.send_to(&buf[..n], client_addr).await.map(|_| true)
+                }
+            }
+        })
+        .await;
+
+        match activity {
+            Ok(Ok(true)) => {}
+            Ok(Ok(false)) | Err(_) => return Ok(()),
+            Ok(Err(e)) => return Err(e.into()),
+        }
+    }
+}
+
+/// Fails over like the tcp connect, but without a handshake a candidate
+/// only fails if it doesn't resolve or its network is unreachable. Pool
+/// health checks take dead members out.
+async fn connect(candidates: &[upstream::Candidate]) -> Result<(UdpSocket, Option<Lease>)> {
+    let mut last_err = color_eyre::eyre::eyre!("No upstream candidates");
+    for candidate in candidates {
+        match tokio::time::timeout(candidate.timeout, dial(&candidate.addr)).await {
+            Ok(Ok(upstream)) => {
+                let lease = candidate.member.as_ref().map(|member| member.lease());
+                return Ok((upstream, lease));
+            }
+            Ok(Err(e)) => last_err = e,
+            Err(_) => last_err = color_eyre::eyre::eyre!("Resolving the upstream timed out"),
+        }
+
+        if LOG_CONNECTIONS.load(Ordering::Relaxed) {
+            println!("Upstream {} failed: {}", candidate.addr, last_err);
+        }
+    }
+
+    Err(last_err)
+}
+
+async fn dial(addr: &str) -> Result<UdpSocket> {
+    if let upstream::Target::Unix(_) = upstream::parse_target(addr)? {
+        color_eyre::eyre::bail!("Unix socket upstreams only work for tcp listeners");
+    }
+    let upstream_addr =
+        tokio::net::lookup_host(addr)
+            .await?
+            .next()
+            .ok_or(color_eyre::eyre::eyre!(
+                "Failed to resolve upstream address"
+            ))?;
+
+    let bind_addr = if upstream_addr.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let upstream = UdpSocket::bind(bind_addr).await?;
+    upstream.connect(upstream_addr).await?;
+    Ok(upstream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structs::{CloseInfo, FilterCall, FilterResult};
+    use futures::future::BoxFuture;
+    use std::sync::atomic::AtomicUsize;
+
+    /// Sends every flow to the same upstreams and counts the flows.
+    struct Fixed {
+        upstreams: serde_json::Value,
+        flows: Arc<AtomicUsize>,
+    }
+
+    impl Engine for Fixed {
+        fn decide(&self, _req: V8Request) -> BoxFuture<'static, Result<V8Response>> {
+            self.flows.fetch_add(1, Ordering::Relaxed);
+            let res = serde_json::from_value(serde_json::json!({ "upstreams": self.upstreams }));
+            Box::pin(async move { Ok(res?) })
+        }
+
+        fn on_close(&self, _info: CloseInfo) {}
+
+        fn filter(
+            &self,
+            _call: FilterCall,
+            _timeout: Duration,
+        ) -> BoxFuture<'static, Result<Option<FilterResult>>> {
+            Box::pin(async { Ok(None) })
+        }
+    }
+
+    async fn echo_server() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            while let Ok((n, from)) = socket.recv_from(&mut buf).await {
+                let _ = socket.send_to(&buf[..n], from).await;
+            }
+        });
+        addr
+    }
+
+    /// Starts a listener with the engine, returns a client connected to it.
+    async fn listen(engine: Fixed, idle_timeout: Duration) -> UdpSocket {
+        listen_on("127.0.0.1:0", engine, idle_timeout).await
+    }
+
+    async fn listen_on(host: &str, engine: Fixed, idle_timeout: Duration) -> UdpSocket {
+        let addr = UdpSocket::bind(host).await.unwrap().local_addr().unwrap();
+        tokio::spawn(udp_listener(addr, idle_timeout, Arc::new(engine)));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        let client = UdpSocket::bind(host).await.unwrap();
+        client.connect(addr).await.unwrap();
+        client
+    }
+
+    async fn round_trip(client: &UdpSocket, data: &[u8]) -> Vec<u8> {
+        client.send(data).await.unwrap();
+        let mut buf = [0u8; 1024];
+        let n = tokio::time::timeout(Duration::from_secs(2), client.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        buf[..n].to_vec()
+    }
+
+    #[tokio::test]
+    async fn fails_over_to_the_next_upstream() {
+        let echo = echo_server().await;
+        let flows = Arc::new(AtomicUsize::new(0));
+        let engine = Fixed {
+            upstreams: serde_json::json!([
+                { "addr": "unix:/nonexistent.sock" },
+                { "addr": echo.to_string() },
+            ]),
+            flows: flows.clone(),
+        };
+
+        let client = listen(engine, Duration::from_secs(5)).await;
+        assert_eq!(round_trip(&client, b"one").await, b"one");
+        assert_eq!(round_trip(&client, b"two").await, b"two");
+        assert_eq!(flows.load(Ordering::Relaxed), 1);
+    }
+
+    #[tokio::test]
+    async fn idle_flows_end() {
+        let echo = echo_server().await;
+        let flows = Arc::new(AtomicUsize::new(0));
+        let engine = Fixed {
+            upstreams: serde_json::json!([{ "addr": echo.to_string() }]),
+            flows: flows.clone(),
+        };
+
+        let client = listen(engine, Duration::from_millis(100)).await;
+        assert_eq!(round_trip(&client, b"one").await, b"one");
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        assert_eq!(round_trip(&client, b"two").await, b"two");
+        assert_eq!(flows.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn bans_count_once_per_flow() {
+        // the other tests run on 127.0.0.1, so the ban can't reach them
+        firewall::ban("::1", &Default::default()).unwrap();
+        let echo = echo_server().await;
+        let flows = Arc::new(AtomicUsize::new(0));
+        let engine = Fixed {
+            upstreams: serde_json::json!([{ "addr": echo.to_string() }]),
+            flows: flows.clone(),
+        };
+
+        let banned = STATS.banned.load(Ordering::Relaxed);
+        let client = listen_on("[::1]:0", engine, Duration::from_secs(5)).await;
+        for _ in 0..3 {
+            client.send(b"one").await.unwrap();
+        }
+        let mut buf = [0u8; 16];
+        let reply = tokio::time::timeout(Duration::from_millis(200), client.recv(&mut buf)).await;
+        assert!(reply.is_err());
+        assert_eq!(STATS.banned.load(Ordering::Relaxed) - banned, 1);
+        assert_eq!(flows.load(Ordering::Relaxed), 0);
+    }
+}
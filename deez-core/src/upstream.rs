@@ -0,0 +1,206 @@
+use crate::{
+    pools::{self, Lease, Member, DEFAULT_CONNECT_TIMEOUT},
+    stats::LOG_CONNECTIONS,
+    stream::Stream,
+    structs::V8Response,
+};
+use color_eyre::Result;
+use futures::{stream::FuturesUnordered, StreamExt};
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, Instant},
+};
+use tokio::net::{TcpStream, UnixStream};
+
+/// RFC 8305 "Connection Attempt Delay"
+const ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub addr: String,
+    pub weight: u32,
+    pub timeout: Duration,
+    /// Set for pool members, their latency and connections are tracked
+    pub member: Option<Arc<Member>>,
+}
+
+pub struct Connected {
+    pub stream: Stream,
+    /// Candidate that won, as the script wrote it
+    pub upstream: String,
+    /// Address it connected to, `unix:/path` for Unix sockets
+    pub addr: String,
+    pub lease: Option<Lease>,
+}
+
+/// Upstreams to try in order: the members of `pool` as its strategy
+/// orders them, `upstreams` (shuffled by weight when `upstream_order` is
+/// "weighted") or the single `ip`.
+pub fn candidates(res: &V8Response, client_ip: IpAddr) -> Result<Vec<Candidate>> {
+    let candidates = if let Some(pool) = &res.pool {
+        pools::select(pool, client_ip)?
+            .into_iter()
+            .map(|member| Candidate {
+                addr: member.addr.clone(),
+                weight: member.weight,
+                timeout: member.timeout,
+                member: Some(member),
+            })
+            .collect::<Vec<Candidate>>()
+    } else if let Some(upstreams) = &res.upstreams {
+        let candidates = upstreams
+            .iter()
+            .map(|upstream| Candidate {
+                addr: upstream.addr.clone(),
+                weight: upstream.weight.unwrap_or(1),
+                timeout: upstream
+                    .connect_timeout_ms
+                    .map(Duration::from_millis)
+                    .unwrap_or(DEFAULT_CONNECT_TIMEOUT),
+                member: None,
+            })
+            .collect::<Vec<Candidate>>();
+
+        match res.upstream_order.as_deref() {
+            Some("weighted") => pools::weighted_shuffle(candidates, |candidate| candidate.weight),
+            _ => candidates,
+        }
+    } else {
+        vec![Candidate {
+            addr: res
+                .ip
+                .clone()
+                .ok_or(color_eyre::eyre::eyre!("Ip is null in V8Response"))?,
+            weight: 1,
+            timeout: DEFAULT_CONNECT_TIMEOUT,
+            member: None,
+        }]
+    };
+
+    if candidates.is_empty() {
+        color_eyre::eyre::bail!("Upstreams are empty in V8Response");
+    }
+
+    Ok(candidates)
+}
+
+/// Tries every candidate until one accepts the connection.
+pub async fn connect(candidates: &[Candidate]) -> Result<Connected> {
+    let mut last_err = color_eyre::eyre::eyre!("No upstream candidates");
+    for candidate in candidates {
+        let start = Instant::now();
+        let res = tokio::time::timeout(candidate.timeout, dial(&candidate.addr)).await;
+        if let Some(member) = &candidate.member {
+            // failures count as the full timeout
+            member.record_latency(match res {
+                Ok(Ok(_)) => start.elapsed(),
+                _ => candidate.timeout,
+            });
+        }
+
+        match res {
+            Ok(Ok((stream, addr))) => {
+                return Ok(Connected {
+                    stream,
+                    upstream: candidate.addr.clone(),
+                    addr,
+                    lease: candidate.member.as_ref().map(|member| member.lease()),
+                })
+            }
+            Ok(Err(e)) => last_err = e,
+            Err(_) => last_err = color_eyre::eyre::eyre!("Connect timed out"),
+        }
+
+        if LOG_CONNECTIONS.load(Ordering::Relaxed) {
+            println!("Upstream {} failed: {}", candidate.addr, last_err);
+        }
+    }
+
+    Err(last_err)
+}
+
+/// How an upstream address is reached
+pub enum Target<'a> {
+    /// `unix:/path`
+    Unix(&'a str),
+    /// `host:port`, IPv6 literals in brackets
+    Host(&'a str),
+}
+
+/// Checks an upstream address before it is resolved or connected to.
+pub fn parse_target(addr: &str) -> Result<Target<'_>> {
+    if let Some(path) = addr.strip_prefix("unix:") {
+        if path.is_empty() {
+            color_eyre::eyre::bail!("Upstream {} is missing the socket path", addr);
+        }
+        return Ok(Target::Unix(path));
+    }
+
+    let (host, port) = addr.rsplit_once(':').ok_or(color_eyre::eyre::eyre!(
+        "Upstream {} is missing the port",
+        addr
+    ))?;
+    if host.contains(':') && !(host.starts_with('[') && host.ends_with(']')) {
+        color_eyre::eyre::bail!("IPv6 upstream {} needs brackets, like [::1]:25565", addr);
+    }
+    port.parse::<u16>()
+        .map_err(|e| color_eyre::eyre::eyre!("Upstream {} has an invalid port: {}", addr, e))?;
+
+    Ok(Target::Host(addr))
+}
+
+/// Connects to a Unix socket or the host, returns the address it connected to.
+pub async fn dial(addr: &str) -> Result<(Stream, String)> {
+    match parse_target(addr)? {
+        Target::Unix(path) => Ok((
+            Stream::unix(UnixStream::connect(path).await?),
+            addr.to_string(),
+        )),
+        Target::Host(host) => {
+            let (stream, addr) = happy_eyeballs(host).await?;
+            Ok((Stream::tcp(stream), addr.to_string()))
+        }
+    }
+}
+
+/// Connects to every address the host resolves to, alternating IPv6 and
+/// IPv4 and starting the next attempt if the previous one takes too long.
+async fn happy_eyeballs(host: &str) -> Result<(TcpStream, SocketAddr)> {
+    let (v6, v4): (Vec<SocketAddr>, Vec<SocketAddr>) = tokio::net::lookup_host(host)
+        .await?
+        .partition(|addr| addr.is_ipv6());
+
+    let mut addrs = vec![];
+    for i in 0..v6.len().max(v4.len()) {
+        addrs.extend(v6.get(i));
+        addrs.extend(v4.get(i));
+    }
+    let mut addrs = addrs.into_iter().peekable();
+
+    let mut attempts = FuturesUnordered::new();
+    let mut last_err = color_eyre::eyre::eyre!("{} didn't resolve to any address", host);
+    loop {
+        if let Some(addr) = addrs.next() {
+            attempts.push(async move { (addr, TcpStream::connect(addr).await) });
+        }
+
+        let delay = tokio::time::sleep(ATTEMPT_DELAY);
+        tokio::pin!(delay);
+        loop {
+            tokio::select! {
+                Some((addr, res)) = attempts.next() => match res {
+                    Ok(stream) => return Ok((stream, addr)),
+                    Err(e) => {
+                        last_err = e.into();
+                        if addrs.peek().is_some() {
+                            break;
+                        }
+                    }
+                },
+                _ = &mut delay, if addrs.peek().is_some() => break,
+                else => return Err(last_err),
+            }
+        }
+    }
+}
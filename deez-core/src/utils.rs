@@ -0,0 +1,433 @@
+use color_eyre::Result;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+
+/// Where a listener accepts connections.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Udp(SocketAddr),
+    Unix(String),
+}
+
+impl std::fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{}", addr),
+            ListenAddr::Udp(addr) => write!(f, "udp/{}", addr),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path),
+        }
+    }
+}
+
+/// Parses a comma separated list like `7000-7010,8080`. Reversed ranges,
+/// overlaps and duplicates are errors that name their column.
+pub fn parse_ports(ports: &str) -> Result<Vec<u16>> {
+    let mut res = vec![];
+    let mut seen = vec![];
+    for (col, entry) in entries(ports) {
+        let range = parse_range(entry, col)?;
+        check_overlap(&mut seen, (), range, col, |_, _| true)?;
+        res.extend(range.0..=range.1);
+    }
+    Ok(res)
+}
+
+/// Parses listener specs like `[::]:25565,127.0.0.1:7000-7010,udp/53,unix:/run/deez.sock`.
+/// Entries without an address bind `ip`, entries without a `tcp/` or `udp/`
+/// prefix use udp if `udp` is set. Errors name their column.
+pub fn parse_listen(spec: &str, ip: IpAddr, udp: bool) -> Result<Vec<ListenAddr>> {
+    let mut res = vec![];
+    let mut seen = vec![];
+    for (col, entry) in entries(spec) {
+        let (udp, rest, col) = match entry.split_once('/') {
+            Some(("tcp", rest)) => (false, rest, col + 4),
+            Some(("udp", rest)) => (true, rest, col + 4),
+            _ => (udp, entry, col),
+        };
+
+        if let Some(path) = rest.strip_prefix("unix:") {
+            if udp {
+                color_eyre::eyre::bail!("column {}: unix sockets can't be udp", col);
+            } else if path.is_empty() {
+                color_eyre::eyre::bail!("column {}: missing socket path", col + 5);
+            }
+            let addr = ListenAddr::Unix(path.to_string());
+            if res.contains(&addr) {
+                color_eyre::eyre::bail!("column {}: {} is listed twice", col, addr);
+            }
+            res.push(addr);
+            continue;
+        }
+
+        let (ip, ports, col) = if let Some(rest) = rest.strip_prefix('[') {
+            let (addr, ports) = rest
+                .split_once(']')
+                .ok_or(color_eyre::eyre::eyre!("column {}: missing ]", col))?;
+            let ip = addr
+                .parse::<Ipv6Addr>()
+                .map_err(|e| color_eyre::eyre::eyre!("column {}: {}: {}", col + 1, addr, e))?;
+            let ports = ports.strip_prefix(':').ok_or(color_eyre::eyre::eyre!(
+                "column {}: expected :port after ]",
+                col + addr.len() + 2
+            ))?;
+            (IpAddr::V6(ip), ports, col + addr.len() + 3)
+        } else if let Some((addr, ports)) = rest.rsplit_once(':') {
+            if addr.contains(':') {
+                color_eyre::eyre::bail!(
+                    "column {}: IPv6 addresses need brackets, like [::1]:25565",
+                    col
+                );
+            }
+            let ip = addr
+                .parse::<IpAddr>()
+                .map_err(|e| color_eyre::eyre::eyre!("column {}: {}: {}", col, addr, e))?;
+            (ip, ports, col + addr.len() + 1)
+        } else {
+            (ip, rest, col)
+        };
+
+        let range = parse_range(ports, col)?;
+        check_overlap(&mut seen, (udp, ip), range, col, |a, b| {
+            a.0 == b.0 && ips_overlap(a.1, b.1)
+        })?;
+        res.extend((range.0..=range.1).map(|port| match udp {
+            true => ListenAddr::Udp(SocketAddr::new(ip, port)),
+            false => ListenAddr::Tcp(SocketAddr::new(ip, port)),
+        }));
+    }
+    Ok(res)
+}
+
+/// Whether binding both addresses to the same port would fail.
+pub fn listen_overlaps(a: &ListenAddr, b: &ListenAddr) -> bool {
+    match (a, b) {
+        (ListenAddr::Tcp(a), ListenAddr::Tcp(b)) | (ListenAddr::Udp(a), ListenAddr::Udp(b)) => {
+            a.port() == b.port() && ips_overlap(a.ip(), b.ip())
+        }
+        (ListenAddr::Unix(a), ListenAddr::Unix(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// The unspecified address takes every address of its family, `[::]` also
+/// takes IPv4 unless the system binds it v6-only.
+fn ips_overlap(a: IpAddr, b: IpAddr) -> bool {
+    let takes = |any: IpAddr, other: IpAddr| {
+        any.is_unspecified() && (any.is_ipv4() == other.is_ipv4() || (any.is_ipv6() && !v6_only()))
+    };
+    a == b || takes(a, b) || takes(b, a)
+}
+
+fn v6_only() -> bool {
+    std::fs::read_to_string("/proc/sys/net/ipv6/bindv6only").is_ok_and(|value| value.trim() == "1")
+}
+
+/// The non-empty entries of a comma separated list with their 1-based column.
+fn entries(list: &str) -> impl Iterator<Item = (usize, &str)> {
+    let mut col = 1;
+    list.split(',').filter_map(move |entry| {
+        let start = col + entry.len() - entry.trim_start().len();
+        col += entry.len() + 1;
+        let entry = entry.trim();
+        (!entry.is_empty()).then_some((start, entry))
+    })
+}
+
+/// `port` or `start-end`, `col` is where `range` starts.
+fn parse_range(range: &str, col: usize) -> Result<(u16, u16)> {
+    let port = |port: &str, col: usize| {
+        let trimmed = port.trim();
+        trimmed.parse::<u16>().map_err(|e| {
+            let col = col + port.len() - port.trim_start().len();
+            color_eyre::eyre::eyre!("column {}: port \"{}\": {}", col, trimmed, e)
+        })
+    };
+
+    match range.split_once('-') {
+        Some((start, end)) => {
+            let (start, end) = (port(start, col)?, port(end, col + start.len() + 1)?);
+            if start > end {
+                color_eyre::eyre::bail!("column {}: {}-{} is reversed", col, start, end);
+            }
+            Ok((start, end))
+        }
+        None => port(range, col).map(|port| (port, port)),
+    }
+}
+
+/// Ranges with clashing keys (protocol and address) may not overlap.
+fn check_overlap<K>(
+    seen: &mut Vec<(K, (u16, u16), usize)>,
+    key: K,
+    range: (u16, u16),
+    col: usize,
+    clash: impl Fn(&K, &K) -> bool,
+) -> Result<()> {
+    let show = |(start, end): (u16, u16)| match start == end {
+        true => format!("port {}", start),
+        false => format!("{}-{}", start, end),
+    };
+
+    for (other_key, other, other_col) in seen.iter() {
+        if !clash(other_key, &key) || range.1 < other.0 || other.1 < range.0 {
+            continue;
+        } else if range == *other {
+            color_eyre::eyre::bail!(
+                "column {}: {} is already listed at column {}",
+                col,
+                show(range),
+                other_col
+            );
+        }
+        color_eyre::eyre::bail!(
+            "column {}: {} overlaps {} at column {}",
+            col,
+            show(range),
+            show(*other),
+            other_col
+        );
+    }
+
+    seen.push((key, range, col));
+    Ok(())
+}
+
+/// Returns the value following `name` in the command line arguments.
+pub fn get_arg<'a>(args: &'a [String], name: &str) -> Option<&'a str> {
+    args.iter()
+        .position(|arg| arg == name)
+        .and_then(|i| args.get(i + 1))
+        .map(|arg| arg.as_str())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub fn parse(cidr: &str) -> Result<Self> {
+        let cidr = cidr.trim();
+        let (addr, prefix) = match cidr.split_once('/') {
+            Some((addr, prefix)) => (addr.parse::<IpAddr>()?, Some(prefix.parse::<u8>()?)),
+            None => (cidr.parse::<IpAddr>()?.to_canonical(), None),
+        };
+
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = prefix.unwrap_or(max_prefix);
+        if prefix > max_prefix {
+            color_eyre::eyre::bail!("Invalid prefix length in {}", cidr);
+        }
+
+        // keep only the network part so equal networks compare equal
+        let addr = match addr {
+            IpAddr::V4(addr) => {
+                let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+                IpAddr::V4((u32::from(addr) & mask).into())
+            }
+            IpAddr::V6(addr) => {
+                let mask = u128::MAX.checked_shl(128 - prefix as u32).unwrap_or(0);
+                IpAddr::V6((u128::from(addr) & mask).into())
+            }
+        };
+
+        Ok(Self { addr, prefix })
+    }
+
+    /// The address if this covers exactly one
+    pub fn single(&self) -> Option<IpAddr> {
+        let max_prefix = if self.addr.is_ipv4() { 32 } else { 128 };
+        (self.prefix == max_prefix).then_some(self.addr)
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Scripts can pass raw bytes as an array of numbers, as text or as base64.
+pub fn decode_payload(
+    bytes: &Option<Vec<u8>>,
+    text: &Option<String>,
+    base64: &Option<String>,
+) -> Result<Vec<u8>> {
+    use base64::Engine;
+
+    if let Some(bytes) = bytes {
+        Ok(bytes.clone())
+    } else if let Some(text) = text {
+        Ok(text.as_bytes().to_vec())
+    } else if let Some(base64) = base64 {
+        Ok(base64::engine::general_purpose::STANDARD.decode(base64.trim())?)
+    } else {
+        Ok(vec![])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tcp(addr: &str) -> ListenAddr {
+        ListenAddr::Tcp(addr.parse().unwrap())
+    }
+
+    fn udp(addr: &str) -> ListenAddr {
+        ListenAddr::Udp(addr.parse().unwrap())
+    }
+
+    fn listen(spec: &str) -> Result<Vec<ListenAddr>> {
+        parse_listen(spec, "0.0.0.0".parse().unwrap(), false)
+    }
+
+    fn error(res: Result<impl std::fmt::Debug>) -> String {
+        res.unwrap_err().to_string()
+    }
+
+    #[test]
+    fn ports() {
+        assert_eq!(parse_ports("25565").unwrap(), [25565]);
+        assert_eq!(
+            parse_ports("7000-7002, 8080").unwrap(),
+            [7000, 7001, 7002, 8080]
+        );
+        assert_eq!(parse_ports(" ,80,").unwrap(), [80]);
+
+        assert_eq!(
+            error(parse_ports("7010-7000")),
+            "column 1: 7010-7000 is reversed"
+        );
+        assert_eq!(
+            error(parse_ports("80,7000-7010,7005")),
+            "column 14: port 7005 overlaps 7000-7010 at column 4"
+        );
+        assert_eq!(
+            error(parse_ports("80, 80")),
+            "column 5: port 80 is already listed at column 1"
+        );
+        assert!(error(parse_ports("80,70000")).starts_with("column 4: port \"70000\""));
+        assert!(error(parse_ports("80-")).starts_with("column 4: port \"\""));
+    }
+
+    #[test]
+    fn listen_specs() {
+        assert_eq!(listen("[::]:25565").unwrap(), [tcp("[::]:25565")]);
+        assert_eq!(
+            listen("127.0.0.1:7000-7002").unwrap(),
+            [
+                tcp("127.0.0.1:7000"),
+                tcp("127.0.0.1:7001"),
+                tcp("127.0.0.1:7002")
+            ]
+        );
+        assert_eq!(listen("udp/53").unwrap(), [udp("0.0.0.0:53")]);
+        assert_eq!(
+            listen("unix:/run/deez.sock").unwrap(),
+            [ListenAddr::Unix("/run/deez.sock".into())]
+        );
+        assert_eq!(
+            listen("tcp/[::1]:80, udp/10.0.0.1:53").unwrap(),
+            [tcp("[::1]:80"), udp("10.0.0.1:53")]
+        );
+
+        // entries without a prefix follow the udp flag
+        let ip = "127.0.0.1".parse().unwrap();
+        assert_eq!(
+            parse_listen("53,tcp/53", ip, true).unwrap(),
+            [udp("127.0.0.1:53"), tcp("127.0.0.1:53")]
+        );
+    }
+
+    #[test]
+    fn listen_errors() {
+        assert_eq!(
+            error(listen("80,127.0.0.1:7010-7000")),
+            "column 14: 7010-7000 is reversed"
+        );
+        assert_eq!(
+            error(listen("::1:80")),
+            "column 1: IPv6 addresses need brackets, like [::1]:25565"
+        );
+        assert_eq!(error(listen("[::1:80")), "column 1: missing ]");
+        assert_eq!(error(listen("[::1]80")), "column 6: expected :port after ]");
+        assert!(error(listen("[nope]:80")).starts_with("column 2: nope:"));
+        assert!(error(listen("1.2.3:80")).starts_with("column 1: 1.2.3:"));
+        assert_eq!(
+            error(listen("udp/unix:/tmp/a")),
+            "column 5: unix sockets can't be udp"
+        );
+        assert_eq!(error(listen("unix:")), "column 6: missing socket path");
+        assert_eq!(
+            error(listen("unix:/tmp/a,unix:/tmp/a")),
+            "column 13: unix:/tmp/a is listed twice"
+        );
+        assert_eq!(
+            error(listen("127.0.0.1:80,127.0.0.1:80")),
+            "column 24: port 80 is already listed at column 11"
+        );
+    }
+
+    #[test]
+    fn listen_overlaps_across_addresses() {
+        // tcp and udp, or different addresses, can share a port
+        assert!(listen("80,udp/80,127.0.0.1:81,127.0.0.2:81").is_ok());
+
+        // the unspecified address takes its whole family
+        assert_eq!(
+            error(listen("0.0.0.0:80,127.0.0.1:80")),
+            "column 22: port 80 is already listed at column 9"
+        );
+        assert!(listen("127.0.0.1:80,[::]:81,[::1]:80-81").is_err());
+        assert_eq!(listen("[::]:80,127.0.0.1:80").is_err(), !v6_only());
+
+        assert!(listen_overlaps(&tcp("0.0.0.0:80"), &tcp("10.0.0.1:80")));
+        assert!(!listen_overlaps(&tcp("0.0.0.0:80"), &tcp("10.0.0.1:81")));
+        assert!(!listen_overlaps(&tcp("0.0.0.0:80"), &udp("10.0.0.1:80")));
+        assert!(!listen_overlaps(&tcp("0.0.0.0:80"), &tcp("[::1]:80")));
+        assert_eq!(
+            listen_overlaps(&tcp("[::]:80"), &tcp("10.0.0.1:80")),
+            !v6_only()
+        );
+        assert!(listen_overlaps(
+            &ListenAddr::Unix("/a".into()),
+            &ListenAddr::Unix("/a".into())
+        ));
+    }
+
+    #[test]
+    fn cidrs() {
+        let net = Cidr::parse("10.1.2.3/8").unwrap();
+        assert_eq!(net, Cidr::parse("10.0.0.0/8").unwrap());
+        assert!(net.contains("10.255.0.1".parse().unwrap()));
+        assert!(net.contains("::ffff:10.0.0.1".parse().unwrap()));
+        assert!(!net.contains("11.0.0.1".parse().unwrap()));
+        assert!(!net.contains("::1".parse().unwrap()));
+        assert_eq!(net.single(), None);
+
+        let net = Cidr::parse("2001:db8::/32").unwrap();
+        assert!(net.contains("2001:db8:1::1".parse().unwrap()));
+        assert!(!net.contains("2001:db9::1".parse().unwrap()));
+
+        let ip = Cidr::parse(" ::ffff:127.0.0.1 ").unwrap();
+        assert_eq!(ip.single(), Some("127.0.0.1".parse().unwrap()));
+
+        assert!(Cidr::parse("0.0.0.0/0")
+            .unwrap()
+            .contains("1.2.3.4".parse().unwrap()));
+        assert!(Cidr::parse("10.0.0.0/33").is_err());
+        assert!(Cidr::parse("::/129").is_err());
+        assert!(Cidr::parse("10.0.0.0/").is_err());
+        assert!(Cidr::parse("example.com").is_err());
+    }
+}
@@ -1,6 +1,12 @@
 import * as console from 'ext:console/console.js';
 import * as others from 'ext:others/others.js';
 import * as fetch from 'ext:fetch/fetch.js';
+import * as firewall from 'ext:firewall/firewall.js';
+import * as kv from 'ext:kv/kv.js';
+import * as pools from 'ext:pools/pools.js';
+import * as ratelimit from 'ext:ratelimit/ratelimit.js';
+import * as store from 'ext:store/store.js';
+import * as upstreams from 'ext:upstreams/upstreams.js';
 
 globalThis.console = console;
 
@@ -11,3 +17,11 @@ globalThis.fetch = fetch.fetch;
 globalThis.Response = fetch.Response;
 globalThis.Request = fetch.Request;
 globalThis.Headers = fetch.Headers;
+
+globalThis.Firewall = firewall;
+globalThis.KV = kv;
+globalThis.Pools = pools;
+globalThis.upstreams = upstreams;
+globalThis.RateLimit = ratelimit;
+
+Deno.openKv = store.openKv;
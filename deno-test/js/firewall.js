@@ -0,0 +1,21 @@
+// Banned clients are rejected right after accept, before any script runs.
+// Takes an IP or CIDR, e.g. ban(req.ip, { ttl_ms: 3600000, reason: "spam" })
+function ban(target, options) {
+    Deno.core.ops.op_firewall_ban(target, options ?? null);
+}
+
+// only lifts bans added by scripts, not the ones from --ban-lists files
+function unban(target) {
+    return Deno.core.ops.op_firewall_unban(target);
+}
+
+// { target, reason, expires_in_ms } or null
+function isBanned(ip) {
+    return Deno.core.ops.op_firewall_check(ip);
+}
+
+export {
+    ban,
+    unban,
+    isBanned
+}
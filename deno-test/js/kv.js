@@ -0,0 +1,30 @@
+// Shared by every worker, values are anything JSON can hold
+function get(key) {
+    return Deno.core.ops.op_kv_get(key);
+}
+
+function set(key, value, options) {
+    Deno.core.ops.op_kv_set(key, value, options?.ttl_ms ?? null);
+}
+
+function del(key) {
+    return Deno.core.ops.op_kv_delete(key);
+}
+
+// the TTL is only set if the key didn't exist yet
+function incr(key, by = 1, options) {
+    return Deno.core.ops.op_kv_incr(key, by, options?.ttl_ms ?? null);
+}
+
+// null as expected means the key must not exist
+function cas(key, expected, value, options) {
+    return Deno.core.ops.op_kv_cas(key, expected ?? null, value, options?.ttl_ms ?? null);
+}
+
+export {
+    get,
+    set,
+    del as delete,
+    incr,
+    cas
+}
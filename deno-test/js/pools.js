@@ -0,0 +1,13 @@
+// Pools are shared by every worker, registering the same pool again keeps its state
+function register(name, config) {
+    Deno.core.ops.op_pool_register(name, config);
+}
+
+function remove(name) {
+    return Deno.core.ops.op_pool_remove(name);
+}
+
+export {
+    register,
+    remove
+}
@@ -0,0 +1,8 @@
+// Budgets are shared by every worker, e.g. check("ip:" + req.ip, { rate: 10, per: "1m" })
+function check(key, options) {
+    return Deno.core.ops.op_ratelimit_check(key, options);
+}
+
+export {
+    check
+}
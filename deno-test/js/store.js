@@ -0,0 +1,60 @@
+// Durable KV, entries are kept in the data directory (--data-dir) and
+// survive restarts. Keys are arrays of strings, numbers and booleans.
+class Kv {
+    constructor(namespace) {
+        this.namespace = namespace;
+    }
+
+    // { key, value }, value is null if the key doesn't exist
+    async get(key) {
+        key = toKey(key);
+        const value = decode(await Deno.core.ops.op_store_get(this.namespace, key));
+        return { key, value };
+    }
+
+    // options.expireIn is a TTL in ms, named like Deno.openKv's since this
+    // mirrors it. KV and Firewall, which don't, take ttl_ms
+    async set(key, value, options) {
+        await Deno.core.ops.op_store_set(this.namespace, toKey(key), value ?? null, options?.expireIn ?? null);
+    }
+
+    async delete(key) {
+        return decode(await Deno.core.ops.op_store_delete(this.namespace, toKey(key)));
+    }
+
+    // for await (const { key, value } of kv.list({ prefix: ["bans"] })) {}
+    list(selector, options) {
+        const entries = Deno.core.ops.op_store_list(
+            this.namespace,
+            toKey(selector?.prefix ?? []),
+            options?.limit ?? null,
+            options?.reverse ?? false
+        );
+
+        return {
+            async *[Symbol.asyncIterator]() {
+                yield* decode(await entries);
+            }
+        };
+    }
+
+    close() { }
+}
+
+function decode(json) {
+    return JSON.parse(new TextDecoder().decode(json));
+}
+
+function toKey(key) {
+    return Array.isArray(key) ? key : [key];
+}
+
+// Every namespace is its own file, "default" if none is given
+async function openKv(namespace = "default") {
+    await Deno.core.ops.op_store_open(namespace);
+    return new Kv(namespace);
+}
+
+export {
+    openKv
+}
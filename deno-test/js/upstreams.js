@@ -0,0 +1,8 @@
+// Health of a pool member by address, null if no pool has it
+function status(addr) {
+    return Deno.core.ops.op_upstream_status(addr);
+}
+
+export {
+    status
+}
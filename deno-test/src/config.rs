@@ -0,0 +1,48 @@
+use color_eyre::Result;
+use deez_core::config::{flag, EngineConfig};
+
+/// The `[engine]` table, settings of the worker pool
+#[derive(serde::Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct WorkerConfig {
+    /// Threads running scripts
+    pub workers: usize,
+    /// Connections waiting for a worker before new ones are dropped, 0 for
+    /// no limit
+    pub queue_limit: usize,
+    pub data_dir: String,
+}
+
+impl Default for WorkerConfig {
+    fn default() -> Self {
+        WorkerConfig {
+            workers: 100,
+            queue_limit: 0,
+            data_dir: "data".into(),
+        }
+    }
+}
+
+impl EngineConfig for WorkerConfig {
+    fn apply_args(&mut self, args: &[String]) -> Result<()> {
+        if let Some(value) = flag(args, "--workers")? {
+            self.workers = value;
+        }
+        if let Some(value) = flag(args, "--queue-limit")? {
+            self.queue_limit = value;
+        }
+        if let Some(dir) = flag(args, "--data-dir")? {
+            self.data_dir = dir;
+        }
+
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<()> {
+        if self.workers == 0 {
+            color_eyre::eyre::bail!("engine.workers: has to be at least 1");
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,31 @@
+use deez_core::structs::{BanInfo, BanOptions};
+use deno_core::{error::AnyError, op2};
+
+deno_core::extension!(
+    firewall,
+    ops = [op_firewall_ban, op_firewall_unban, op_firewall_check],
+    esm = [ dir "js", "firewall.js"]
+);
+
+#[op2]
+pub fn op_firewall_ban(
+    #[string] target: String,
+    #[serde] options: Option<BanOptions>,
+) -> Result<(), AnyError> {
+    deez_core::firewall::ban(&target, &options.unwrap_or_default())
+        .map_err(|e| deno_core::error::type_error(e.to_string()))
+}
+
+#[op2(fast)]
+pub fn op_firewall_unban(#[string] target: String) -> Result<bool, AnyError> {
+    deez_core::firewall::unban(&target).map_err(|e| deno_core::error::type_error(e.to_string()))
+}
+
+#[op2]
+#[serde]
+pub fn op_firewall_check(#[string] ip: String) -> Result<Option<BanInfo>, AnyError> {
+    let ip = ip
+        .parse()
+        .map_err(|_| deno_core::error::type_error(format!("Invalid IP: {}", ip)))?;
+    Ok(deez_core::firewall::check(ip))
+}
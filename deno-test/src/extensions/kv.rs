@@ -0,0 +1,46 @@
+use deno_core::{error::AnyError, op2};
+
+// #[op2] reserves the name Value for v8::Value
+type JsonValue = deno_core::serde_json::Value;
+
+deno_core::extension!(
+    kv,
+    ops = [op_kv_get, op_kv_set, op_kv_delete, op_kv_incr, op_kv_cas],
+    esm = [ dir "js", "kv.js"]
+);
+
+#[op2]
+#[serde]
+pub fn op_kv_get(#[string] key: String) -> Option<JsonValue> {
+    deez_core::kv::get(&key)
+}
+
+#[op2]
+pub fn op_kv_set(#[string] key: String, #[serde] value: JsonValue, #[serde] ttl_ms: Option<u64>) {
+    deez_core::kv::set(&key, value, ttl_ms)
+}
+
+#[op2(fast)]
+pub fn op_kv_delete(#[string] key: String) -> bool {
+    deez_core::kv::delete(&key)
+}
+
+#[op2]
+#[serde]
+pub fn op_kv_incr(
+    #[string] key: String,
+    by: f64,
+    #[serde] ttl_ms: Option<u64>,
+) -> Result<JsonValue, AnyError> {
+    deez_core::kv::incr(&key, by, ttl_ms).map_err(|e| deno_core::error::type_error(e.to_string()))
+}
+
+#[op2]
+pub fn op_kv_cas(
+    #[string] key: String,
+    #[serde] expected: JsonValue,
+    #[serde] value: JsonValue,
+    #[serde] ttl_ms: Option<u64>,
+) -> bool {
+    deez_core::kv::compare_and_swap(&key, &expected, value, ttl_ms)
+}
@@ -1,14 +1,21 @@
-use crate::{structs::V8Response, JOB_QUEUE};
+use crate::workers::{FILTER_QUEUE, JOB_QUEUE};
+use deez_core::structs::{FilterResult, V8Response};
 use deno_core::{op2, Extension};
 
 mod console;
 mod fetch;
+mod firewall;
+mod kv;
 mod others;
+mod pools;
+mod ratelimit;
+mod store;
+mod upstreams;
 
 deno_core::extension!(
     runtime,
-    deps = [console, others, fetch],
-    ops = [op_callback],
+    deps = [console, others, fetch, firewall, kv, pools, ratelimit, store, upstreams],
+    ops = [op_callback, op_filter_callback],
     esm = [ dir "js", "entry.js"],
 );
 
@@ -21,8 +28,10 @@ deno_core::extension!(
 #[op2(async)]
 async fn op_callback(
     job_id: u32,
-    #[serde] response: V8Response,
+    #[serde] mut response: V8Response,
+    on_close: bool,
 ) -> Result<(), deno_core::error::AnyError> {
+    response.on_close = on_close;
     JOB_QUEUE
         .send_response(job_id, response)
         .await
@@ -31,11 +40,28 @@ async fn op_callback(
     Ok(())
 }
 
+#[op2(async)]
+async fn op_filter_callback(
+    job_id: u32,
+    #[serde] result: FilterResult,
+) -> Result<(), deno_core::error::AnyError> {
+    // the connection may have stopped waiting, its budget ran out
+    let _ = FILTER_QUEUE.send_response(job_id, result).await;
+
+    Ok(())
+}
+
 pub fn get_all_extensions() -> Vec<Extension> {
     vec![
         others::others::init_ops_and_esm(),
         console::console::init_ops_and_esm(),
         fetch::fetch::init_ops_and_esm(),
+        firewall::firewall::init_ops_and_esm(),
+        kv::kv::init_ops_and_esm(),
+        pools::pools::init_ops_and_esm(),
+        ratelimit::ratelimit::init_ops_and_esm(),
+        store::store::init_ops_and_esm(),
+        upstreams::upstreams::init_ops_and_esm(),
         // MUST BE LAST
         runtime::init_ops_and_esm(),
         runtime_entry::init_ops_and_esm(),
@@ -0,0 +1,22 @@
+use deez_core::structs::PoolConfig;
+use deno_core::{error::AnyError, op2};
+
+deno_core::extension!(
+    pools,
+    ops = [op_pool_register, op_pool_remove],
+    esm = [ dir "js", "pools.js"]
+);
+
+#[op2]
+pub fn op_pool_register(
+    #[string] name: String,
+    #[serde] config: PoolConfig,
+) -> Result<(), AnyError> {
+    deez_core::pools::register(&name, &config)
+        .map_err(|e| deno_core::error::type_error(e.to_string()))
+}
+
+#[op2(fast)]
+pub fn op_pool_remove(#[string] name: String) -> bool {
+    deez_core::pools::remove(&name)
+}
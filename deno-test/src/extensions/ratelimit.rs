@@ -0,0 +1,18 @@
+use deez_core::structs::{RateLimitOptions, RateLimitResult};
+use deno_core::{error::AnyError, op2};
+
+deno_core::extension!(
+    ratelimit,
+    ops = [op_ratelimit_check],
+    esm = [ dir "js", "ratelimit.js"]
+);
+
+#[op2]
+#[serde]
+pub fn op_ratelimit_check(
+    #[string] key: String,
+    #[serde] options: RateLimitOptions,
+) -> Result<RateLimitResult, AnyError> {
+    deez_core::ratelimit::check(&key, &options)
+        .map_err(|e| deno_core::error::type_error(e.to_string()))
+}
@@ -0,0 +1,83 @@
+use deno_core::{error::AnyError, op2};
+
+// #[op2] reserves the name Value for v8::Value
+type JsonValue = deno_core::serde_json::Value;
+
+deno_core::extension!(
+    store,
+    ops = [
+        op_store_open,
+        op_store_get,
+        op_store_set,
+        op_store_delete,
+        op_store_list
+    ],
+    esm = [ dir "js", "store.js"]
+);
+
+/// Disk access blocks, so it runs off the worker's thread.
+async fn blocking<T, F>(namespace: String, f: F) -> Result<T, AnyError>
+where
+    T: Send + 'static,
+    F: FnOnce(&mut crate::store::Store) -> color_eyre::Result<T> + Send + 'static,
+{
+    tokio::task::spawn_blocking(move || {
+        let store = crate::store::open(&namespace)?;
+        let mut store = store.lock().unwrap();
+        f(&mut store)
+    })
+    .await?
+    .map_err(|e| deno_core::error::type_error(e.to_string()))
+}
+
+// async ops can only return buffers, so results come back as JSON like fetch's
+fn json<T: serde::Serialize>(value: &T) -> color_eyre::Result<Vec<u8>> {
+    Ok(deno_core::serde_json::to_vec(value)?)
+}
+
+#[op2(async)]
+pub async fn op_store_open(#[string] namespace: String) -> Result<(), AnyError> {
+    blocking(namespace, |_| Ok(())).await
+}
+
+#[op2(async)]
+#[buffer]
+pub async fn op_store_get(
+    #[string] namespace: String,
+    #[serde] key: Vec<JsonValue>,
+) -> Result<Vec<u8>, AnyError> {
+    blocking(namespace, move |store| json(&store.get(&key)?)).await
+}
+
+#[op2(async)]
+pub async fn op_store_set(
+    #[string] namespace: String,
+    #[serde] key: Vec<JsonValue>,
+    #[serde] value: JsonValue,
+    #[serde] expire_in_ms: Option<u64>,
+) -> Result<(), AnyError> {
+    blocking(namespace, move |store| store.set(key, value, expire_in_ms)).await
+}
+
+#[op2(async)]
+#[buffer]
+pub async fn op_store_delete(
+    #[string] namespace: String,
+    #[serde] key: Vec<JsonValue>,
+) -> Result<Vec<u8>, AnyError> {
+    blocking(namespace, move |store| json(&store.delete(key)?)).await
+}
+
+#[op2(async)]
+#[buffer]
+pub async fn op_store_list(
+    #[string] namespace: String,
+    #[serde] prefix: Vec<JsonValue>,
+    #[serde] limit: Option<usize>,
+    reverse: bool,
+) -> Result<Vec<u8>, AnyError> {
+    blocking(namespace, move |store| {
+        json(&store.list(&prefix, limit, reverse)?)
+    })
+    .await
+}
@@ -0,0 +1,14 @@
+use deez_core::structs::HealthStatus;
+use deno_core::op2;
+
+deno_core::extension!(
+    upstreams,
+    ops = [op_upstream_status],
+    esm = [ dir "js", "upstreams.js"]
+);
+
+#[op2]
+#[serde]
+pub fn op_upstream_status(#[string] addr: String) -> Option<HealthStatus> {
+    deez_core::pools::status(&addr)
+}
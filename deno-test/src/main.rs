@@ -1,23 +1,43 @@
-use crate::workers::JOB_QUEUE;
 use color_eyre::Result;
-use structs::V8Response;
-use tokio::net::TcpStream;
-use workers::port_listener;
-use workers::v8_worker;
+use config::WorkerConfig;
+use deez_core::{
+    firewall, health, history, kv, listener, pools,
+    stats::{self, LOG_CONNECTIONS},
+    structs::{AcceptProxy, ListenerOptions},
+    tarpit, udp,
+    utils::ListenAddr,
+};
+use std::sync::Arc;
+use workers::{v8_worker, WorkerEngine};
 
+mod config;
 mod extensions;
-mod structs;
-mod utils;
+mod queue;
+mod store;
 mod workers;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     color_eyre::install()?;
-    workers::worker_script_updater()?;
 
-    let workers_count = 100usize;
+    let args = std::env::args().collect::<Vec<String>>();
+    let config = deez_core::config::load::<WorkerConfig>(&args)?;
+    let listeners = config.listeners()?;
+
+    let mut scripts = listeners
+        .iter()
+        .map(|listener| Arc::from(listener.script.as_str()))
+        .collect::<Vec<Arc<str>>>();
+    scripts.sort();
+    scripts.dedup();
+    workers::worker_script_updater(scripts)?;
+    workers::QUEUE_LIMIT.store(
+        config.engine.queue_limit,
+        std::sync::atomic::Ordering::Relaxed,
+    );
+
     let mut workers = vec![];
-    for i in 0..workers_count {
+    for i in 0..config.engine.workers {
         workers.push(std::thread::spawn(move || {
             let rt = tokio::runtime::Builder::new_current_thread()
                 .enable_all()
@@ -34,33 +54,66 @@ async fn main() -> Result<()> {
         }));
     }
 
-    let args = std::env::args().collect::<Vec<String>>();
-    let ports = utils::parse_ports(args.get(1).unwrap_or(&String::from("7070")))?;
-    let mut tasks = vec![];
+    let trusted_proxies = config.trusted_proxies()?;
+    if trusted_proxies.is_empty()
+        && listeners.iter().any(|listener| {
+            listener.accept_proxy != AcceptProxy::Off
+                && !matches!(listener.addr, ListenAddr::Unix(_))
+        })
+    {
+        println!("No --trusted-proxies set, every inbound PROXY header will be rejected!");
+    }
 
-    for port in ports {
-        tasks.push(tokio::spawn(port_listener("0.0.0.0", port)));
+    tarpit::TARPIT_MAX.store(config.tarpit_max, std::sync::atomic::Ordering::Relaxed);
+    if let Some(path) = &config.pools_file {
+        pools::load_file(path)?;
     }
-    futures::future::try_join_all(tasks).await?;
+    for (name, pool) in &config.pools {
+        pools::register(name, pool)
+            .map_err(|e| color_eyre::eyre::eyre!("pools.{}: {}", name, e))?;
+    }
+    health::health_checker();
+    firewall::ban_list_updater(config.ban_lists.clone())?;
+    kv::KV_MAX_BYTES.store(config.kv_max_bytes, std::sync::atomic::Ordering::Relaxed);
+    *store::DATA_DIR.write().unwrap() = config.engine.data_dir.clone().into();
+    history::HISTORY_MAX_CLIENTS.store(
+        config.history.max_clients,
+        std::sync::atomic::Ordering::Relaxed,
+    );
+    *history::HISTORY_WINDOWS.write().unwrap() = config.history_windows();
+    LOG_CONNECTIONS.store(config.log.connections, std::sync::atomic::Ordering::Relaxed);
+    stats::stats_reporter(std::time::Duration::from_secs(
+        config.log.stats_interval_sec,
+    ));
 
-    Ok(())
-}
+    let mut tasks = vec![];
+    for listener in listeners {
+        let engine = Arc::new(WorkerEngine {
+            script: Arc::from(listener.script.as_str()),
+        });
+        if let ListenAddr::Udp(addr) = listener.addr {
+            tasks.push(tokio::spawn(udp::udp_listener(
+                addr,
+                config.udp_flow_idle(),
+                engine,
+            )));
+            continue;
+        }
 
-async fn handle_client(mut socket: TcpStream, res: V8Response) -> Result<()> {
-    if res.block_connection.unwrap_or(false) {
-        return Ok(());
-    } else if res.hang_connection.unwrap_or(false) {
-        tokio::time::sleep(tokio::time::Duration::from_secs(30)).await;
-        return Ok(());
+        let options = ListenerOptions {
+            accept_proxy: listener.accept_proxy,
+            trusted_proxies: trusted_proxies.clone(),
+            sniff: listener.sniff,
+            timeouts: config.timeouts(),
+            proxy_timeout: listener.proxy_timeout,
+        };
+        tasks.push(tokio::spawn(listener::port_listener(
+            listener.addr,
+            options,
+            engine,
+        )));
     }
+    futures::future::try_join_all(tasks).await?;
 
-    let mut out_stream = TcpStream::connect(
-        res.ip
-            .ok_or(color_eyre::eyre::eyre!("Ip is null in V8Response"))?,
-    )
-    .await?;
-    out_stream.set_nodelay(res.no_delay.unwrap_or(false))?;
-
-    tokio::io::copy_bidirectional(&mut socket, &mut out_stream).await?;
     Ok(())
 }
@@ -1,24 +1,6 @@
 use color_eyre::Result;
 use std::{collections::HashMap, sync::Arc};
 
-#[derive(serde::Deserialize, Debug)]
-#[allow(dead_code)]
-pub struct V8Response {
-    pub block_connection: Option<bool>,
-    pub hang_connection: Option<bool>,
-    pub ip: Option<String>,
-    pub no_delay: Option<bool>,
-
-    pub cpu_time: Option<u64>,
-}
-
-#[derive(serde::Serialize, Debug)]
-#[allow(dead_code)]
-pub struct V8Request {
-    pub ip: String,
-    pub port: u16,
-}
-
 #[derive(serde::Serialize, Debug)]
 pub struct WorkerRequest<T> {
     pub job_id: u32,
@@ -77,6 +59,11 @@ where
         Ok(())
     }
 
+    /// Jobs no worker picked up yet
+    pub fn len(&self) -> usize {
+        self.queue_rx.len()
+    }
+
     pub fn get_rx(&self) -> crossbeam_channel::Receiver<WorkerRequest<S>> {
         self.queue_rx.clone()
     }
@@ -0,0 +1,292 @@
+use color_eyre::Result;
+use deno_core::serde_json::{self, Value};
+use lazy_static::lazy_static;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Logs are rewritten once they hold this many records more than entries
+const COMPACT_SLACK: usize = 1000;
+
+lazy_static! {
+    pub static ref DATA_DIR: RwLock<PathBuf> = RwLock::new(PathBuf::from("data"));
+    /// Open namespaces, every worker shares one store per namespace
+    static ref STORES: Mutex<HashMap<String, Arc<Mutex<Store>>>> = Mutex::new(HashMap::new());
+}
+
+/// One line of the log, later lines win
+#[derive(serde::Serialize, serde::Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum Record {
+    Set {
+        key: Vec<Value>,
+        value: Value,
+        expires_at: Option<u64>,
+    },
+    Delete {
+        key: Vec<Value>,
+    },
+}
+
+struct Entry {
+    key: Vec<Value>,
+    value: Value,
+    /// Unix time in ms, so TTLs keep counting across restarts
+    expires_at: Option<u64>,
+}
+
+impl Entry {
+    fn expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+#[derive(serde::Serialize, Debug)]
+pub struct StoreEntry {
+    pub key: Vec<Value>,
+    pub value: Value,
+}
+
+/// A namespace kept in memory and backed by an append-only log in the
+/// data directory. Every write is synced before it returns.
+pub struct Store {
+    path: PathBuf,
+    file: File,
+    /// By encoded key, see `encode`
+    entries: BTreeMap<String, Entry>,
+    records: usize,
+}
+
+/// Opens the namespace's store, loading it from disk the first time.
+pub fn open(namespace: &str) -> Result<Arc<Mutex<Store>>> {
+    if namespace.is_empty()
+        || !namespace
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        color_eyre::eyre::bail!("Invalid KV namespace: {}", namespace);
+    }
+
+    let mut stores = STORES.lock().unwrap();
+    if let Some(store) = stores.get(namespace) {
+        return Ok(store.clone());
+    }
+
+    let dir = DATA_DIR.read().unwrap().clone();
+    std::fs::create_dir_all(&dir)?;
+    let store = Arc::new(Mutex::new(Store::load(
+        dir.join(format!("{}.kv", namespace)),
+    )?));
+    stores.insert(namespace.to_string(), store.clone());
+    Ok(store)
+}
+
+impl Store {
+    fn load(path: PathBuf) -> Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)?;
+
+        let mut entries = BTreeMap::new();
+        let mut records = 0;
+        let mut valid_len = 0;
+        let mut reader = BufReader::new(&file);
+        let mut line = String::new();
+        loop {
+            line.clear();
+            let n = reader.read_line(&mut line)?;
+            if n == 0 {
+                break;
+            }
+
+            // a crash can leave a torn last line, nothing after it is trusted
+            let record = match line.ends_with('\n') {
+                true => serde_json::from_str::<Record>(&line).ok(),
+                false => None,
+            };
+            let Some(record) = record else {
+                println!("Dropping the damaged end of {}", path.display());
+                break;
+            };
+
+            apply(&mut entries, record)?;
+            records += 1;
+            valid_len += n as u64;
+        }
+
+        if valid_len < file.metadata()?.len() {
+            file.set_len(valid_len)?;
+            file.sync_all()?;
+        }
+
+        let mut store = Store {
+            path,
+            file,
+            entries,
+            records,
+        };
+        store.compact_if_needed()?;
+        Ok(store)
+    }
+
+    pub fn get(&mut self, key: &[Value]) -> Result<Option<Value>> {
+        let encoded = encode(key)?;
+        let expired = match self.entries.get(&encoded) {
+            Some(entry) => entry.expired(now_ms()),
+            None => return Ok(None),
+        };
+        if expired {
+            self.entries.remove(&encoded);
+            return Ok(None);
+        }
+
+        Ok(self.entries.get(&encoded).map(|entry| entry.value.clone()))
+    }
+
+    /// Without `expire_in_ms` the key never expires, an existing expiry is
+    /// replaced.
+    pub fn set(&mut self, key: Vec<Value>, value: Value, expire_in_ms: Option<u64>) -> Result<()> {
+        let expires_at = expire_in_ms.filter(|ms| *ms > 0).map(|ms| now_ms() + ms);
+        self.write(Record::Set {
+            key,
+            value,
+            expires_at,
+        })
+    }
+
+    pub fn delete(&mut self, key: Vec<Value>) -> Result<bool> {
+        let existed = self.get(&key)?.is_some();
+        if existed {
+            self.write(Record::Delete { key })?;
+        }
+
+        Ok(existed)
+    }
+
+    /// Live entries whose key starts with every part of `prefix`, in key order.
+    pub fn list(
+        &self,
+        prefix: &[Value],
+        limit: Option<usize>,
+        reverse: bool,
+    ) -> Result<Vec<StoreEntry>> {
+        // every key starting with the prefix parts also starts with the
+        // prefix's encoding without the closing bracket
+        let mut start = encode(prefix)?;
+        start.pop();
+
+        let now = now_ms();
+        let mut matches = self
+            .entries
+            .range(start.clone()..)
+            .take_while(|(encoded, _)| encoded.starts_with(&start))
+            .map(|(_, entry)| entry)
+            .filter(|entry| entry.key.starts_with(prefix) && !entry.expired(now))
+            .collect::<Vec<&Entry>>();
+        if reverse {
+            matches.reverse();
+        }
+
+        Ok(matches
+            .into_iter()
+            .take(limit.unwrap_or(usize::MAX))
+            .map(|entry| StoreEntry {
+                key: entry.key.clone(),
+                value: entry.value.clone(),
+            })
+            .collect())
+    }
+
+    fn write(&mut self, record: Record) -> Result<()> {
+        let mut line = serde_json::to_string(&record)?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        self.file.sync_data()?;
+
+        apply(&mut self.entries, record)?;
+        self.records += 1;
+        self.compact_if_needed()
+    }
+
+    /// Rewrites the log with only the live entries. The new log is synced
+    /// before it replaces the old one, a crash leaves one of them intact.
+    fn compact_if_needed(&mut self) -> Result<()> {
+        if self.records <= self.entries.len() * 2 + COMPACT_SLACK {
+            return Ok(());
+        }
+
+        let now = now_ms();
+        self.entries.retain(|_, entry| !entry.expired(now));
+
+        let tmp = self.path.with_extension("kv.tmp");
+        let mut file = File::create(&tmp)?;
+        for entry in self.entries.values() {
+            let mut line = serde_json::to_string(&Record::Set {
+                key: entry.key.clone(),
+                value: entry.value.clone(),
+                expires_at: entry.expires_at,
+            })?;
+            line.push('\n');
+            file.write_all(line.as_bytes())?;
+        }
+        file.sync_all()?;
+        std::fs::rename(&tmp, &self.path)?;
+        if let Some(dir) = self.path.parent().filter(|dir| dir != &Path::new("")) {
+            File::open(dir)?.sync_all()?;
+        }
+
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        self.records = self.entries.len();
+        Ok(())
+    }
+}
+
+fn apply(entries: &mut BTreeMap<String, Entry>, record: Record) -> Result<()> {
+    match record {
+        Record::Set {
+            key,
+            value,
+            expires_at,
+        } => {
+            entries.insert(
+                encode(&key)?,
+                Entry {
+                    key,
+                    value,
+                    expires_at,
+                },
+            );
+        }
+        Record::Delete { key } => {
+            entries.remove(&encode(&key)?);
+        }
+    }
+
+    Ok(())
+}
+
+/// Keys are arrays of strings, numbers and booleans, stored as their JSON.
+fn encode(key: &[Value]) -> Result<String> {
+    if let Some(part) = key
+        .iter()
+        .find(|part| !(part.is_string() || part.is_number() || part.is_boolean()))
+    {
+        color_eyre::eyre::bail!("Invalid KV key part: {}", part);
+    }
+
+    Ok(serde_json::to_string(key)?)
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
@@ -1,36 +1,89 @@
 use color_eyre::Result;
 use deno_core::{JsRuntime, RuntimeOptions};
+use futures::future::BoxFuture;
 use lazy_static::lazy_static;
-use std::sync::Arc;
-use tokio::{net::TcpListener, sync::RwLock};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, RwLock,
+    },
+    time::Duration,
+};
 
-use crate::{
-    handle_client,
-    structs::{Queue, V8Request, V8Response},
+use crate::queue::Queue;
+use deez_core::{
+    engine::Engine,
+    stats::{Stats, STATS},
+    structs::{CloseInfo, FilterCall, FilterResult, V8Request, V8Response},
 };
 
+/// onClose calls waiting for a worker before new ones are dropped
+const CLOSE_QUEUE_SIZE: usize = 10_000;
+
+/// Connections waiting for a worker before new ones are dropped, 0 for no
+/// limit
+pub static QUEUE_LIMIT: AtomicUsize = AtomicUsize::new(0);
+
+/// onClose arguments and the script that gets them
+type CloseEvent = (Arc<str>, CloseInfo);
+
 lazy_static! {
-    pub static ref JOB_QUEUE: Queue<V8Request, V8Response> = Queue::new();
-    pub static ref WORKER_SCRIPT: Arc<RwLock<String>> = {
-        let script = std::fs::read_to_string("main.js").unwrap();
-        Arc::new(RwLock::new(script))
-    };
+    /// Jobs go with the path of the script they run
+    pub static ref JOB_QUEUE: Queue<(Arc<str>, V8Request), V8Response> = Queue::new();
+    /// Chunks of connections the script filters
+    pub static ref FILTER_QUEUE: Queue<(Arc<str>, FilterCall), FilterResult> = Queue::new();
+    static ref CLOSE_EVENTS: (
+        crossbeam_channel::Sender<CloseEvent>,
+        crossbeam_channel::Receiver<CloseEvent>
+    ) = crossbeam_channel::bounded(CLOSE_QUEUE_SIZE);
+    /// Source of every listener's script by path
+    pub static ref SCRIPTS: RwLock<HashMap<Arc<str>, String>> = RwLock::new(HashMap::new());
 }
 
-pub fn worker_script_updater() -> Result<()> {
+/// Loads the scripts, then reloads each of them whenever it changes.
+/// Changes with syntax errors are skipped, the listeners using that script
+/// keep its last working version.
+pub fn worker_script_updater(paths: Vec<Arc<str>>) -> Result<()> {
+    let mut scripts = HashMap::new();
+    for path in &paths {
+        let script = std::fs::read_to_string(&**path)
+            .map_err(|e| color_eyre::eyre::eyre!("Can't read script {}: {}", path, e))?;
+        check_script(&script).map_err(|e| color_eyre::eyre::eyre!("Script {}: {}", path, e))?;
+        scripts.insert(path.clone(), script);
+    }
+    *SCRIPTS.write().unwrap() = scripts;
+
     tokio::spawn(async move {
         let mut interval = tokio::time::interval(std::time::Duration::from_secs(5));
+        let mut rejected: HashMap<Arc<str>, String> = HashMap::new();
 
         loop {
             interval.tick().await;
 
-            let script = tokio::fs::read_to_string("main.js").await;
-            if let Ok(script) = script {
-                if script != *WORKER_SCRIPT.read().await {
-                    println!("WORKER SCRIPT UPDATED");
+            for path in &paths {
+                let Ok(script) = tokio::fs::read_to_string(&**path).await else {
+                    continue;
+                };
+                if SCRIPTS.read().unwrap().get(path) == Some(&script)
+                    || rejected.get(path) == Some(&script)
+                {
+                    continue;
+                }
 
-                    let mut worker_script = WORKER_SCRIPT.write().await;
-                    *worker_script = script;
+                match check_script(&script) {
+                    Ok(()) => {
+                        println!("WORKER SCRIPT UPDATED: {}", path);
+                        SCRIPTS.write().unwrap().insert(path.clone(), script);
+                        rejected.remove(path);
+                    }
+                    Err(e) => {
+                        println!(
+                            "WORKER SCRIPT UPDATE SKIPPED: {}, keeping the last working version: {}",
+                            path, e
+                        );
+                        rejected.insert(path.clone(), script);
+                    }
                 }
             }
         }
@@ -39,22 +92,69 @@ pub fn worker_script_updater() -> Result<()> {
     Ok(())
 }
 
+/// Parses the script without running it.
+fn check_script(script: &str) -> Result<()> {
+    let mut runtime = JsRuntime::new(RuntimeOptions::default());
+    let source = deno_core::serde_json::to_string(script)?;
+    runtime
+        .execute_script("check.js", format!("new Function({})", source).into())
+        .map_err(|e| color_eyre::eyre::eyre!("{}", e))?;
+
+    Ok(())
+}
+
+/// What a worker runs a script for, with the argument as JSON
+enum Job {
+    Connect { job_id: u32, req: String },
+    Close { info: String },
+    Filter { job_id: u32, call: String },
+}
+
 pub async fn v8_worker(worker_id: usize) -> Result<()> {
     let rx = JOB_QUEUE.get_rx();
+    let close_rx = CLOSE_EVENTS.1.clone();
+    let filter_rx = FILTER_QUEUE.get_rx();
 
-    let extensions = crate::extensions::get_all_extensions();
-    let mut runtime = JsRuntime::new(RuntimeOptions {
-        extensions,
-        ..Default::default()
-    });
+    // every script gets its own runtime, so they don't see each other's globals
+    let mut runtimes: HashMap<Arc<str>, JsRuntime> = HashMap::new();
 
-    while let Ok(job) = rx.recv() {
-        let req = deno_core::serde_json::to_string(&job.value)?;
-        let job_id = job.job_id;
+    loop {
+        // connections, close events and filters share the workers, each job
+        // runs in the runtime of the script it was routed to
+        let job = crossbeam_channel::select! {
+            recv(rx) -> job => match job {
+                Ok(job) => (job.value.0, Job::Connect {
+                    job_id: job.job_id,
+                    req: deno_core::serde_json::to_string(&job.value.1)?,
+                }),
+                Err(_) => break,
+            },
+            recv(close_rx) -> info => match info {
+                Ok((path, info)) => (path, Job::Close {
+                    info: deno_core::serde_json::to_string(&info)?,
+                }),
+                Err(_) => break,
+            },
+            recv(filter_rx) -> job => match job {
+                Ok(job) => (job.value.0, Job::Filter {
+                    job_id: job.job_id,
+                    call: deno_core::serde_json::to_string(&job.value.1)?,
+                }),
+                Err(_) => break,
+            },
+        };
+        let (path, job) = job;
 
-        let worker_script = WORKER_SCRIPT.read().await;
-        let script = format!(
-            r#"
+        let worker_script = SCRIPTS
+            .read()
+            .unwrap()
+            .get(&path)
+            .cloned()
+            .unwrap_or_default();
+        let script = match &job {
+            Job::Connect { job_id, req } => {
+                format!(
+                    r#"
                 {worker_script}
 
                 async function handler(req) {{
@@ -70,54 +170,166 @@ pub async fn v8_worker(worker_id: usize) -> Result<()> {
                 }}
 
                 handler({req}).then(async (res) => {{
-                    await Deno.core.ops.op_callback({job_id}, res);
+                    await Deno.core.ops.op_callback({job_id}, res, typeof onClose === "function");
                 }});
                 "#,
-        );
+                )
+            }
+            Job::Close { info } => {
+                format!(
+                    r#"
+                {worker_script}
+
+                (async () => {{
+                    // a reload since the connection started can take onClose away
+                    if (typeof onClose !== "function") {{
+                        return;
+                    }}
+                    try {{
+                        await onClose({info});
+                    }} catch (e) {{
+                        console.error("| ERROR | Worker {worker_id} | onClose |");
+                        console.error(e.stack);
+                    }}
+                }})();
+                "#,
+                )
+            }
+            Job::Filter { job_id, call } => {
+                format!(
+                    r#"
+                {worker_script}
+
+                async function filter(call) {{
+                    const state = () => call.conn.state;
+                    try {{
+                        const handler = call.direction == "client" ? globalThis.onClientData : globalThis.onServerData;
+                        if (typeof handler !== "function") return {{ action: "pass", state: state() }};
+
+                        const res = await handler(new Uint8Array(call.chunk), call.conn);
+                        if (res === undefined || res === true) return {{ action: "pass", state: state() }};
+                        if (res === null || res === false) return {{ action: "drop", state: state() }};
+                        if (res.close) return {{ action: "close", state: state() }};
 
-        runtime
-            .execute_script("main.js", script.into())
-            .map_err(|e| color_eyre::eyre::eyre!("Runtime Error (Execute Script): {}", e))?;
+                        const data = typeof res == "string" ? new TextEncoder().encode(res) : res;
+                        return {{ action: "replace", data: Array.from(data), state: state() }};
+                    }} catch (e) {{
+                        console.error("| ERROR | Worker {worker_id} | Filter {job_id} |");
+                        console.error(e.stack);
+                        return {{ action: "close" }};
+                    }}
+                }}
+
+                filter({call}).then(async (res) => {{
+                    await Deno.core.ops.op_filter_callback({job_id}, res);
+                }});
+                "#,
+                )
+            }
+        };
 
-        runtime
-            .run_event_loop(false)
-            .await
-            .map_err(|e| color_eyre::eyre::eyre!("Runtime Error (Run Event Loop): {}", e))?;
+        let runtime = runtimes.entry(path.clone()).or_insert_with(|| {
+            JsRuntime::new(RuntimeOptions {
+                extensions: crate::extensions::get_all_extensions(),
+                ..Default::default()
+            })
+        });
+        if let Err(e) = run_script(runtime, script).await {
+            println!("| ERROR | Worker {} | {} | {}", worker_id, path, e);
+            // only this script starts over, the others keep their runtimes
+            runtimes.remove(&path);
+            fail_job(job).await;
+        }
     }
 
     Ok(())
 }
 
-pub async fn port_listener(bind_ip: &str, port: u16) -> Result<()> {
-    let addr = format!("{}:{}", bind_ip, port);
-    let listener = TcpListener::bind(&addr).await?;
-    println!("Listening on: {}", addr);
+async fn run_script(runtime: &mut JsRuntime, script: String) -> Result<()> {
+    runtime
+        .execute_script("main.js", script.into())
+        .map_err(|e| color_eyre::eyre::eyre!("Runtime Error (Execute Script): {}", e))?;
 
-    loop {
-        let socket_res = listener.accept().await;
-
-        match socket_res {
-            Ok((socket, addr)) => {
-                tokio::spawn(async move {
-                    let (job_id, mut rx) = JOB_QUEUE
-                        .enqueue(V8Request {
-                            ip: addr.ip().to_string(),
-                            port,
-                        })
-                        .await?;
-                    let res = rx.recv().await.unwrap();
-
-                    if let Err(_e) = handle_client(socket, res).await {
-                        //println!("Handle Client Error: {}", _e);
-                        JOB_QUEUE.remove_job(job_id).await?;
-                    }
+    runtime
+        .run_event_loop(false)
+        .await
+        .map_err(|e| color_eyre::eyre::eyre!("Runtime Error (Run Event Loop): {}", e))?;
 
-                    return Ok::<(), color_eyre::eyre::Error>(());
-                });
-            }
-            Err(e) => {
-                println!("Bind Socket Error: {}", e);
-            }
+    Ok(())
+}
+
+/// Answers the job of a script that failed, the same way as a script that
+/// throws. Jobs the script already answered are left alone.
+async fn fail_job(job: Job) {
+    match job {
+        Job::Connect { job_id, .. } => {
+            let res = V8Response {
+                block_connection: Some(true),
+                ..Default::default()
+            };
+            let _ = JOB_QUEUE.send_response(job_id, res).await;
         }
+        Job::Filter { job_id, .. } => {
+            let res = FilterResult {
+                action: "close".into(),
+                ..Default::default()
+            };
+            let _ = FILTER_QUEUE.send_response(job_id, res).await;
+        }
+        Job::Close { .. } => {}
+    }
+}
+
+/// Hands a listener's jobs to the workers, they run them with its script.
+pub struct WorkerEngine {
+    pub script: Arc<str>,
+}
+
+impl Engine for WorkerEngine {
+    fn decide(&self, req: V8Request) -> BoxFuture<'static, Result<V8Response>> {
+        let script = self.script.clone();
+        Box::pin(async move {
+            let (job_id, mut rx) = JOB_QUEUE.enqueue((script, req)).await?;
+            rx.recv()
+                .await
+                .ok_or_else(|| color_eyre::eyre::eyre!("Job {} was dropped", job_id))
+        })
+    }
+
+    /// Queues the script's onClose, dropped if the workers are too far behind.
+    fn on_close(&self, info: CloseInfo) {
+        if CLOSE_EVENTS
+            .0
+            .try_send((self.script.clone(), info))
+            .is_err()
+        {
+            Stats::inc(&STATS.close_events_dropped);
+        }
+    }
+
+    /// `None` if no worker answered in time, the worker still finishes the call.
+    fn filter(
+        &self,
+        call: FilterCall,
+        timeout: Duration,
+    ) -> BoxFuture<'static, Result<Option<FilterResult>>> {
+        let script = self.script.clone();
+        Box::pin(async move {
+            let (job_id, mut rx) = FILTER_QUEUE.enqueue((script, call)).await?;
+            match tokio::time::timeout(timeout, rx.recv()).await {
+                Ok(res) => Ok(Some(res.ok_or_else(|| {
+                    color_eyre::eyre::eyre!("Filter job {} was dropped", job_id)
+                })?)),
+                Err(_) => {
+                    FILTER_QUEUE.remove_job(job_id).await?;
+                    Ok(None)
+                }
+            }
+        })
+    }
+
+    fn overloaded(&self) -> bool {
+        let queue_limit = QUEUE_LIMIT.load(Ordering::Relaxed);
+        queue_limit > 0 && JOB_QUEUE.len() >= queue_limit
     }
 }
@@ -0,0 +1,11 @@
+// deno-test only: a store kept in --data-dir (default ./data) that survives
+// restarts. It mirrors Deno.openKv, so TTLs are set(key, value, { expireIn })
+// in ms rather than KV's ttl_ms. Also delete and list({ prefix }).
+async function run(req) {
+    const bans = await Deno.openKv("bans");
+    if ((await bans.get(["ip", req.ip])).value) {
+        return { block_connection: true }
+    }
+
+    return { ip: "localhost:80" }
+}
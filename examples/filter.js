@@ -0,0 +1,21 @@
+// With filter: true every chunk after the opening bytes goes through
+// onClientData / onServerData until the budget is spent, then the rest is
+// copied as is ("pass") or the connection is closed ("close"). Slow, so it's
+// opt in per connection.
+async function run(req) {
+    return {
+        ip: "localhost:25566",
+        filter: true,
+        filter_budget: { max_bytes: 1048576, max_time_ms: 1000, on_exhausted: "pass" },
+    }
+}
+
+// conn is { ip, src_port, port, upstream, state }, state is kept between
+// chunks of the connection. Return nothing to pass the chunk, false to drop
+// it, bytes or a string to replace it, or { close: true }.
+function onClientData(chunk, conn) {
+    conn.state.up = (conn.state.up ?? 0) + chunk.length;
+    if (new TextDecoder().decode(chunk).includes("/op ")) return { close: true };
+}
+
+function onServerData(chunk, conn) {}
@@ -0,0 +1,10 @@
+// Banned IPs and networks are dropped right after accept and never reach the
+// script. Lists can also be loaded with --ban-lists bans.txt.
+async function run(req) {
+    if (req.port == 23) {
+        Firewall.ban(req.ip, { ttl_ms: 600000, reason: "telnet probe" });
+        return { block_connection: true }
+    }
+
+    return { ip: "localhost:80" }
+}
@@ -0,0 +1,10 @@
+// req.history has the client's connections, last_seen_ms_ago, bytes_up /
+// bytes_down, last_decision and counts for every --history-windows window.
+async function run(req) {
+    // a port scan
+    if (req.history?.windows[0].distinct_ports > 5) {
+        return { block_connection: true }
+    }
+
+    return { ip: "localhost:80" }
+}
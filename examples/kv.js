@@ -0,0 +1,10 @@
+// An in-memory store shared by every worker, entries can expire and the
+// oldest are evicted past kv_max_bytes. Also KV.get / set / delete / cas.
+async function run(req) {
+    const seen = KV.incr("conns:" + req.ip, 1, { ttl_ms: 60000 });
+    if (seen > 100) {
+        return { block_connection: true }
+    }
+
+    return { ip: "localhost:80" }
+}
@@ -0,0 +1,13 @@
+// Shape the relayed traffic. Connections with the same key share the limits,
+// the connection is closed once max_total_bytes is exceeded.
+async function run(req) {
+    return {
+        ip: "localhost:25566",
+        limits: {
+            up_bps: 128000,
+            down_bps: 1024000,
+            max_total_bytes: 1073741824,
+            key: req.ip,
+        },
+    }
+}
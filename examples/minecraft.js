@@ -0,0 +1,22 @@
+// Route Minecraft players by the hostname they typed in, and answer the
+// server list natively while the backend is down.
+async function run(req) {
+    // without the Forge suffix, req.minecraft.forge has it
+    if (req.minecraft?.server_address == "lobby.localhost") {
+        return { ip: "localhost:25567", no_delay: true }
+    }
+
+    if (req.port == 25565) {
+        return {
+            // answers pings and kicks joining players without a backend
+            minecraft_status: {
+                motd: "Server is restarting",
+                max_players: 20,
+                online: 0,
+                version_name: "Maintenance",
+            },
+        }
+    }
+
+    return { block_connection: true }
+}
@@ -0,0 +1,11 @@
+// Called after a proxied connection closes, on the same workers as the script.
+async function run(req) {
+    return { ip: "localhost:80" }
+}
+
+async function onClose(info) {
+    // { ip, src_port, port, upstream, upstream_addr, bytes_up, bytes_down, duration_ms, reason, connect_error, error }
+    if (info.reason == "connect_failed") {
+        console.log(info.ip, "couldn't reach", info.connect_error);
+    }
+}
@@ -0,0 +1,16 @@
+// Named upstream pools with a load-balancing strategy (round-robin,
+// weighted, least-connections, consistent-hash or ewma) and active health
+// checks. Pools can also come from the config file or --pools pools.json.
+Pools.register("mc-lobby", {
+    strategy: "least-connections",
+    upstreams: [{ addr: "localhost:25567" }, { addr: "localhost:25568" }],
+    health_check: { type: "minecraft", interval_ms: 5000, rise: 2, fall: 3 },
+});
+
+async function run(req) {
+    // { healthy, checked, successes, failures, last_check_ms, last_error, latency_ms }
+    const status = upstreams.status("localhost:25567");
+    if (status && !status.healthy) console.log("lobby 1 is down:", status.last_error);
+
+    return { pool: "mc-lobby", no_delay: true }
+}
@@ -0,0 +1,12 @@
+// Send the real client address to the upstream. PROXY headers from load
+// balancers in front are read with accept_proxy / --accept-proxy, their
+// TLVs show up in req.proxy_tlvs.
+async function run(req) {
+    return {
+        ip: "localhost:25566",
+        // "v1" or "v2"
+        proxy_protocol: "v2",
+        // only sent with "v2", value is a string or bytes
+        proxy_protocol_tlvs: [{ type: 0x05, value: "connection-id" }],
+    }
+}
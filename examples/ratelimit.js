@@ -0,0 +1,10 @@
+// A token bucket shared by every worker.
+async function run(req) {
+    // { allowed, remaining, retry_after_ms, limit }
+    const limit = RateLimit.check("ip:" + req.ip, { rate: 10, per: "1m" });
+    if (!limit.allowed) {
+        return { block_connection: true }
+    }
+
+    return { ip: "localhost:80" }
+}
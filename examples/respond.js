@@ -0,0 +1,16 @@
+// Answer with static bytes instead of connecting to an upstream.
+async function run(req) {
+    if (req.protocol == "http") {
+        return {
+            respond: {
+                text: "HTTP/1.1 403 Forbidden\r\nContent-Length: 0\r\n\r\n",
+                // read and throw away up to 4 KiB of the request around the reply
+                discard_input: 4096,
+                // close_after: false proxies to ip / upstreams / pool after the reply
+            },
+        }
+    }
+
+    // or { bytes: [...] } / { base64: "..." }
+    return { respond: { text: "go away\n" } }
+}
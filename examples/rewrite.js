@@ -0,0 +1,18 @@
+// Change the buffered opening bytes before they reach the upstream.
+async function run(req) {
+    if (req.minecraft) {
+        return {
+            ip: "localhost:25566",
+            // the backend sees a different hostname, Forge markers are kept
+            rewrite_initial: { minecraft_host: "lobby.internal" },
+        }
+    }
+
+    // offsets are into req.initial_bytes, end defaults to start (an insert),
+    // or { text } / { bytes } / { base64 } to replace everything
+    const line_end = req.initial_bytes.indexOf(10) + 1;
+    return {
+        ip: "localhost:80",
+        rewrite_initial: { replace: [{ start: line_end, text: `X-Real-IP: ${req.ip}\r\n` }] },
+    }
+}
@@ -0,0 +1,12 @@
+// Keep unwanted clients busy. hang_connection is a 30s tarpit that sends
+// nothing, block_connection closes right away.
+async function run(req) {
+    if (req.port == 22) {
+        return {
+            // a junk byte every 10s for up to 10 minutes, slots are capped by --tarpit-max
+            tarpit: { duration_ms: 600000, drip_bytes: 1, drip_interval_ms: 10000 },
+        }
+    }
+
+    return { block_connection: true }
+}
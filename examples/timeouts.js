@@ -0,0 +1,10 @@
+// Override the listener's timeouts (--idle-timeout-ms, --max-lifetime-ms,
+// --half-close-linger-ms) for one connection, 0 disables the timer.
+async function run(req) {
+    return {
+        ip: "localhost:80",
+        idle_timeout_ms: 60000,
+        max_lifetime_ms: 3600000,
+        half_close_linger_ms: 5000,
+    }
+}
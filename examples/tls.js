@@ -0,0 +1,9 @@
+// Route TLS by the ClientHello, req.tls has sni, alpn, versions and
+// cipher_suites.
+async function run(req) {
+    if (req.tls?.sni == "api.example.com") {
+        return { ip: "localhost:8443" }
+    }
+
+    return { ip: "localhost:443" }
+}
@@ -0,0 +1,13 @@
+// Fail over across several upstreams. They're tried in order until one
+// connects, upstreams replaces ip.
+async function run(req) {
+    return {
+        upstreams: [
+            { addr: "localhost:8080", weight: 2 },
+            { addr: "[::1]:8081", connect_timeout_ms: 1000 },
+            { addr: "unix:/run/backend.sock" },
+        ],
+        // shuffle by weight instead of trying them in order
+        upstream_order: "weighted",
+    }
+}
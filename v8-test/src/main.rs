@@ -1,42 +1,157 @@
-use color_eyre::{eyre::eyre, Result};
-use std::{net::SocketAddr, sync::Arc};
-use tokio::{
-    net::{TcpListener, TcpStream},
-    sync::RwLock,
+use color_eyre::Result;
+use deez_core::{
+    config::EngineConfig,
+    engine::Engine,
+    firewall, health, history, kv, listener, pools,
+    stats::{self, LOG_CONNECTIONS},
+    structs::{
+        AcceptProxy, CloseInfo, FilterCall, FilterResult, ListenerOptions, V8Request, V8Response,
+    },
+    tarpit, udp,
+    utils::ListenAddr,
 };
+use futures::future::BoxFuture;
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tokio::sync::RwLock;
 
-mod utils;
+/// How long onClose may take before its isolate is dropped
+const ON_CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The `[engine]` table, every call gets a fresh isolate so there is
+/// nothing to set. Worker pool keys like `workers` or `queue_limit` are
+/// rejected rather than ignored.
+#[derive(serde::Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct IsolateConfig {}
+
+impl EngineConfig for IsolateConfig {
+    fn apply_args(&mut self, _args: &[String]) -> Result<()> {
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Runs the listener's script in a new isolate for every call
+struct IsolateEngine {
+    code: Arc<RwLock<String>>,
+}
+
+impl Engine for IsolateEngine {
+    fn decide(&self, req: V8Request) -> BoxFuture<'static, Result<V8Response>> {
+        let code = self.code.clone();
+        Box::pin(async move {
+            let code = code.read().await.to_owned();
+            v8_engine::utils::get_script_res(&code, req).await
+        })
+    }
+
+    /// Isolates block, so onClose runs off the runtime's threads too. It runs
+    /// the current script, which may be newer than the one decide ran.
+    fn on_close(&self, info: CloseInfo) {
+        let code = self.code.clone();
+        tokio::spawn(async move {
+            let code = code.read().await.to_owned();
+            let res = tokio::task::spawn_blocking(move || {
+                v8_engine::utils::run_on_close(&code, info, ON_CLOSE_TIMEOUT)
+            })
+            .await;
+            if let Ok(Err(e)) = res {
+                println!("onClose Error: {}", e);
+            }
+        });
+    }
+
+    /// Isolates block, so the call runs off the runtime's threads.
+    fn filter(
+        &self,
+        call: FilterCall,
+        timeout: Duration,
+    ) -> BoxFuture<'static, Result<Option<FilterResult>>> {
+        let code = self.code.clone();
+        Box::pin(async move {
+            let code = code.read().await.to_owned();
+            tokio::task::spawn_blocking(move || v8_engine::utils::run_filter(&code, call, timeout))
+                .await?
+        })
+    }
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
     color_eyre::install()?;
     v8_engine::utils::install();
 
-    let code_cache = Arc::new(RwLock::new(tokio::fs::read_to_string("main.js").await?));
-    let code_cache_clone = code_cache.clone();
-    tokio::spawn(async move {
-        loop {
-            tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
-            let code = tokio::fs::read_to_string("main.js")
-                .await
-                .unwrap_or_default();
+    let args = std::env::args().collect::<Vec<String>>();
+    let config = deez_core::config::load::<IsolateConfig>(&args)?;
+    let listeners = config.listeners()?;
 
-            if code_cache_clone.read().await.to_owned() != code {
-                println!("Code Updated");
-                *code_cache_clone.write().await = code;
-            }
+    let mut code_caches = HashMap::new();
+    for listener in &listeners {
+        if !code_caches.contains_key(&listener.script) {
+            let code_cache = code_cache(listener.script.clone()).await?;
+            code_caches.insert(listener.script.clone(), code_cache);
         }
-    });
+    }
+
+    let trusted_proxies = config.trusted_proxies()?;
+    if trusted_proxies.is_empty()
+        && listeners.iter().any(|listener| {
+            listener.accept_proxy != AcceptProxy::Off
+                && !matches!(listener.addr, ListenAddr::Unix(_))
+        })
+    {
+        println!("No --trusted-proxies set, every inbound PROXY header will be rejected!");
+    }
+
+    tarpit::TARPIT_MAX.store(config.tarpit_max, std::sync::atomic::Ordering::Relaxed);
+    if let Some(path) = &config.pools_file {
+        pools::load_file(path)?;
+    }
+    for (name, pool) in &config.pools {
+        pools::register(name, pool)
+            .map_err(|e| color_eyre::eyre::eyre!("pools.{}: {}", name, e))?;
+    }
+    health::health_checker();
+    firewall::ban_list_updater(config.ban_lists.clone())?;
+    kv::KV_MAX_BYTES.store(config.kv_max_bytes, std::sync::atomic::Ordering::Relaxed);
+    history::HISTORY_MAX_CLIENTS.store(
+        config.history.max_clients,
+        std::sync::atomic::Ordering::Relaxed,
+    );
+    *history::HISTORY_WINDOWS.write().unwrap() = config.history_windows();
+    LOG_CONNECTIONS.store(config.log.connections, std::sync::atomic::Ordering::Relaxed);
+    stats::stats_reporter(std::time::Duration::from_secs(
+        config.log.stats_interval_sec,
+    ));
 
-    let args = std::env::args().collect::<Vec<String>>();
-    let ports = utils::parse_ports(args.get(1).unwrap_or(&String::from("7070")))?;
     let mut tasks = vec![];
+    for listener in listeners {
+        let engine = Arc::new(IsolateEngine {
+            code: code_caches[&listener.script].clone(),
+        });
+        if let ListenAddr::Udp(addr) = listener.addr {
+            tasks.push(tokio::spawn(udp::udp_listener(
+                addr,
+                config.udp_flow_idle(),
+                engine,
+            )));
+            continue;
+        }
 
-    for port in ports {
-        tasks.push(tokio::spawn(port_worker(
-            "0.0.0.0",
-            port,
-            code_cache.clone(),
+        let options = ListenerOptions {
+            accept_proxy: listener.accept_proxy,
+            trusted_proxies: trusted_proxies.clone(),
+            sniff: listener.sniff,
+            timeouts: config.timeouts(),
+            proxy_timeout: listener.proxy_timeout,
+        };
+        tasks.push(tokio::spawn(listener::port_listener(
+            listener.addr,
+            options,
+            engine,
         )));
     }
     futures::future::try_join_all(tasks).await?;
@@ -44,50 +159,44 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
-async fn port_worker(bind_ip: &str, port: u16, code_cache: Arc<RwLock<String>>) -> Result<()> {
-    let addr = format!("{}:{}", bind_ip, port);
-    let listener = TcpListener::bind(&addr).await?;
-    println!("Listening on: {}", addr);
-
-    loop {
-        let socket_res = listener.accept().await;
+/// Loads a script, then reloads it whenever it changes. Changes that don't
+/// compile are skipped, the listener keeps the last working version.
+async fn code_cache(path: String) -> Result<Arc<RwLock<String>>> {
+    let code = tokio::fs::read_to_string(&path)
+        .await
+        .map_err(|e| color_eyre::eyre::eyre!("Can't read script {}: {}", path, e))?;
+    v8_engine::utils::check_script(&code)
+        .map_err(|e| color_eyre::eyre::eyre!("Script {}: {}", path, e))?;
+    let code_cache = Arc::new(RwLock::new(code));
 
-        match socket_res {
-            Ok((socket, addr)) => {
-                let code = code_cache.read().await.to_owned();
-
-                tokio::spawn(async move {
-                    if let Err(e) = handle_client(socket, port, addr, &code).await {
-                        println!("Handle Client Error: {}", e);
-                    }
-                });
+    let code_cache_clone = code_cache.clone();
+    tokio::spawn(async move {
+        let mut rejected = None;
+        loop {
+            tokio::time::sleep(tokio::time::Duration::from_secs(5)).await;
+            let Ok(code) = tokio::fs::read_to_string(&path).await else {
+                continue;
+            };
+            if *code_cache_clone.read().await == code || rejected.as_ref() == Some(&code) {
+                continue;
             }
-            Err(e) => {
-                println!("Bind Socket Error: {}", e);
+
+            match v8_engine::utils::check_script(&code) {
+                Ok(()) => {
+                    println!("Code Updated: {}", path);
+                    *code_cache_clone.write().await = code;
+                    rejected = None;
+                }
+                Err(e) => {
+                    println!(
+                        "Code Update of {} skipped, keeping the last working version: {}",
+                        path, e
+                    );
+                    rejected = Some(code);
+                }
             }
         }
-    }
-}
-
-async fn handle_client(
-    mut socket: TcpStream,
-    port: u16,
-    addr: SocketAddr,
-    code: &str,
-) -> Result<()> {
-    let res = v8_engine::utils::get_script_res(&code, port, addr).await?;
-    if res.block_connection.unwrap_or(false) {
-        return Ok(());
-    } else if res.hang_connection.unwrap_or(false) {
-        tokio::time::sleep(tokio::time::Duration::from_secs(30)).await;
-        return Ok(());
-    }
-
-    let mut out_stream =
-        TcpStream::connect(res.ip.ok_or(eyre!("Ip is null in V8Response"))?).await?;
-    out_stream.set_nodelay(res.no_delay.unwrap_or(false))?;
-
-    tokio::io::copy_bidirectional(&mut socket, &mut out_stream).await?;
+    });
 
-    Ok(())
+    Ok(code_cache)
 }
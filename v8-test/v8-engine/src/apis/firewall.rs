@@ -0,0 +1,68 @@
+use crate::utils;
+use color_eyre::Result;
+use deez_core::{firewall, structs::BanOptions};
+
+#[inline(always)]
+pub fn register(scope: &mut v8::HandleScope, global: v8::Local<v8::Object>) -> Result<()> {
+    let firewall_key = v8::String::new(scope, "Firewall").unwrap();
+    let firewall_val = v8::Object::new(scope);
+    global.set(scope, firewall_key.into(), firewall_val.into());
+
+    utils::set_func(scope, firewall_val, "ban", firewall_ban);
+    utils::set_func(scope, firewall_val, "unban", firewall_unban);
+    utils::set_func(scope, firewall_val, "isBanned", firewall_is_banned);
+
+    Ok(())
+}
+
+fn throw(scope: &mut v8::HandleScope, message: &str) {
+    let message = v8::String::new(scope, message).unwrap();
+    let exception = v8::Exception::type_error(scope, message);
+    scope.throw_exception(exception);
+}
+
+// Banned clients are rejected right after accept, before any script runs.
+// Takes an IP or CIDR, e.g. ban(req.ip, { ttl_ms: 3600000, reason: "spam" })
+fn firewall_ban(
+    scope: &mut v8::HandleScope,
+    args: v8::FunctionCallbackArguments,
+    mut rv: v8::ReturnValue,
+) {
+    let target = args.get(0).to_rust_string_lossy(scope);
+    let options = match args.get(1).is_null_or_undefined() {
+        true => Ok(BanOptions::default()),
+        false => serde_v8::from_v8::<BanOptions>(scope, args.get(1))
+            .map_err(|e| color_eyre::eyre::eyre!("Invalid Firewall options: {}", e)),
+    };
+
+    match options.and_then(|options| firewall::ban(&target, &options)) {
+        Ok(()) => rv.set(v8::undefined(scope).into()),
+        Err(e) => throw(scope, &e.to_string()),
+    }
+}
+
+// only lifts bans added by scripts, not the ones from --ban-lists files
+fn firewall_unban(
+    scope: &mut v8::HandleScope,
+    args: v8::FunctionCallbackArguments,
+    mut rv: v8::ReturnValue,
+) {
+    let target = args.get(0).to_rust_string_lossy(scope);
+    match firewall::unban(&target) {
+        Ok(removed) => rv.set(v8::Boolean::new(scope, removed).into()),
+        Err(e) => throw(scope, &e.to_string()),
+    }
+}
+
+// { target, reason, expires_in_ms } or null
+fn firewall_is_banned(
+    scope: &mut v8::HandleScope,
+    args: v8::FunctionCallbackArguments,
+    mut rv: v8::ReturnValue,
+) {
+    let ip = args.get(0).to_rust_string_lossy(scope);
+    match ip.parse() {
+        Ok(ip) => rv.set(serde_v8::to_v8(scope, firewall::check(ip)).unwrap()),
+        Err(_) => throw(scope, &format!("Invalid IP: {}", ip)),
+    }
+}
@@ -0,0 +1,125 @@
+use crate::utils;
+use color_eyre::Result;
+use deez_core::kv;
+use serde_json::Value;
+
+#[inline(always)]
+pub fn register(scope: &mut v8::HandleScope, global: v8::Local<v8::Object>) -> Result<()> {
+    let kv_key = v8::String::new(scope, "KV").unwrap();
+    let kv_val = v8::Object::new(scope);
+    global.set(scope, kv_key.into(), kv_val.into());
+
+    utils::set_func(scope, kv_val, "get", kv_get);
+    utils::set_func(scope, kv_val, "set", kv_set);
+    utils::set_func(scope, kv_val, "delete", kv_delete);
+    utils::set_func(scope, kv_val, "incr", kv_incr);
+    utils::set_func(scope, kv_val, "cas", kv_cas);
+
+    Ok(())
+}
+
+fn throw(scope: &mut v8::HandleScope, message: &str) {
+    let message = v8::String::new(scope, message).unwrap();
+    let exception = v8::Exception::type_error(scope, message);
+    scope.throw_exception(exception);
+}
+
+/// undefined is stored as null, like JSON.stringify does in arrays
+fn value(scope: &mut v8::HandleScope, value: v8::Local<v8::Value>) -> Result<Value> {
+    if value.is_undefined() {
+        return Ok(Value::Null);
+    }
+
+    serde_v8::from_v8::<Value>(scope, value)
+        .map_err(|e| color_eyre::eyre::eyre!("KV values have to be JSON: {}", e))
+}
+
+/// `ttl_ms` of the options object, if there is one
+fn ttl_ms(scope: &mut v8::HandleScope, options: v8::Local<v8::Value>) -> Option<u64> {
+    let options = options
+        .to_object(scope)
+        .filter(|_| !options.is_null_or_undefined())?;
+    let key = v8::String::new(scope, "ttl_ms").unwrap();
+    let ttl = options.get(scope, key.into())?;
+    match ttl.is_null_or_undefined() {
+        true => None,
+        false => ttl.number_value(scope).map(|ttl| ttl.max(0.0) as u64),
+    }
+}
+
+// Shared by every isolate, values are anything JSON can hold
+fn kv_get(
+    scope: &mut v8::HandleScope,
+    args: v8::FunctionCallbackArguments,
+    mut rv: v8::ReturnValue,
+) {
+    let key = args.get(0).to_rust_string_lossy(scope);
+    let value = kv::get(&key).unwrap_or(Value::Null);
+    rv.set(serde_v8::to_v8(scope, &value).unwrap());
+}
+
+fn kv_set(
+    scope: &mut v8::HandleScope,
+    args: v8::FunctionCallbackArguments,
+    mut rv: v8::ReturnValue,
+) {
+    let key = args.get(0).to_rust_string_lossy(scope);
+    match value(scope, args.get(1)) {
+        Ok(value) => {
+            let ttl_ms = ttl_ms(scope, args.get(2));
+            kv::set(&key, value, ttl_ms);
+            rv.set(v8::undefined(scope).into());
+        }
+        Err(e) => throw(scope, &e.to_string()),
+    }
+}
+
+fn kv_delete(
+    scope: &mut v8::HandleScope,
+    args: v8::FunctionCallbackArguments,
+    mut rv: v8::ReturnValue,
+) {
+    let key = args.get(0).to_rust_string_lossy(scope);
+    rv.set(v8::Boolean::new(scope, kv::delete(&key)).into());
+}
+
+// the TTL is only set if the key didn't exist yet
+fn kv_incr(
+    scope: &mut v8::HandleScope,
+    args: v8::FunctionCallbackArguments,
+    mut rv: v8::ReturnValue,
+) {
+    let key = args.get(0).to_rust_string_lossy(scope);
+    let by = match args.get(1).is_undefined() {
+        true => 1.0,
+        false => args.get(1).number_value(scope).unwrap_or(f64::NAN),
+    };
+    if !by.is_finite() {
+        return throw(scope, "KV incr needs a number");
+    }
+
+    let ttl_ms = ttl_ms(scope, args.get(2));
+    match kv::incr(&key, by, ttl_ms) {
+        Ok(value) => rv.set(serde_v8::to_v8(scope, &value).unwrap()),
+        Err(e) => throw(scope, &e.to_string()),
+    }
+}
+
+// null as expected means the key must not exist
+fn kv_cas(
+    scope: &mut v8::HandleScope,
+    args: v8::FunctionCallbackArguments,
+    mut rv: v8::ReturnValue,
+) {
+    let key = args.get(0).to_rust_string_lossy(scope);
+    let values =
+        value(scope, args.get(1)).and_then(|expected| Ok((expected, value(scope, args.get(2))?)));
+    match values {
+        Ok((expected, value)) => {
+            let ttl_ms = ttl_ms(scope, args.get(3));
+            let swapped = kv::compare_and_swap(&key, &expected, value, ttl_ms);
+            rv.set(v8::Boolean::new(scope, swapped).into());
+        }
+        Err(e) => throw(scope, &e.to_string()),
+    }
+}
@@ -3,10 +3,20 @@ use v8::{HandleScope, Local, Object, TryCatch};
 
 mod console;
 mod fetch;
+mod firewall;
+mod kv;
+mod pools;
+mod ratelimit;
+mod upstreams;
 
 pub fn register_all(scope: &mut TryCatch<HandleScope>, global: Local<Object>) -> Result<()> {
     console::register(scope, global)?;
     fetch::register(scope, global)?;
+    firewall::register(scope, global)?;
+    kv::register(scope, global)?;
+    pools::register(scope, global)?;
+    upstreams::register(scope, global)?;
+    ratelimit::register(scope, global)?;
     crate::utils::set_func(scope, global, "sleep", __internal_sleep2);
 
     crate::utils::register_script(include_str!("./js/others.js"), "others.js", scope)?;
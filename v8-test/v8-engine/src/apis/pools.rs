@@ -0,0 +1,46 @@
+use crate::utils;
+use color_eyre::Result;
+use deez_core::{pools, structs::PoolConfig};
+
+#[inline(always)]
+pub fn register(scope: &mut v8::HandleScope, global: v8::Local<v8::Object>) -> Result<()> {
+    let pools_key = v8::String::new(scope, "Pools").unwrap();
+    let pools_val = v8::Object::new(scope);
+    global.set(scope, pools_key.into(), pools_val.into());
+
+    utils::set_func(scope, pools_val, "register", pools_register);
+    utils::set_func(scope, pools_val, "remove", pools_remove);
+
+    Ok(())
+}
+
+// Pools are shared by every isolate, registering the same pool again keeps its state
+fn pools_register(
+    scope: &mut v8::HandleScope,
+    args: v8::FunctionCallbackArguments,
+    mut rv: v8::ReturnValue,
+) {
+    let name = args.get(0).to_rust_string_lossy(scope);
+    let res = serde_v8::from_v8::<PoolConfig>(scope, args.get(1))
+        .map_err(|e| color_eyre::eyre::eyre!("Invalid pool config: {}", e))
+        .and_then(|config| pools::register(&name, &config));
+
+    if let Err(e) = res {
+        let message = v8::String::new(scope, &e.to_string()).unwrap();
+        let exception = v8::Exception::type_error(scope, message);
+        scope.throw_exception(exception);
+        return;
+    }
+
+    rv.set(v8::undefined(scope).into());
+}
+
+fn pools_remove(
+    scope: &mut v8::HandleScope,
+    args: v8::FunctionCallbackArguments,
+    mut rv: v8::ReturnValue,
+) {
+    let name = args.get(0).to_rust_string_lossy(scope);
+    let removed = pools::remove(&name);
+    rv.set(v8::Boolean::new(scope, removed).into());
+}
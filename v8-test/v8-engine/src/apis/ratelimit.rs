@@ -0,0 +1,35 @@
+use crate::utils;
+use color_eyre::Result;
+use deez_core::{ratelimit, structs::RateLimitOptions};
+
+#[inline(always)]
+pub fn register(scope: &mut v8::HandleScope, global: v8::Local<v8::Object>) -> Result<()> {
+    let ratelimit_key = v8::String::new(scope, "RateLimit").unwrap();
+    let ratelimit_val = v8::Object::new(scope);
+    global.set(scope, ratelimit_key.into(), ratelimit_val.into());
+
+    utils::set_func(scope, ratelimit_val, "check", ratelimit_check);
+
+    Ok(())
+}
+
+// Budgets are shared by every isolate, e.g. check("ip:" + req.ip, { rate: 10, per: "1m" })
+fn ratelimit_check(
+    scope: &mut v8::HandleScope,
+    args: v8::FunctionCallbackArguments,
+    mut rv: v8::ReturnValue,
+) {
+    let key = args.get(0).to_rust_string_lossy(scope);
+    let res = serde_v8::from_v8::<RateLimitOptions>(scope, args.get(1))
+        .map_err(|e| color_eyre::eyre::eyre!("Invalid RateLimit options: {}", e))
+        .and_then(|options| ratelimit::check(&key, &options));
+
+    match res {
+        Ok(res) => rv.set(serde_v8::to_v8(scope, &res).unwrap()),
+        Err(e) => {
+            let message = v8::String::new(scope, &e.to_string()).unwrap();
+            let exception = v8::Exception::type_error(scope, message);
+            scope.throw_exception(exception);
+        }
+    }
+}
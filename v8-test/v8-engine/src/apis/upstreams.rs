@@ -0,0 +1,27 @@
+use crate::utils;
+use color_eyre::Result;
+use deez_core::pools;
+
+#[inline(always)]
+pub fn register(scope: &mut v8::HandleScope, global: v8::Local<v8::Object>) -> Result<()> {
+    let upstreams_key = v8::String::new(scope, "upstreams").unwrap();
+    let upstreams_val = v8::Object::new(scope);
+    global.set(scope, upstreams_key.into(), upstreams_val.into());
+
+    utils::set_func(scope, upstreams_val, "status", upstreams_status);
+
+    Ok(())
+}
+
+// Health of a pool member by address, null if no pool has it
+fn upstreams_status(
+    scope: &mut v8::HandleScope,
+    args: v8::FunctionCallbackArguments,
+    mut rv: v8::ReturnValue,
+) {
+    let addr = args.get(0).to_rust_string_lossy(scope);
+    match pools::status(&addr) {
+        Some(status) => rv.set(serde_v8::to_v8(scope, &status).unwrap()),
+        None => rv.set(v8::null(scope).into()),
+    }
+}
@@ -1,23 +1,5 @@
 use color_eyre::Result;
-use std::net::SocketAddr;
-
-#[derive(serde::Deserialize, Debug)]
-#[allow(dead_code)]
-pub struct V8Response {
-    pub block_connection: Option<bool>,
-    pub hang_connection: Option<bool>,
-    pub ip: Option<String>,
-    pub no_delay: Option<bool>,
-
-    pub cpu_time: Option<u128>,
-}
-
-#[derive(serde::Serialize, Debug)]
-#[allow(dead_code)]
-pub struct V8Request {
-    pub ip: String,
-    pub port: u16,
-}
+use deez_core::structs::{CloseInfo, FilterCall, FilterResult, V8Request, V8Response};
 
 pub fn install() {
     let platform = v8::new_default_platform(0, false).make_shared();
@@ -25,7 +7,7 @@ pub fn install() {
     v8::V8::initialize();
 }
 
-pub async fn get_script_res(script: &str, port: u16, addr: SocketAddr) -> Result<V8Response> {
+pub async fn get_script_res(script: &str, request: V8Request) -> Result<V8Response> {
     let isolate = &mut v8::Isolate::new(Default::default());
     let scope = &mut v8::HandleScope::new(isolate);
     let context = v8::Context::new(scope);
@@ -65,10 +47,6 @@ pub async fn get_script_res(script: &str, port: u16, addr: SocketAddr) -> Result
     let function = script.run(&mut scope).to_res("Failed to run script!")?;
     let function = v8::Local::<v8::Function>::try_from(function)?;
 
-    let request = V8Request {
-        ip: format!("{}", addr.ip()),
-        port,
-    };
     let arg = serde_v8::to_v8(&mut scope, request)?.into();
 
     let result = function
@@ -98,6 +76,11 @@ pub async fn get_script_res(script: &str, port: u16, addr: SocketAddr) -> Result
 
         if let Ok(mut result) = result_res {
             result.cpu_time = Some(cpu_time_start.elapsed().as_micros());
+            let on_close =
+                v8::String::new(&mut scope, "onClose").to_res("Failed to create new string")?;
+            result.on_close = global
+                .get(&mut scope, on_close.into())
+                .is_some_and(|value| value.is_function());
             return Ok(result);
         }
     }
@@ -105,6 +88,165 @@ pub async fn get_script_res(script: &str, port: u16, addr: SocketAddr) -> Result
     color_eyre::eyre::bail!("Failed to get result!")
 }
 
+/// Compiles the script without running it, so a broken reload can be
+/// rejected before connections use it.
+pub fn check_script(script: &str) -> Result<()> {
+    let isolate = &mut v8::Isolate::new(Default::default());
+    let scope = &mut v8::HandleScope::new(isolate);
+    let context = v8::Context::new(scope);
+    let scope = &mut v8::ContextScope::new(scope, context);
+    let mut scope = v8::TryCatch::new(scope);
+
+    let code = v8::String::new(&mut scope, script).to_res("Failed to change code to v8 string!")?;
+    if v8::Script::compile(&mut scope, code, None).is_none() {
+        let exception = scope
+            .exception()
+            .and_then(|exception| exception.to_string(&mut scope))
+            .map(|exception| exception.to_rust_string_lossy(&mut scope))
+            .unwrap_or_default();
+        crate::utils::report_exceptions(&mut scope)?;
+        color_eyre::eyre::bail!("Error compiling script: {}", exception);
+    }
+
+    Ok(())
+}
+
+/// Calls the script's onClose with a fresh isolate, like `get_script_res`.
+/// Blocks until the handler finished or `timeout` ran out.
+pub fn run_on_close(script: &str, info: CloseInfo, timeout: std::time::Duration) -> Result<()> {
+    let isolate = &mut v8::Isolate::new(Default::default());
+    let scope = &mut v8::HandleScope::new(isolate);
+    let context = v8::Context::new(scope);
+    let scope = &mut v8::ContextScope::new(scope, context);
+    let mut scope = v8::TryCatch::new(scope);
+    let global = context.global(&mut scope);
+    crate::apis::register_all(&mut scope, global)?;
+
+    let code = format!(
+        r#"
+        async function run(info) {{
+            // a reload since decide can take onClose away
+            if (typeof onClose !== "function") {{
+                return;
+            }}
+            try {{
+                await onClose(info);
+            }}
+            catch (e) {{
+                console.error(e.stack);
+            }}
+        }}
+
+        {}
+
+        run
+    "#,
+        script
+    );
+
+    let code = v8::String::new(&mut scope, &code).to_res("Failed to change code to v8 string!")?;
+    let script = match v8::Script::compile(&mut scope, code, None) {
+        Some(script) => script,
+        None => {
+            crate::utils::report_exceptions(&mut scope)?;
+            return Err(color_eyre::eyre::eyre!("Error compiling script"));
+        }
+    };
+
+    let start = std::time::Instant::now();
+    let function = script.run(&mut scope).to_res("Failed to run script!")?;
+    let function = v8::Local::<v8::Function>::try_from(function)?;
+    let arg = serde_v8::to_v8(&mut scope, info)?;
+
+    let result = function
+        .call(&mut scope, global.into(), &[arg])
+        .to_res("Failed to call function!")?;
+    let promise = v8::Local::<v8::Promise>::try_from(result)?;
+
+    while promise.state() == v8::PromiseState::Pending {
+        if start.elapsed() > timeout {
+            color_eyre::eyre::bail!("onClose timed out!");
+        }
+        std::thread::sleep(std::time::Duration::from_millis(1));
+    }
+
+    Ok(())
+}
+
+/// Runs one chunk through the script's onClientData / onServerData with a
+/// fresh isolate, `None` if the handler didn't finish within `timeout`.
+pub fn run_filter(
+    script: &str,
+    call: FilterCall,
+    timeout: std::time::Duration,
+) -> Result<Option<FilterResult>> {
+    let isolate = &mut v8::Isolate::new(Default::default());
+    let scope = &mut v8::HandleScope::new(isolate);
+    let context = v8::Context::new(scope);
+    let scope = &mut v8::ContextScope::new(scope, context);
+    let mut scope = v8::TryCatch::new(scope);
+    let global = context.global(&mut scope);
+    crate::apis::register_all(&mut scope, global)?;
+
+    let code = format!(
+        r#"
+        async function run(call) {{
+            const state = () => call.conn.state;
+            try {{
+                const handler = call.direction == "client" ? globalThis.onClientData : globalThis.onServerData;
+                if (typeof handler !== "function") return {{ action: "pass", state: state() }};
+
+                const res = await handler(new Uint8Array(call.chunk), call.conn);
+                if (res === undefined || res === true) return {{ action: "pass", state: state() }};
+                if (res === null || res === false) return {{ action: "drop", state: state() }};
+                if (res.close) return {{ action: "close", state: state() }};
+
+                const data = typeof res == "string" ? new TextEncoder().encode(res) : res;
+                return {{ action: "replace", data: Array.from(data), state: state() }};
+            }}
+            catch (e) {{
+                console.error(e.stack);
+                return {{ action: "close" }};
+            }}
+        }}
+
+        {}
+
+        run
+    "#,
+        script
+    );
+
+    let code = v8::String::new(&mut scope, &code).to_res("Failed to change code to v8 string!")?;
+    let script = match v8::Script::compile(&mut scope, code, None) {
+        Some(script) => script,
+        None => {
+            crate::utils::report_exceptions(&mut scope)?;
+            return Err(color_eyre::eyre::eyre!("Error compiling script"));
+        }
+    };
+
+    let start = std::time::Instant::now();
+    let function = script.run(&mut scope).to_res("Failed to run script!")?;
+    let function = v8::Local::<v8::Function>::try_from(function)?;
+    let arg = serde_v8::to_v8(&mut scope, call)?;
+
+    let result = function
+        .call(&mut scope, global.into(), &[arg])
+        .to_res("Failed to call function!")?;
+    let promise = v8::Local::<v8::Promise>::try_from(result)?;
+
+    while promise.state() == v8::PromiseState::Pending {
+        if start.elapsed() > timeout {
+            return Ok(None);
+        }
+        std::thread::sleep(std::time::Duration::from_millis(1));
+    }
+
+    let result = promise.result(&mut scope);
+    Ok(Some(serde_v8::from_v8(&mut scope, result)?))
+}
+
 #[inline(always)]
 pub fn set_func(
     scope: &mut v8::HandleScope,